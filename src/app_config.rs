@@ -0,0 +1,112 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::brightness_schedule::BrightnessSchedule;
+use crate::color_pipeline::ColorPipeline;
+use crate::macropad_updater::ReleaseSourceConfig;
+use crate::palette::Palette;
+use crate::profile::LedPreset;
+use crate::reactive_lighting::ReactiveSource;
+use crate::write_scheduler;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AppConfig {
+    pub release_source: ReleaseSourceConfig,
+    /// Refuse to flash firmware unless the release publishes a matching
+    /// SHA-256 checksum file.
+    pub verify_firmware_checksum: bool,
+    /// Read every setting back from the macropad after writing it.
+    pub verify_writes: bool,
+    /// Milliseconds a setting has to stop changing for before it is written,
+    /// `None` for the default.
+    pub write_delay_ms: Option<u64>,
+    /// Accept LED changes from other programs through
+    /// [`crate::control_api`].
+    pub control_api: bool,
+    /// How picked colors and brightness are turned into LED values.
+    pub color_pipeline: ColorPipeline,
+    /// Saved lighting setups, in the order they are listed.
+    pub led_presets: Vec<LedPreset>,
+    /// Recently used and saved colors.
+    pub palette: Palette,
+    /// What the key colors follow, `None` to leave them as configured.
+    pub reactive_lighting: Option<ReactiveSource>,
+    /// The key colors from before reactive lighting was turned on, put back
+    /// when it is turned off.
+    pub reactive_saved_colors: Option<Vec<(u8, u8, u8)>>,
+    /// Dims or turns off the LEDs at set times of day.
+    pub brightness_schedule: BrightnessSchedule,
+}
+
+pub fn data_dir() -> Option<PathBuf> {
+    dirs::data_dir().map(|dir| dir.join("macropad_configurator"))
+}
+
+pub fn config_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("macropad_configurator").join("config.json"))
+}
+
+impl AppConfig {
+    /// Loads the config from disk, falling back to the defaults if it is
+    /// missing or can not be parsed.
+    pub fn load() -> Self {
+        config_path()
+            .and_then(|path| File::open(path).ok())
+            .and_then(|file| serde_json::from_reader(file).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn write_delay(&self) -> Duration {
+        self.write_delay_ms
+            .map_or(write_scheduler::DEFAULT_DELAY, Duration::from_millis)
+    }
+
+    pub fn led_preset_names(&self) -> Vec<String> {
+        self.led_presets
+            .iter()
+            .map(|preset| preset.name.clone())
+            .collect()
+    }
+
+    pub fn led_preset(&self, name: &str) -> Option<&LedPreset> {
+        self.led_presets.iter().find(|preset| preset.name == name)
+    }
+
+    /// Adds the preset, replacing any preset of the same name in place.
+    pub fn save_led_preset(&mut self, preset: LedPreset) {
+        match self
+            .led_presets
+            .iter_mut()
+            .find(|saved| saved.name == preset.name)
+        {
+            Some(saved) => *saved = preset,
+            None => self.led_presets.push(preset),
+        }
+    }
+
+    /// The key colors to keep in profiles and presets while reactive
+    /// lighting is showing its own, `None` when it is off.
+    pub fn saved_key_colors(&self) -> Option<&[(u8, u8, u8)]> {
+        self.reactive_lighting.as_ref()?;
+        self.reactive_saved_colors.as_deref()
+    }
+
+    pub fn remove_led_preset(&mut self, name: &str) {
+        self.led_presets.retain(|preset| preset.name != name);
+    }
+
+    pub fn save(&self) -> Result<(), ()> {
+        let path = config_path().ok_or(())?;
+        std::fs::create_dir_all(path.parent().unwrap()).map_err(|_| ())?;
+
+        let data = serde_json::to_vec_pretty(self).map_err(|_| ())?;
+        File::create(path)
+            .and_then(|mut f| f.write_all(&data))
+            .map_err(|_| ())
+    }
+}
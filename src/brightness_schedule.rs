@@ -0,0 +1,391 @@
+use std::fmt;
+
+use chrono::{Datelike, NaiveDate, NaiveDateTime, NaiveTime, Weekday};
+use macropad_protocol::data_protocol::LedEffect;
+use serde::{Deserialize, Serialize};
+
+use crate::hid_manager::MacropadCommand;
+use crate::macro_parser::LedConfig;
+
+/// What the LEDs are set to from a point in the day.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Level {
+    /// The effect turned off.
+    Off,
+    /// The effect back on at the brightness it had.
+    On,
+    /// The effect on at this brightness, as it looks rather than the value
+    /// written, see [`crate::color_pipeline`].
+    Brightness(u8),
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Level::Off => write!(f, "off"),
+            Level::On => write!(f, "on"),
+            Level::Brightness(brightness) => write!(f, "brightness {}", brightness),
+        }
+    }
+}
+
+/// Reads `off`, `on` or a brightness from 0 to 255.
+pub fn parse_level(text: &str) -> Result<Level, ()> {
+    match text.trim().to_lowercase().as_str() {
+        "off" => Ok(Level::Off),
+        "on" => Ok(Level::On),
+        text => text.parse().map(Level::Brightness).map_err(|_| ()),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Transition {
+    pub hour: u8,
+    pub minute: u8,
+    pub level: Level,
+}
+
+impl Transition {
+    /// Reads the time as `hh:mm` on a 24 hour clock.
+    pub fn parse(time: &str, level: &str) -> Result<Self, ()> {
+        let (hour, minute) = time.trim().split_once(':').ok_or(())?;
+        let transition = Self {
+            hour: hour.parse().map_err(|_| ())?,
+            minute: minute.parse().map_err(|_| ())?,
+            level: parse_level(level)?,
+        };
+
+        transition.time().ok_or(())?;
+        Ok(transition)
+    }
+
+    /// `None` if the hour or minute is out of range.
+    pub fn time(&self) -> Option<NaiveTime> {
+        NaiveTime::from_hms_opt(self.hour as u32, self.minute as u32, 0)
+    }
+}
+
+impl fmt::Display for Transition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02}:{:02} {}", self.hour, self.minute, self.level)
+    }
+}
+
+/// Changes the LEDs at set times of the day, such as dimming them at night.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BrightnessSchedule {
+    pub enabled: bool,
+    /// Sorted by time. Each day starts at the level of the last one.
+    pub transitions: Vec<Transition>,
+    /// Keep the LEDs off all Saturday and Sunday. Monday starts at the level
+    /// of the last transition, or with the effect back on if there are none.
+    pub off_on_weekends: bool,
+    /// The effect to turn back on after the schedule turned it off, kept
+    /// here so it survives reconnecting the macropad or restarting the app.
+    pub effect_before_off: Option<u8>,
+}
+
+impl BrightnessSchedule {
+    /// Adds a transition, replacing any at the same time.
+    pub fn add(&mut self, transition: Transition) {
+        self.transitions
+            .retain(|t| (t.hour, t.minute) != (transition.hour, transition.minute));
+        self.transitions.push(transition);
+        self.transitions.sort_by_key(|t| (t.hour, t.minute));
+    }
+
+    fn is_off(&self, date: NaiveDate) -> bool {
+        self.off_on_weekends && matches!(date.weekday(), Weekday::Sat | Weekday::Sun)
+    }
+
+    /// Every change from a week before `now` to a week after, in order.
+    fn changes_around(&self, now: NaiveDateTime) -> Vec<(NaiveDateTime, Level)> {
+        let mut transitions: Vec<_> = self
+            .transitions
+            .iter()
+            .filter_map(|t| Some((t.time()?, t.level)))
+            .collect();
+        transitions.sort_by_key(|(time, _)| *time);
+        let midnight = NaiveTime::from_hms_opt(0, 0, 0).unwrap();
+
+        let mut changes = Vec::new();
+        for offset in -8..=8 {
+            let date = now.date() + chrono::Duration::days(offset);
+            if self.is_off(date) {
+                changes.push((date.and_time(midnight), Level::Off));
+                continue;
+            }
+
+            if matches!(date.pred_opt(), Some(previous) if self.is_off(previous)) {
+                let level = transitions.last().map_or(Level::On, |(_, level)| *level);
+                changes.push((date.and_time(midnight), level));
+            }
+            for (time, level) in &transitions {
+                changes.push((date.and_time(*time), *level));
+            }
+        }
+
+        changes
+    }
+
+    /// The level the schedule asks for at `now` and when it started, `None`
+    /// if the schedule never changes anything.
+    pub fn current(&self, now: NaiveDateTime) -> Option<(Level, NaiveDateTime)> {
+        self.changes_around(now)
+            .into_iter()
+            .take_while(|(at, _)| *at <= now)
+            .last()
+            .map(|(at, level)| (level, at))
+    }
+
+    /// When the level next changes after `now`.
+    pub fn next_change(&self, now: NaiveDateTime) -> Option<NaiveDateTime> {
+        self.changes_around(now)
+            .into_iter()
+            .map(|(at, _)| at)
+            .find(|at| *at > now)
+    }
+}
+
+/// Applies a schedule to a macropad. Each level is only written once when it
+/// starts, so a change made by hand holds until the next transition.
+#[derive(Debug, Clone, Default)]
+pub struct ScheduleRunner {
+    /// When the level last written started.
+    applied: Option<NaiveDateTime>,
+    overridden: bool,
+}
+
+impl ScheduleRunner {
+    /// The writes due at `now` for a pad with the LEDs set as in `leds`.
+    /// Turning the LEDs off or back on changes `schedule`, which should be
+    /// saved when it does.
+    pub fn update(
+        &mut self,
+        schedule: &mut BrightnessSchedule,
+        now: NaiveDateTime,
+        leds: &LedConfig,
+    ) -> Vec<MacropadCommand> {
+        if !schedule.enabled {
+            return Vec::new();
+        }
+
+        let (level, since) = match schedule.current(now) {
+            Some(current) => current,
+            None => return Vec::new(),
+        };
+        if self.applied == Some(since) {
+            return Vec::new();
+        }
+        self.applied = Some(since);
+        self.overridden = false;
+
+        if level == Level::Off {
+            if leds.effect != LedEffect::None {
+                schedule.effect_before_off = Some(leds.effect as u8);
+            }
+            return vec![MacropadCommand::LedEffect(LedEffect::None)];
+        }
+
+        let mut commands = Vec::new();
+        if let Level::Brightness(brightness) = level {
+            commands.push(MacropadCommand::LedBrightness(brightness));
+        }
+        if let Some(effect) = schedule.effect_before_off.take() {
+            if leds.effect == LedEffect::None {
+                commands.push(MacropadCommand::LedEffect(LedEffect::from(effect)));
+            }
+        }
+
+        commands
+    }
+
+    /// Notes the LEDs were changed by hand, which holds until the next
+    /// transition.
+    pub fn override_level(&mut self) {
+        if self.applied.is_some() {
+            self.overridden = true;
+        }
+    }
+
+    pub fn is_overridden(&self) -> bool {
+        self.overridden
+    }
+
+    /// Applies the current level again on the next update, for a newly
+    /// connected pad or a changed schedule.
+    pub fn reset(&mut self) {
+        self.applied = None;
+        self.overridden = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2024-01-01 is a Monday.
+    fn at(day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2024, 1, day)
+            .unwrap()
+            .and_hms_opt(hour, minute, 0)
+            .unwrap()
+    }
+
+    fn schedule() -> BrightnessSchedule {
+        let mut schedule = BrightnessSchedule {
+            enabled: true,
+            ..Default::default()
+        };
+        schedule.add(Transition::parse("20:00", "40").unwrap());
+        schedule.add(Transition::parse("8:00", "200").unwrap());
+        schedule
+    }
+
+    fn leds(effect: LedEffect) -> LedConfig {
+        LedConfig {
+            effect,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parses_transitions() {
+        assert_eq!(
+            Transition::parse(" 07:30", "Off"),
+            Ok(Transition {
+                hour: 7,
+                minute: 30,
+                level: Level::Off,
+            })
+        );
+        assert_eq!(parse_level("255"), Ok(Level::Brightness(255)));
+        assert_eq!(Transition::parse("24:00", "on"), Err(()));
+        assert_eq!(Transition::parse("12", "on"), Err(()));
+        assert_eq!(Transition::parse("12:00", "256"), Err(()));
+    }
+
+    #[test]
+    fn days_wrap_around_midnight() {
+        let schedule = schedule();
+
+        assert_eq!(
+            schedule.current(at(2, 12, 0)),
+            Some((Level::Brightness(200), at(2, 8, 0)))
+        );
+        assert_eq!(
+            schedule.current(at(2, 3, 0)),
+            Some((Level::Brightness(40), at(1, 20, 0)))
+        );
+        assert_eq!(schedule.next_change(at(2, 3, 0)), Some(at(2, 8, 0)));
+        assert_eq!(BrightnessSchedule::default().current(at(2, 3, 0)), None);
+    }
+
+    #[test]
+    fn weekends_are_off() {
+        let mut schedule = schedule();
+        schedule.off_on_weekends = true;
+
+        assert_eq!(
+            schedule.current(at(6, 12, 0)),
+            Some((Level::Off, at(6, 0, 0)))
+        );
+        assert_eq!(
+            schedule.current(at(7, 21, 0)),
+            Some((Level::Off, at(7, 0, 0)))
+        );
+        assert_eq!(schedule.next_change(at(6, 12, 0)), Some(at(7, 0, 0)));
+        // Monday night carries on from Sunday evening's transition
+        assert_eq!(
+            schedule.current(at(8, 1, 0)),
+            Some((Level::Brightness(40), at(8, 0, 0)))
+        );
+
+        schedule.transitions.clear();
+        assert_eq!(
+            schedule.current(at(8, 1, 0)),
+            Some((Level::On, at(8, 0, 0)))
+        );
+    }
+
+    #[test]
+    fn changes_by_hand_hold_until_the_next_transition() {
+        let mut schedule = schedule();
+        let mut runner = ScheduleRunner::default();
+
+        assert!(matches!(
+            runner
+                .update(&mut schedule, at(2, 12, 0), &leds(LedEffect::Static))
+                .as_slice(),
+            [MacropadCommand::LedBrightness(200)]
+        ));
+
+        runner.override_level();
+        assert!(runner.is_overridden());
+        assert!(runner
+            .update(&mut schedule, at(2, 19, 59), &leds(LedEffect::Static))
+            .is_empty());
+
+        assert!(matches!(
+            runner
+                .update(&mut schedule, at(2, 20, 0), &leds(LedEffect::Static))
+                .as_slice(),
+            [MacropadCommand::LedBrightness(40)]
+        ));
+        assert!(!runner.is_overridden());
+    }
+
+    #[test]
+    fn effect_comes_back_after_being_off() {
+        let mut schedule = schedule();
+        schedule.off_on_weekends = true;
+        let mut runner = ScheduleRunner::default();
+
+        assert!(matches!(
+            runner
+                .update(&mut schedule, at(7, 12, 0), &leds(LedEffect::Rainbow))
+                .as_slice(),
+            [MacropadCommand::LedEffect(LedEffect::None)]
+        ));
+        assert!(matches!(
+            runner
+                .update(&mut schedule, at(8, 8, 0), &leds(LedEffect::None))
+                .as_slice(),
+            [
+                MacropadCommand::LedBrightness(200),
+                MacropadCommand::LedEffect(LedEffect::Rainbow)
+            ]
+        ));
+        assert_eq!(schedule.effect_before_off, None);
+    }
+
+    #[test]
+    fn effect_comes_back_after_a_restart() {
+        let mut schedule = schedule();
+        schedule.off_on_weekends = true;
+
+        ScheduleRunner::default().update(&mut schedule, at(6, 12, 0), &leds(LedEffect::Breathing));
+
+        // Started again over the weekend with the pad already off
+        let mut schedule: BrightnessSchedule =
+            serde_json::from_str(&serde_json::to_string(&schedule).unwrap()).unwrap();
+        let mut runner = ScheduleRunner::default();
+        assert!(matches!(
+            runner
+                .update(&mut schedule, at(7, 12, 0), &leds(LedEffect::None))
+                .as_slice(),
+            [MacropadCommand::LedEffect(LedEffect::None)]
+        ));
+        assert!(matches!(
+            runner
+                .update(&mut schedule, at(8, 8, 0), &leds(LedEffect::None))
+                .as_slice(),
+            [
+                MacropadCommand::LedBrightness(200),
+                MacropadCommand::LedEffect(LedEffect::Breathing)
+            ]
+        ));
+    }
+}
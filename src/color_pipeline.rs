@@ -0,0 +1,231 @@
+use iced::Color;
+use serde::{Deserialize, Serialize};
+
+use crate::hid_manager::MacropadCommand;
+
+/// Converts between colors as they are shown on screen and the values
+/// written to the LEDs. Screens expect gamma encoded colors while the light
+/// of an LED goes up linearly with the value written, and the green LED is
+/// brighter than the other two.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ColorPipeline {
+    /// The gamma screen colors are encoded with.
+    pub gamma: f32,
+    /// How far each of the red, green and blue LEDs is turned down so white
+    /// looks white.
+    pub correction: (f32, f32, f32),
+}
+
+impl Default for ColorPipeline {
+    /// Typical for WS2812 style LEDs.
+    fn default() -> Self {
+        Self {
+            gamma: 2.2,
+            correction: (1.0, 0.69, 0.94),
+        }
+    }
+}
+
+/// The fraction of the light an LED gives off which looks like `lightness`
+/// of its full brightness, from CIE 1976 L*.
+fn intensity(lightness: f32) -> f32 {
+    let lightness = lightness.clamp(0.0, 1.0) * 100.0;
+
+    if lightness > 8.0 {
+        ((lightness + 16.0) / 116.0).powi(3)
+    } else {
+        lightness / 903.3
+    }
+}
+
+/// How bright `intensity` of the full light looks, see [`intensity`].
+fn lightness(intensity: f32) -> f32 {
+    let intensity = intensity.clamp(0.0, 1.0);
+
+    let lightness = if intensity > 0.008856 {
+        116.0 * intensity.cbrt() - 16.0
+    } else {
+        903.3 * intensity
+    };
+    lightness / 100.0
+}
+
+fn to_u8(value: f32) -> u8 {
+    (value.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+impl ColorPipeline {
+    /// Writes colors and brightness exactly as picked.
+    pub const LINEAR: ColorPipeline = ColorPipeline {
+        gamma: 1.0,
+        correction: (1.0, 1.0, 1.0),
+    };
+
+    fn gamma(&self) -> f32 {
+        if self.gamma.is_finite() && self.gamma > 0.0 {
+            self.gamma
+        } else {
+            1.0
+        }
+    }
+
+    fn correction(&self) -> [f32; 3] {
+        let (r, g, b) = self.correction;
+        [r, g, b].map(|channel| {
+            if channel.is_finite() {
+                channel.clamp(0.0, 1.0)
+            } else {
+                1.0
+            }
+        })
+    }
+
+    /// The LED values giving off the light of a screen color, channels from
+    /// 0 to 1.
+    pub fn device_rgb(&self, rgb: [f32; 3]) -> [f32; 3] {
+        let correction = self.correction();
+
+        std::array::from_fn(|i| rgb[i].clamp(0.0, 1.0).powf(self.gamma()) * correction[i])
+    }
+
+    /// The screen color for LED values, channels from 0 to 1.
+    pub fn screen_rgb(&self, rgb: [f32; 3]) -> [f32; 3] {
+        let correction = self.correction();
+
+        std::array::from_fn(|i| {
+            let linear = if correction[i] > 0.0 {
+                rgb[i] / correction[i]
+            } else {
+                0.0
+            };
+            linear.clamp(0.0, 1.0).powf(1.0 / self.gamma())
+        })
+    }
+
+    /// The value to write for a color picked on screen.
+    pub fn to_device(&self, color: (u8, u8, u8)) -> (u8, u8, u8) {
+        let [r, g, b] = self.device_rgb([color.0, color.1, color.2].map(|c| c as f32 / 255.0));
+        (to_u8(r), to_u8(g), to_u8(b))
+    }
+
+    /// The color to show for a value read from the device.
+    pub fn to_screen(&self, color: (u8, u8, u8)) -> (u8, u8, u8) {
+        let [r, g, b] = self.screen_rgb([color.0, color.1, color.2].map(|c| c as f32 / 255.0));
+        (to_u8(r), to_u8(g), to_u8(b))
+    }
+
+    /// The brightness to write for one which should look `perceived` of the
+    /// way to full.
+    pub fn brightness_to_device(&self, perceived: u8) -> u8 {
+        if *self == Self::LINEAR {
+            return perceived;
+        }
+
+        to_u8(intensity(perceived as f32 / 255.0))
+    }
+
+    /// How bright a brightness read from the device looks.
+    pub fn brightness_to_screen(&self, brightness: u8) -> u8 {
+        if *self == Self::LINEAR {
+            return brightness;
+        }
+
+        to_u8(lightness(brightness as f32 / 255.0))
+    }
+
+    /// An LED as computed by [`crate::led_effects`], with its intensity as
+    /// alpha, the way it looks on the pad.
+    pub fn screen_led(&self, led: Color) -> Color {
+        let [r, g, b] = self.screen_rgb([led.r, led.g, led.b]);
+        let a = if *self == Self::LINEAR {
+            led.a
+        } else {
+            lightness(led.a)
+        };
+
+        Color::from_rgba(r, g, b, a)
+    }
+
+    pub fn screen_leds(&self, leds: [Color; 4]) -> [Color; 4] {
+        leds.map(|led| self.screen_led(led))
+    }
+
+    /// Converts the colors and brightness in a command given in screen
+    /// terms to the values to write.
+    pub fn to_device_command(&self, command: MacropadCommand) -> MacropadCommand {
+        match command {
+            MacropadCommand::KeyColor(i, color) => {
+                MacropadCommand::KeyColor(i, self.to_device(color))
+            }
+            MacropadCommand::LedBaseColor(color) => {
+                MacropadCommand::LedBaseColor(self.to_device(color))
+            }
+            MacropadCommand::LedBrightness(brightness) => {
+                MacropadCommand::LedBrightness(self.brightness_to_device(brightness))
+            }
+            command => command,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn linear_changes_nothing() {
+        let pipeline = ColorPipeline::LINEAR;
+
+        for value in [0, 1, 77, 128, 254, 255] {
+            let color = (value, 255 - value, value / 2);
+            assert_eq!(pipeline.to_device(color), color);
+            assert_eq!(pipeline.to_screen(color), color);
+            assert_eq!(pipeline.brightness_to_device(value), value);
+        }
+    }
+
+    #[test]
+    fn correction_balances_white() {
+        let pipeline = ColorPipeline::default();
+
+        assert_eq!(pipeline.to_device((255, 255, 255)), (255, 176, 240));
+        assert_eq!(pipeline.to_screen((255, 176, 240)), (255, 255, 255));
+        // Half way on screen is much less than half the light
+        assert_eq!(pipeline.to_device((128, 0, 0)), (56, 0, 0));
+    }
+
+    #[test]
+    fn screen_colors_survive_a_round_trip() {
+        let pipeline = ColorPipeline::default();
+
+        for value in (64..=255).step_by(16) {
+            let color = (value as u8, value as u8, (319 - value) as u8);
+            let back = pipeline.to_screen(pipeline.to_device(color));
+
+            for (a, b) in [(color.0, back.0), (color.1, back.1), (color.2, back.2)] {
+                assert!(
+                    (a as i16 - b as i16).abs() <= 2,
+                    "{:?} != {:?}",
+                    color,
+                    back
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn brightness_follows_perceived_lightness() {
+        let pipeline = ColorPipeline::default();
+
+        assert_eq!(pipeline.brightness_to_device(0), 0);
+        assert_eq!(pipeline.brightness_to_device(255), 255);
+        // Half as bright looking takes under a fifth of the light
+        assert_eq!(pipeline.brightness_to_device(128), 47);
+
+        for brightness in [0, 10, 64, 128, 200, 255] {
+            let device = pipeline.brightness_to_device(brightness);
+            assert!((pipeline.brightness_to_screen(device) as i16 - brightness as i16).abs() <= 3);
+        }
+    }
+}
@@ -0,0 +1,530 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Once};
+use std::time::{Duration, Instant};
+
+use iced_futures::futures;
+use iced_native::subscription::{self, Subscription};
+use macropad_protocol::data_protocol::LedEffect;
+use serde::{Deserialize, Serialize};
+
+use futures::channel::{mpsc, oneshot};
+use futures::stream::StreamExt;
+
+use crate::app_config;
+use crate::hid_manager::MacropadCommand;
+use crate::macro_parser::Macropad;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EffectName {
+    None,
+    Static,
+    Breathing,
+    BreathingSpaced,
+    ColorCycle,
+    Rainbow,
+}
+
+impl From<EffectName> for LedEffect {
+    fn from(effect: EffectName) -> Self {
+        match effect {
+            EffectName::None => LedEffect::None,
+            EffectName::Static => LedEffect::Static,
+            EffectName::Breathing => LedEffect::Breathing,
+            EffectName::BreathingSpaced => LedEffect::BreathingSpaced,
+            EffectName::ColorCycle => LedEffect::ColorCycle,
+            EffectName::Rainbow => LedEffect::Rainbow,
+        }
+    }
+}
+
+/// One setting to change, for example
+/// `{"command": "key_color", "key": 0, "color": [255, 0, 0]}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum ControlCommand {
+    KeyColor { key: u8, color: (u8, u8, u8) },
+    LedBaseColor { color: (u8, u8, u8) },
+    LedEffect { effect: EffectName },
+    LedBrightness { brightness: u8 },
+    LedEffectPeriod { period: f32 },
+    LedEffectOffset { offset: f32 },
+}
+
+impl From<&ControlCommand> for MacropadCommand {
+    fn from(command: &ControlCommand) -> Self {
+        match command {
+            ControlCommand::KeyColor { key, color } => MacropadCommand::KeyColor(*key, *color),
+            ControlCommand::LedBaseColor { color } => MacropadCommand::LedBaseColor(*color),
+            ControlCommand::LedEffect { effect } => MacropadCommand::LedEffect((*effect).into()),
+            ControlCommand::LedBrightness { brightness } => {
+                MacropadCommand::LedBrightness(*brightness)
+            }
+            ControlCommand::LedEffectPeriod { period } => MacropadCommand::LedEffectPeriod(*period),
+            ControlCommand::LedEffectOffset { offset } => MacropadCommand::LedEffectOffset(*offset),
+        }
+    }
+}
+
+/// The longest a change can be held before it is put back, a day.
+pub const MAX_RESTORE_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// One line sent to the control socket. With `restore_after` set the
+/// settings go back to how they were after that many seconds, so a
+/// notification does not overwrite the saved configuration. Colors and
+/// brightness are given as they would be picked in the configurator.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ControlRequest {
+    pub commands: Vec<ControlCommand>,
+    #[serde(default)]
+    pub restore_after: Option<f32>,
+}
+
+impl ControlRequest {
+    pub fn commands(&self) -> Vec<MacropadCommand> {
+        self.commands.iter().map(MacropadCommand::from).collect()
+    }
+
+    /// At most [`MAX_RESTORE_AFTER`], so the time it is due can always be
+    /// worked out.
+    pub fn restore_after(&self) -> Result<Option<Duration>, String> {
+        self.restore_after
+            .map(|seconds| {
+                Duration::try_from_secs_f32(seconds)
+                    .ok()
+                    .filter(|after| *after <= MAX_RESTORE_AFTER)
+                    .ok_or_else(|| format!("Invalid restore_after: {}", seconds))
+            })
+            .transpose()
+    }
+}
+
+/// The line sent back for every request.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ControlResponse {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl From<Result<(), String>> for ControlResponse {
+    fn from(result: Result<(), String>) -> Self {
+        Self {
+            ok: result.is_ok(),
+            error: result.err(),
+        }
+    }
+}
+
+/// A request waiting for the app to act on it.
+#[derive(Clone)]
+pub struct Request {
+    pub request: ControlRequest,
+    responder: Arc<Mutex<Option<oneshot::Sender<Result<(), String>>>>>,
+}
+
+impl Request {
+    /// Answers the program which sent the request, only the first answer is
+    /// sent.
+    pub fn respond(&self, result: Result<(), String>) {
+        if let Some(sender) = self.responder.lock().unwrap().take() {
+            sender.send(result).ok();
+        }
+    }
+}
+
+impl fmt::Debug for Request {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.request, f)
+    }
+}
+
+/// The value `command` would overwrite, as the command which puts it back.
+/// `None` if the macropad has no such setting.
+pub fn current_value(command: &MacropadCommand, macropad: &Macropad) -> Option<MacropadCommand> {
+    let led = &macropad.led_config;
+
+    Some(match command {
+        MacropadCommand::KeyColor(i, _) => {
+            MacropadCommand::KeyColor(*i, macropad.key_configs.get(*i as usize)?.key_color)
+        }
+        MacropadCommand::LedBaseColor(_) => MacropadCommand::LedBaseColor(led.base_color),
+        MacropadCommand::LedEffect(_) => MacropadCommand::LedEffect(led.effect),
+        MacropadCommand::LedBrightness(_) => MacropadCommand::LedBrightness(led.brightness),
+        MacropadCommand::LedEffectPeriod(_) => MacropadCommand::LedEffectPeriod(led.effect_period),
+        MacropadCommand::LedEffectOffset(_) => MacropadCommand::LedEffectOffset(led.effect_offset),
+        _ => return None,
+    })
+}
+
+/// Keeps the settings temporary changes overwrote so they can be put back.
+/// Overlapping changes keep the values from before the first one and are
+/// all put back together once the last one runs out.
+#[derive(Debug, Default)]
+pub struct Restore {
+    due: Option<Instant>,
+    previous: Vec<MacropadCommand>,
+}
+
+impl Restore {
+    /// Remembers what `commands` are about to overwrite, to be put back at
+    /// `due`.
+    pub fn hold(&mut self, commands: &[MacropadCommand], macropad: &Macropad, due: Instant) {
+        for command in commands {
+            if self
+                .previous
+                .iter()
+                .any(|previous| previous.setting() == command.setting())
+            {
+                continue;
+            }
+
+            if let Some(current) = current_value(command, macropad) {
+                self.previous.push(current);
+            }
+        }
+
+        self.due = Some(self.due.map_or(due, |held| held.max(due)));
+    }
+
+    /// Forgets the settings `commands` change for good.
+    pub fn release(&mut self, commands: &[MacropadCommand]) {
+        self.previous.retain(|previous| {
+            commands
+                .iter()
+                .all(|command| command.setting() != previous.setting())
+        });
+
+        if self.previous.is_empty() {
+            self.due = None;
+        }
+    }
+
+    /// Takes the commands putting everything back once they are due.
+    pub fn due(&mut self, now: Instant) -> Vec<MacropadCommand> {
+        match self.due {
+            Some(due) if due <= now => {
+                self.due = None;
+                std::mem::take(&mut self.previous)
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Takes the commands putting everything back now, whether or not they
+    /// are due.
+    pub fn take(&mut self) -> Vec<MacropadCommand> {
+        self.due = None;
+        std::mem::take(&mut self.previous)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.previous.is_empty()
+    }
+}
+
+static START: Once = Once::new();
+static LISTENING: AtomicBool = AtomicBool::new(false);
+static ERROR: Mutex<Option<String>> = Mutex::new(None);
+static SUBSCRIBERS: Mutex<Vec<mpsc::UnboundedSender<Request>>> = Mutex::new(Vec::new());
+
+pub fn socket_path() -> Option<PathBuf> {
+    app_config::data_dir().map(|dir| dir.join("control.sock"))
+}
+
+/// Whether the control socket is accepting connections.
+pub fn is_listening() -> bool {
+    LISTENING.load(Ordering::Relaxed)
+}
+
+/// Why the control socket could not be opened, shown in the settings.
+pub fn error() -> Option<String> {
+    ERROR.lock().unwrap().clone()
+}
+
+/// Emits every request sent to the control socket, which is opened the first
+/// time this is subscribed to. Requests sent while nothing is subscribed are
+/// refused.
+pub fn requests() -> Subscription<Request> {
+    struct Requests;
+
+    START.call_once(|| {
+        #[cfg(unix)]
+        std::thread::spawn(|| {
+            if let Err(error) = serve() {
+                *ERROR.lock().unwrap() = Some(error.to_string());
+            }
+            LISTENING.store(false, Ordering::Relaxed);
+        });
+
+        #[cfg(not(unix))]
+        {
+            *ERROR.lock().unwrap() = Some(String::from("Only available on Linux and macOS"));
+        }
+    });
+
+    subscription::unfold(
+        std::any::TypeId::of::<Requests>(),
+        None,
+        |receiver: Option<mpsc::UnboundedReceiver<Request>>| async move {
+            let mut receiver = match receiver {
+                Some(receiver) => receiver,
+                None => {
+                    let (sender, receiver) = mpsc::unbounded();
+                    SUBSCRIBERS.lock().unwrap().push(sender);
+                    receiver
+                }
+            };
+
+            let request = receiver.select_next_some().await;
+            (Some(request), Some(receiver))
+        },
+    )
+}
+
+/// Hands the request to the app and waits for its answer.
+async fn dispatch(request: ControlRequest) -> Result<(), String> {
+    let (sender, receiver) = oneshot::channel();
+    let mut request = Request {
+        request,
+        responder: Arc::new(Mutex::new(Some(sender))),
+    };
+
+    {
+        let mut subscribers = SUBSCRIBERS.lock().unwrap();
+        subscribers.retain(|subscriber| !subscriber.is_closed());
+
+        let mut delivered = false;
+        for subscriber in subscribers.iter() {
+            match subscriber.unbounded_send(request) {
+                Ok(_) => {
+                    delivered = true;
+                    break;
+                }
+                Err(error) => request = error.into_inner(),
+            }
+        }
+
+        if !delivered {
+            return Err(String::from("The control API is turned off"));
+        }
+    }
+
+    receiver
+        .await
+        .unwrap_or_else(|_| Err(String::from("The request was dropped")))
+}
+
+#[cfg(unix)]
+fn serve() -> std::io::Result<()> {
+    use std::io::{Error, ErrorKind};
+    use std::os::unix::net::UnixStream;
+
+    let path = socket_path().ok_or_else(|| Error::new(ErrorKind::NotFound, "No data directory"))?;
+    std::fs::create_dir_all(path.parent().unwrap())?;
+
+    // A socket nobody answers on is left over from an earlier run
+    if UnixStream::connect(&path).is_ok() {
+        return Err(Error::new(
+            ErrorKind::AddrInUse,
+            "Another configurator is listening",
+        ));
+    }
+    std::fs::remove_file(&path).ok();
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_io()
+        .build()?;
+
+    runtime.block_on(async move {
+        let listener = tokio::net::UnixListener::bind(&path)?;
+        LISTENING.store(true, Ordering::Relaxed);
+
+        loop {
+            let (stream, _) = listener.accept().await?;
+            tokio::spawn(handle_client(stream));
+        }
+    })
+}
+
+/// Answers each line the client sends with one line.
+#[cfg(unix)]
+async fn handle_client(stream: tokio::net::UnixStream) -> std::io::Result<()> {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let result = match serde_json::from_str::<ControlRequest>(&line) {
+            Ok(request) => dispatch(request).await,
+            Err(error) => Err(format!("Invalid request: {}", error)),
+        };
+
+        let mut response = serde_json::to_string(&ControlResponse::from(result))
+            .map_err(|error| std::io::Error::new(std::io::ErrorKind::Other, error))?;
+        response.push('\n');
+        writer.write_all(response.as_bytes()).await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profile::LedPreset;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn parses_requests() {
+        let request: ControlRequest = serde_json::from_str(
+            r#"{"commands": [
+                {"command": "key_color", "key": 2, "color": [255, 0, 0]},
+                {"command": "led_effect", "effect": "breathing_spaced"}
+            ], "restore_after": 1.5}"#,
+        )
+        .unwrap();
+
+        assert!(matches!(
+            request.commands().as_slice(),
+            [
+                MacropadCommand::KeyColor(2, (255, 0, 0)),
+                MacropadCommand::LedEffect(LedEffect::BreathingSpaced)
+            ]
+        ));
+        assert_eq!(request.restore_after(), Ok(Some(ms(1500))));
+
+        let request: ControlRequest = serde_json::from_str(
+            r#"{"commands": [{"command": "led_brightness", "brightness": 10}], "restore_after": -1}"#,
+        )
+        .unwrap();
+        assert!(request.restore_after().is_err());
+
+        // Large enough to overflow an `Instant`
+        let request: ControlRequest = serde_json::from_str(
+            r#"{"commands": [{"command": "led_brightness", "brightness": 10}], "restore_after": 1e19}"#,
+        )
+        .unwrap();
+        assert!(request.restore_after().is_err());
+
+        assert!(serde_json::from_str::<ControlRequest>(
+            r#"{"commands": [{"command": "bootloader"}]}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn restores_the_values_from_before_the_first_change() {
+        let mut macropad = Macropad::offline();
+        macropad.key_configs[0].key_color = (1, 2, 3);
+        let now = Instant::now();
+        let mut restore = Restore::default();
+
+        let first = [MacropadCommand::KeyColor(0, (255, 0, 0))];
+        restore.hold(&first, &macropad, now + ms(1000));
+        first[0].apply(&mut macropad);
+
+        // A second notification on the same key still restores the original
+        // color, once both have run out
+        restore.hold(
+            &[
+                MacropadCommand::KeyColor(0, (0, 255, 0)),
+                MacropadCommand::LedBrightness(255),
+            ],
+            &macropad,
+            now + ms(500),
+        );
+
+        assert!(restore.due(now + ms(500)).is_empty());
+        let due = restore.due(now + ms(1000));
+        assert!(matches!(
+            due.as_slice(),
+            [
+                MacropadCommand::KeyColor(0, (1, 2, 3)),
+                MacropadCommand::LedBrightness(0xA0)
+            ]
+        ));
+        assert!(restore.is_empty());
+    }
+
+    #[test]
+    fn permanent_changes_are_not_restored() {
+        let macropad = Macropad::offline();
+        let now = Instant::now();
+        let mut restore = Restore::default();
+
+        restore.hold(
+            &[
+                MacropadCommand::KeyColor(3, (255, 0, 0)),
+                MacropadCommand::LedEffect(LedEffect::Rainbow),
+            ],
+            &macropad,
+            now,
+        );
+        restore.release(&[MacropadCommand::LedEffect(LedEffect::Static)]);
+
+        assert!(matches!(
+            restore.due(now).as_slice(),
+            [MacropadCommand::KeyColor(3, _)]
+        ));
+
+        restore.hold(&[MacropadCommand::KeyColor(3, (1, 1, 1))], &macropad, now);
+        restore.release(&[MacropadCommand::KeyColor(3, (2, 2, 2))]);
+        assert!(restore.due(now).is_empty());
+    }
+
+    #[test]
+    fn held_settings_can_be_taken_early() {
+        let mut macropad = Macropad::offline();
+        macropad.key_configs[2].key_color = (1, 2, 3);
+        let now = Instant::now();
+        let mut restore = Restore::default();
+
+        restore.hold(
+            &[MacropadCommand::KeyColor(2, (255, 0, 0))],
+            &macropad,
+            now + ms(1000),
+        );
+
+        assert!(matches!(
+            restore.take().as_slice(),
+            [MacropadCommand::KeyColor(2, (1, 2, 3))]
+        ));
+        assert!(restore.is_empty());
+        assert!(restore.due(now + ms(1000)).is_empty());
+    }
+
+    #[test]
+    fn applying_a_preset_releases_held_settings() {
+        let macropad = Macropad::offline();
+        let now = Instant::now();
+        let mut restore = Restore::default();
+
+        restore.hold(
+            &[
+                MacropadCommand::KeyColor(1, (255, 0, 0)),
+                MacropadCommand::LedBrightness(255),
+            ],
+            &macropad,
+            now,
+        );
+
+        // Presets and profiles are applied as one transaction which sets
+        // every LED setting and key color
+        let preset = LedPreset::from_macropad("calm", &macropad);
+        restore.release(&preset.commands());
+
+        assert!(restore.is_empty());
+        assert!(restore.due(now).is_empty());
+    }
+}
@@ -0,0 +1,249 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, Once};
+use std::time::Duration;
+
+use iced_futures::futures;
+use iced_native::subscription::{self, Subscription};
+
+use futures::channel::mpsc;
+use futures::stream::StreamExt;
+
+/// How often to rescan when no hot-plug events are available.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// How often to rescan anyway while hot-plug events are available, in case
+/// one was missed.
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceKind {
+    /// The macropad's HID interface.
+    Hid,
+    /// The UF2 mass-storage drive of the bootloader.
+    Drive,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Change {
+    pub kind: DeviceKind,
+    /// `false` if the device was removed. A drive which changed, such as
+    /// being mounted, is reported as attached.
+    pub attached: bool,
+}
+
+static START: Once = Once::new();
+static LIVE: AtomicBool = AtomicBool::new(false);
+static ERROR: Mutex<Option<String>> = Mutex::new(None);
+static WAITING: Mutex<Vec<(DeviceKind, mpsc::UnboundedSender<Change>)>> = Mutex::new(Vec::new());
+static LISTENERS: Mutex<Vec<mpsc::UnboundedSender<Change>>> = Mutex::new(Vec::new());
+
+/// Whether hot-plug events are being received, if not everything falls back
+/// to polling.
+pub fn is_live() -> bool {
+    start();
+    LIVE.load(Ordering::Relaxed)
+}
+
+/// Why hot-plug events are not being received, shown in the settings.
+pub fn error() -> Option<String> {
+    start();
+    ERROR.lock().unwrap().clone()
+}
+
+/// Waits until a device of `kind` is attached or removed. Returns `None` if
+/// nothing happened before it was time to rescan anyway, callers should
+/// rescan either way.
+pub async fn wait(kind: DeviceKind) -> Option<Change> {
+    if !is_live() {
+        tokio::time::sleep(POLL_INTERVAL).await;
+        return None;
+    }
+
+    wait_for(kind, WATCH_INTERVAL).await
+}
+
+async fn wait_for(kind: DeviceKind, interval: Duration) -> Option<Change> {
+    let (sender, mut receiver) = mpsc::unbounded();
+    {
+        let mut waiting = WAITING.lock().unwrap();
+        // Waiters whose future was dropped before it finished
+        waiting.retain(|(_, sender)| !sender.is_closed());
+        waiting.push((kind, sender));
+    }
+
+    let change = tokio::time::timeout(interval, receiver.select_next_some())
+        .await
+        .ok();
+
+    drop(receiver);
+    WAITING
+        .lock()
+        .unwrap()
+        .retain(|(_, sender)| !sender.is_closed());
+
+    change
+}
+
+/// Emits every attach and detach as it happens. Nothing is emitted while
+/// falling back to polling.
+pub fn changes() -> Subscription<Change> {
+    struct Changes;
+
+    start();
+    subscription::unfold(
+        std::any::TypeId::of::<Changes>(),
+        None,
+        |receiver: Option<mpsc::UnboundedReceiver<Change>>| async move {
+            let mut receiver = receiver.unwrap_or_else(listen);
+            let change = receiver.select_next_some().await;
+
+            (Some(change), Some(receiver))
+        },
+    )
+}
+
+fn listen() -> mpsc::UnboundedReceiver<Change> {
+    let (sender, receiver) = mpsc::unbounded();
+    LISTENERS.lock().unwrap().push(sender);
+    receiver
+}
+
+fn notify(change: Change) {
+    WAITING.lock().unwrap().retain(|(kind, sender)| {
+        if *kind == change.kind {
+            // The waiter is done after one change
+            sender.unbounded_send(change).ok();
+            false
+        } else {
+            !sender.is_closed()
+        }
+    });
+
+    LISTENERS
+        .lock()
+        .unwrap()
+        .retain(|sender| sender.unbounded_send(change).is_ok());
+}
+
+fn start() {
+    START.call_once(|| {
+        #[cfg(target_os = "linux")]
+        std::thread::spawn(|| {
+            if let Err(error) = udev_monitor() {
+                *ERROR.lock().unwrap() = Some(error.to_string());
+            }
+            LIVE.store(false, Ordering::Relaxed);
+        });
+
+        #[cfg(not(target_os = "linux"))]
+        {
+            *ERROR.lock().unwrap() = Some(String::from("Only available on Linux"));
+        }
+    });
+}
+
+#[cfg(target_os = "linux")]
+fn udev_monitor() -> std::io::Result<()> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_io()
+        .build()?;
+
+    runtime.block_on(watch_udev())
+}
+
+#[cfg(target_os = "linux")]
+async fn watch_udev() -> std::io::Result<()> {
+    use tokio::io::unix::AsyncFd;
+
+    // Any change to a HID or block device triggers a rescan, the scans
+    // decide whether it was a macropad
+    let socket = udev::MonitorBuilder::new()?
+        .match_subsystem("hidraw")?
+        .match_subsystem_devtype("block", "disk")?
+        .match_subsystem_devtype("block", "partition")?
+        .listen()?;
+    let mut socket = AsyncFd::new(socket)?;
+    LIVE.store(true, Ordering::Relaxed);
+
+    loop {
+        let mut guard = socket.readable_mut().await?;
+
+        for event in guard.get_inner_mut().iter() {
+            let kind = match event.subsystem().and_then(|s| s.to_str()) {
+                Some("hidraw") => DeviceKind::Hid,
+                Some("block") => DeviceKind::Drive,
+                _ => continue,
+            };
+
+            match event.event_type() {
+                udev::EventType::Add => notify(Change {
+                    kind,
+                    attached: true,
+                }),
+                udev::EventType::Remove => notify(Change {
+                    kind,
+                    attached: false,
+                }),
+                // The bootloader drive is added before it is mounted, the
+                // partition changing is what shows it can be written to
+                udev::EventType::Change if kind == DeviceKind::Drive => notify(Change {
+                    kind,
+                    attached: true,
+                }),
+                _ => {}
+            }
+        }
+
+        guard.clear_ready();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HID_ATTACHED: Change = Change {
+        kind: DeviceKind::Hid,
+        attached: true,
+    };
+
+    /// Yields until `count` waiters are registered, so a change is not sent
+    /// before anyone is waiting for it.
+    async fn waiters(count: usize) {
+        while WAITING.lock().unwrap().len() < count {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn changes_only_wake_waiters_of_their_kind() {
+        let mut listener = listen();
+        let hid = tokio::spawn(wait_for(DeviceKind::Hid, Duration::from_secs(10)));
+        let drive = tokio::spawn(wait_for(DeviceKind::Drive, Duration::from_millis(100)));
+        waiters(2).await;
+
+        notify(HID_ATTACHED);
+
+        assert_eq!(hid.await.unwrap(), Some(HID_ATTACHED));
+        assert_eq!(drive.await.unwrap(), None);
+        assert_eq!(listener.next().await, Some(HID_ATTACHED));
+
+        // Neither the woken nor the timed out waiter is left behind
+        assert!(WAITING.lock().unwrap().is_empty());
+
+        notify(Change {
+            kind: DeviceKind::Drive,
+            attached: false,
+        });
+        assert_eq!(
+            listener.next().await.map(|change| change.attached),
+            Some(false)
+        );
+
+        // Waiting out the interval with nothing happening cleans up as well
+        assert_eq!(
+            wait_for(DeviceKind::Drive, Duration::from_millis(10)).await,
+            None
+        );
+        assert!(WAITING.lock().unwrap().is_empty());
+    }
+}
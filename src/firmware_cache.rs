@@ -0,0 +1,198 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::app_config;
+
+/// Stores downloaded firmware images per release source and tag, along with
+/// the SHA-256 recorded when they were first downloaded and whether that was
+/// checked against a published checksum.
+#[derive(Debug, Clone)]
+pub struct FirmwareCache {
+    root: PathBuf,
+}
+
+/// What is recorded next to each cached image.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Entry {
+    source: String,
+    tag: String,
+    sha256: String,
+    /// Whether the image matched the checksum published with the release.
+    verified: bool,
+}
+
+pub fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Finds the checksum for `name` in a `sha256sum` style file. A file with a
+/// single bare hash is accepted as well.
+pub fn parse_checksum_file(contents: &str, name: &str) -> Option<String> {
+    for line in contents.lines() {
+        let mut parts = line.split_whitespace();
+        let hash = match parts.next() {
+            Some(hash) if hash.len() == 64 && hash.chars().all(|c| c.is_ascii_hexdigit()) => hash,
+            _ => continue,
+        };
+
+        match parts.next() {
+            Some(file) if file.trim_start_matches('*') == name => return Some(hash.to_lowercase()),
+            None => return Some(hash.to_lowercase()),
+            _ => {}
+        }
+    }
+
+    None
+}
+
+impl FirmwareCache {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    pub fn open() -> Option<Self> {
+        app_config::data_dir().map(|dir| Self::new(dir.join("firmware")))
+    }
+
+    /// Sources and tags are hashed so neither can reach outside the cache.
+    fn source_dir(&self, source: &str) -> PathBuf {
+        self.root.join(&sha256_hex(source.as_bytes())[..16])
+    }
+
+    fn entry_dir(&self, source: &str, tag: &str) -> PathBuf {
+        self.source_dir(source)
+            .join(&sha256_hex(tag.as_bytes())[..16])
+    }
+
+    fn read_entry(dir: &Path) -> Option<Entry> {
+        let file = File::open(dir.join("firmware.json")).ok()?;
+        serde_json::from_reader(file).ok()
+    }
+
+    /// Returns the image cached for `tag` from `source` if it still matches
+    /// the recorded checksum. With `require_verified` images which were not
+    /// checked against a published checksum are skipped.
+    pub fn get(&self, source: &str, tag: &str, require_verified: bool) -> Option<Vec<u8>> {
+        let dir = self.entry_dir(source, tag);
+        let entry = Self::read_entry(&dir)?;
+        if entry.source != source || entry.tag != tag || (require_verified && !entry.verified) {
+            return None;
+        }
+
+        let data = std::fs::read(dir.join("firmware.uf2")).ok()?;
+        if entry.sha256 == sha256_hex(&data) {
+            Some(data)
+        } else {
+            None
+        }
+    }
+
+    pub fn insert(
+        &self,
+        source: &str,
+        tag: &str,
+        data: &[u8],
+        verified: bool,
+    ) -> Result<String, ()> {
+        let dir = self.entry_dir(source, tag);
+        std::fs::create_dir_all(&dir).map_err(|_| ())?;
+
+        let entry = Entry {
+            source: source.to_owned(),
+            tag: tag.to_owned(),
+            sha256: sha256_hex(data),
+            verified,
+        };
+        File::create(dir.join("firmware.uf2"))
+            .and_then(|mut f| f.write_all(data))
+            .map_err(|_| ())?;
+        File::create(dir.join("firmware.json"))
+            .map_err(|_| ())
+            .and_then(|f| serde_json::to_writer(f, &entry).map_err(|_| ()))?;
+
+        Ok(entry.sha256)
+    }
+
+    /// The newest tag cached from `source`, used when it can not be reached.
+    pub fn latest(&self, source: &str) -> Option<String> {
+        let mut tags = std::fs::read_dir(self.source_dir(source))
+            .ok()?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| Self::read_entry(&entry.path()))
+            .filter(|entry| entry.source == source)
+            .map(|entry| entry.tag)
+            .collect::<Vec<_>>();
+
+        // Versions sort after tags that are not versions, so the newest is last
+        tags.sort_by_key(|tag| {
+            let version = semver::Version::parse(tag.trim_start_matches('v'));
+            (version.is_ok(), version.ok(), tag.clone())
+        });
+
+        tags.pop()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cache_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = FirmwareCache::new(dir.path().to_owned());
+
+        let hash = cache.insert("github", "v1.0.0", b"firmware", true).unwrap();
+        assert_eq!(hash, sha256_hex(b"firmware"));
+        assert_eq!(cache.get("github", "v1.0.0", true).unwrap(), b"firmware");
+        assert_eq!(cache.latest("github").unwrap(), "v1.0.0");
+
+        cache.insert("github", "v1.10.0", b"newer", true).unwrap();
+        cache.insert("github", "v1.2.0", b"older", true).unwrap();
+        cache.insert("github", "nightly", b"nightly", true).unwrap();
+        assert_eq!(cache.latest("github").unwrap(), "v1.10.0");
+
+        std::fs::write(
+            cache.entry_dir("github", "v1.0.0").join("firmware.uf2"),
+            b"tampered",
+        )
+        .unwrap();
+        assert!(cache.get("github", "v1.0.0", false).is_none());
+    }
+
+    #[test]
+    fn entries_are_kept_apart() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = FirmwareCache::new(dir.path().to_owned());
+
+        cache.insert("url", "v1.0.0", b"unverified", false).unwrap();
+
+        // Only the source which downloaded a tag is served it
+        assert!(cache.get("github", "v1.0.0", false).is_none());
+        assert!(cache.latest("github").is_none());
+        // An image which was never checked is not good enough once
+        // verification is turned on
+        assert_eq!(cache.get("url", "v1.0.0", false).unwrap(), b"unverified");
+        assert!(cache.get("url", "v1.0.0", true).is_none());
+        // Tags can not point outside the cache
+        cache.insert("url", "../../escape", b"data", false).unwrap();
+        assert!(!dir.path().join("escape").exists());
+    }
+
+    #[test]
+    fn checksum_file_formats() {
+        let hash = sha256_hex(b"firmware");
+        let sums = format!("{}  other.uf2\n{} *firmware.uf2\n", "0".repeat(64), hash);
+
+        assert_eq!(parse_checksum_file(&sums, "firmware.uf2").unwrap(), hash);
+        assert_eq!(parse_checksum_file(&hash, "firmware.uf2").unwrap(), hash);
+        assert!(parse_checksum_file("not a checksum", "firmware.uf2").is_none());
+    }
+}
@@ -1,4 +1,4 @@
-use hidapi::{HidApi, HidDevice};
+use hidapi::{DeviceInfo, HidApi, HidDevice};
 use iced_futures::futures;
 use iced_native::subscription::{self, Subscription};
 
@@ -7,214 +7,309 @@ use futures::channel::mpsc;
 use async_std::future;
 use futures::stream::StreamExt;
 
+use std::collections::VecDeque;
 use std::sync::Arc;
-use std::{fmt, sync::Mutex};
-
-use crate::{
-    macro_parser,
-    macropad_wrapper,
-};
-
-async fn scan_devices(api: &mut HidApi) -> Option<HidDevice> {
-    api.refresh_devices().unwrap();
-    for device in api.device_list() {
-        if device.vendor_id() == 0x554D
-            && device.product_id() == 0x2020
-            && device.usage_page() == 0xff00
-            && device.usage() == 1
-        {
-            let d = device.open_device(&api).unwrap();
-            return Some(d);
+use std::time::{Duration, Instant};
+use std::{fmt, mem, sync::Mutex};
+
+use crate::device_watcher::{self, DeviceKind};
+use crate::profile::Profile;
+use crate::transaction::{self, TransactionError};
+use crate::transport::Transport;
+use crate::{macro_parser, macropad_wrapper};
+
+/// Number of times a command is retried when the device does not answer.
+const RETRIES: usize = 2;
+/// Number of messages a connection holds before turning new ones away.
+const QUEUE_SIZE: usize = 100;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DeviceId {
+    pub serial: Option<String>,
+    pub path: String,
+}
+
+impl fmt::Display for DeviceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.serial {
+            Some(serial) if !serial.is_empty() => write!(f, "Macropad {}", serial),
+            _ => write!(f, "Macropad at {}", self.path),
         }
     }
+}
+
+fn is_macropad(device: &DeviceInfo) -> bool {
+    device.vendor_id() == 0x554D
+        && device.product_id() == 0x2020
+        && device.usage_page() == 0xff00
+        && device.usage() == 1
+}
+
+fn device_id(device: &DeviceInfo) -> DeviceId {
+    DeviceId {
+        serial: device.serial_number().map(|serial| serial.to_owned()),
+        path: device.path().to_string_lossy().to_string(),
+    }
+}
+
+fn find_device<'a>(api: &'a HidApi, id: &DeviceId) -> Option<&'a DeviceInfo> {
+    let mut devices = api.device_list().filter(|device| is_macropad(device));
+    let by_path = devices.find(|device| device_id(device).path == id.path);
+
+    by_path.or_else(|| {
+        id.serial.as_ref().and_then(|serial| {
+            api.device_list()
+                .filter(|device| is_macropad(device))
+                .find(|device| device.serial_number() == Some(serial.as_str()))
+        })
+    })
+}
+
+pub async fn scan_devices(api: &mut HidApi) -> Vec<DeviceId> {
+    if api.refresh_devices().is_err() {
+        return Vec::new();
+    }
 
-    None
+    let mut devices = api
+        .device_list()
+        .filter(|device| is_macropad(device))
+        .map(device_id)
+        .collect::<Vec<_>>();
+
+    devices.sort_by(|a, b| (&a.serial, &a.path).cmp(&(&b.serial, &b.path)));
+    devices.dedup();
+    devices
+}
+
+pub async fn open_device(api: &mut HidApi, id: &DeviceId) -> Option<HidDevice> {
+    api.refresh_devices().ok()?;
+    find_device(api, id).and_then(|device| device.open_device(api).ok())
 }
 
-async fn is_connected(api: &mut HidApi) -> bool {
-    api.refresh_devices().unwrap();
-    for device in api.device_list() {
-        if device.vendor_id() == 0x554D
-            && device.product_id() == 0x2020
-            && device.usage_page() == 0xff00
-            && device.usage() == 1
+async fn is_connected(api: &mut HidApi, id: &DeviceId) -> bool {
+    if api.refresh_devices().is_err() {
+        return false;
+    }
+
+    find_device(api, id).is_some()
+}
+
+/// Writes a single command to the device without touching any cached state.
+pub fn write_command(device: &dyn Transport, command: &MacropadCommand) -> Result<(), ()> {
+    match command {
+        MacropadCommand::Bootloader => macropad_wrapper::enter_bootloader(device),
+        MacropadCommand::KeyMode(i, mode) => macropad_wrapper::set_key_mode(device, *i, *mode),
+        MacropadCommand::KeyboardData(i, data) => {
+            macropad_wrapper::set_keyboard_data(device, *i, *data)
+        }
+        MacropadCommand::ConsumerData(i, data) => {
+            macropad_wrapper::set_consumer_data(device, *i, *data)
+        }
+        MacropadCommand::KeyColor(i, color) => macropad_wrapper::set_key_color(device, *i, *color),
+        MacropadCommand::Macro(i, macro_data) => write_macro(device, *i, None, macro_data),
+        MacropadCommand::TapSpeed(speed) => macropad_wrapper::set_tap_speed(device, *speed),
+        MacropadCommand::HoldSpeed(speed) => macropad_wrapper::set_hold_speed(device, *speed),
+        MacropadCommand::LedBaseColor(color) => {
+            macropad_wrapper::set_led_base_color(device, *color)
+        }
+        MacropadCommand::LedEffect(effect) => macropad_wrapper::set_led_effect(device, *effect),
+        MacropadCommand::LedBrightness(brightness) => {
+            macropad_wrapper::set_led_brightness(device, *brightness)
+        }
+        MacropadCommand::LedEffectPeriod(period) => {
+            macropad_wrapper::set_led_effect_period(device, *period)
+        }
+        MacropadCommand::LedEffectOffset(offset) => {
+            macropad_wrapper::set_led_effect_offset(device, *offset)
+        }
+    }
+}
+
+/// Writes a macro, sending only the chunks which differ from `previous` if
+/// the slot is known to hold it. Falls back to rewriting the whole slot if
+/// the device disagrees about the result.
+pub fn write_macro(
+    device: &dyn Transport,
+    index: u8,
+    previous: Option<&macro_parser::Macro>,
+    macro_data: &macro_parser::Macro,
+) -> Result<(), ()> {
+    let data = macro_data.pack()?;
+
+    if let Some(previous) = previous.and_then(|previous| previous.pack().ok()) {
+        if macropad_wrapper::set_macro_delta(device, index, &previous, &data).is_ok()
+            && macropad_wrapper::validate_macro(device, index, &data).is_ok()
         {
-            return true;
+            return Ok(());
         }
     }
 
-    false
+    macropad_wrapper::clear_macro(device, index)?;
+    macropad_wrapper::set_macro(device, index, &data)?;
+    macropad_wrapper::validate_macro(device, index, &data)
 }
 
-pub fn connect() -> Subscription<Event> {
+/// Reads back the value a command wrote, returning whether it matches.
+pub fn verify_command(device: &dyn Transport, command: &MacropadCommand) -> Result<bool, ()> {
+    Ok(match command {
+        MacropadCommand::Bootloader => true,
+        MacropadCommand::KeyMode(i, mode) => macropad_wrapper::get_key_mode(device, *i)? == *mode,
+        MacropadCommand::KeyboardData(i, data) => {
+            macropad_wrapper::get_keyboard_data(device, *i)? == *data
+        }
+        MacropadCommand::ConsumerData(i, data) => {
+            macropad_wrapper::get_consumer_data(device, *i)? == *data
+        }
+        MacropadCommand::KeyColor(i, color) => {
+            macropad_wrapper::get_key_color(device, *i)? == *color
+        }
+        MacropadCommand::Macro(i, macro_data) => {
+            macropad_wrapper::get_macro(device, *i)? == macro_data.pack()?
+        }
+        MacropadCommand::TapSpeed(speed) => macropad_wrapper::get_tap_speed(device)? == *speed,
+        MacropadCommand::HoldSpeed(speed) => macropad_wrapper::get_hold_speed(device)? == *speed,
+        MacropadCommand::LedBaseColor(color) => {
+            macropad_wrapper::get_led_base_color(device)? == *color
+        }
+        MacropadCommand::LedEffect(effect) => macropad_wrapper::get_led_effect(device)? == *effect,
+        MacropadCommand::LedBrightness(brightness) => {
+            macropad_wrapper::get_led_brightness(device)? == *brightness
+        }
+        MacropadCommand::LedEffectPeriod(period) => {
+            macropad_wrapper::get_led_effect_period(device)? == *period
+        }
+        MacropadCommand::LedEffectOffset(offset) => {
+            macropad_wrapper::get_led_effect_offset(device)? == *offset
+        }
+    })
+}
+
+/// Watches for macropads being plugged in or removed, emitting the full list
+/// of matching devices whenever it changes.
+pub fn devices() -> Subscription<Event> {
+    struct Devices;
+
+    subscription::unfold(
+        std::any::TypeId::of::<Devices>(),
+        (None, Vec::new()),
+        |(api, known): (Option<HidApi>, Vec<DeviceId>)| async move {
+            let mut api = match api {
+                Some(api) => api,
+                None => hidapi::HidApi::new().unwrap(),
+            };
+
+            let found = scan_devices(&mut api).await;
+            if found != known {
+                (
+                    Some(Event::DevicesChanged(found.clone())),
+                    (Some(api), found),
+                )
+            } else {
+                device_watcher::wait(DeviceKind::Hid).await;
+
+                (None, (Some(api), known))
+            }
+        },
+    )
+}
+
+/// Manages the connection to a single macropad, one subscription is created
+/// per device reported by [`devices`].
+pub fn connect(id: DeviceId) -> Subscription<Event> {
     struct Connect;
 
     subscription::unfold(
-        std::any::TypeId::of::<Connect>(),
+        (std::any::TypeId::of::<Connect>(), id.clone()),
         State::Uninitialized,
-        |state| async move {
-            match state {
-                State::Uninitialized => {
-                    let api = hidapi::HidApi::new().unwrap();
-                    (Some(Event::Disconnected), State::Disconnected(api))
-                }
-                State::Disconnected(mut api) => {
-                    if let Some(d) = scan_devices(&mut api).await {
-                        let (sender, receiver) = mpsc::channel(100);
-                        let macropad =
-                            Arc::new(Mutex::new(macro_parser::get_macro_pad(&d).unwrap()));
-                        (
-                            Some(Event::Connected(Connection(sender, macropad.clone()))),
-                            State::Connected(api, d, macropad.clone(), receiver),
-                        )
-                    } else {
-                        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
-
+        move |state| {
+            let id = id.clone();
+            async move {
+                match state {
+                    State::Uninitialized => {
+                        let api = hidapi::HidApi::new().unwrap();
                         (None, State::Disconnected(api))
                     }
-                }
-                State::Connected(mut api, device, macropad, mut input) => {
-                    let command = future::timeout(
-                        std::time::Duration::from_secs(1),
-                        input.select_next_some(),
-                    )
-                    .await;
-                    if let Ok(command) = command {
-                        match command {
-                            Message::Set(command) => {
-                                let res = match command {
-                                    MacropadCommand::Bootloader => {
-                                        macropad_wrapper::enter_bootloader(&device)
-                                    }
-                                    MacropadCommand::KeyMode(i, mode) => {
-                                        macropad_wrapper::set_key_mode(&device, i, mode).and_then(
-                                            |_| {
-                                                macropad.lock().unwrap().key_configs[i as usize]
-                                                    .key_mode = mode;
-                                                Ok(())
-                                            },
-                                        )
-                                    }
-                                    MacropadCommand::KeyboardData(i, data) => {
-                                        macropad_wrapper::set_keyboard_data(&device, i, data)
-                                            .and_then(|_| {
-                                                macropad.lock().unwrap().key_configs[i as usize]
-                                                    .keyboard_data = data;
-                                                Ok(())
-                                            })
-                                    }
-                                    MacropadCommand::ConsumerData(i, data) => {
-                                        macropad_wrapper::set_consumer_data(&device, i, data)
-                                            .and_then(|_| {
-                                                macropad.lock().unwrap().key_configs[i as usize]
-                                                    .consumer_data = data;
-                                                Ok(())
-                                            })
-                                    }
-                                    MacropadCommand::KeyColor(i, color) => {
-                                        macropad_wrapper::set_key_color(&device, i, color).and_then(
-                                            |_| {
-                                                macropad.lock().unwrap().key_configs[i as usize]
-                                                    .key_color = color;
-                                                Ok(())
-                                            },
-                                        )
-                                    }
-                                    MacropadCommand::Macro(i, macro_data) => {
-                                        macro_data.pack().and_then(|data| {
-                                            macropad_wrapper::clear_macro(&device, i).and_then(
-                                                |_| {
-                                                    macropad_wrapper::set_macro(&device, i, &data)
-                                                        .and_then(|_| {
-                                                            macropad_wrapper::validate_macro(
-                                                                &device, i, &data,
-                                                            )
-                                                            .and_then(|_| {
-                                                                macropad.lock().unwrap().set_macro(
-                                                                    i as usize, macro_data,
-                                                                );
-                                                                Ok(())
-                                                            })
-                                                        })
-                                                },
-                                            )
-                                        })
-                                    }
-                                    MacropadCommand::TapSpeed(speed) => {
-                                        macropad_wrapper::set_tap_speed(&device, speed).and_then(
-                                            |_| {
-                                                macropad.lock().unwrap().config.tap_speed = speed;
-                                                Ok(())
-                                            },
-                                        )
-                                    }
-                                    MacropadCommand::HoldSpeed(speed) => {
-                                        macropad_wrapper::set_hold_speed(&device, speed).and_then(
-                                            |_| {
-                                                macropad.lock().unwrap().config.hold_speed = speed;
-                                                Ok(())
-                                            },
-                                        )
-                                    }
-                                    MacropadCommand::LedBaseColor(color) => {
-                                        macropad_wrapper::set_led_base_color(&device, color)
-                                            .and_then(|_| {
-                                                macropad.lock().unwrap().led_config.base_color =
-                                                    color;
-                                                Ok(())
-                                            })
-                                    }
-                                    MacropadCommand::LedEffect(effect) => {
-                                        macropad_wrapper::set_led_effect(&device, effect).and_then(
-                                            |_| {
-                                                macropad.lock().unwrap().led_config.effect = effect;
-                                                Ok(())
-                                            },
-                                        )
-                                    }
-                                    MacropadCommand::LedBrightness(brightness) => {
-                                        macropad_wrapper::set_led_brightness(&device, brightness)
-                                            .and_then(|_| {
-                                                macropad.lock().unwrap().led_config.brightness =
-                                                    brightness;
-                                                Ok(())
-                                            })
-                                    }
-                                    MacropadCommand::LedEffectPeriod(period) => {
-                                        macropad_wrapper::set_led_effect_period(&device, period)
-                                            .and_then(|_| {
-                                                macropad.lock().unwrap().led_config.effect_period =
-                                                    period;
-                                                Ok(())
-                                            })
-                                    }
-                                    MacropadCommand::LedEffectOffset(offset) => {
-                                        macropad_wrapper::set_led_effect_offset(&device, offset)
-                                            .and_then(|_| {
-                                                macropad.lock().unwrap().led_config.effect_offset =
-                                                    offset;
-                                                Ok(())
-                                            })
-                                    }
-                                };
-                                if res.is_err() {
-                                    drop(device);
-                                    (Some(Event::Disconnected), State::Disconnected(api))
-                                } else {
-                                    (
-                                        Some(Event::MacropadUpdated),
-                                        State::Connected(api, device, macropad, input),
-                                    )
+                    State::Disconnected(mut api) => {
+                        if let Some(d) = open_device(&mut api, &id).await {
+                            let (sender, receiver) = mpsc::channel(1);
+                            let started = Instant::now();
+                            let macropad = match macro_parser::get_macro_pad_settings(&d) {
+                                Ok(macropad) => Arc::new(Mutex::new(macropad)),
+                                Err(_) => {
+                                    drop(d);
+                                    tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+
+                                    return (None, State::Disconnected(api));
                                 }
-                            }
+                            };
+                            let timings = Arc::new(Mutex::new(Timings {
+                                connect: started.elapsed(),
+                                macros: Duration::ZERO,
+                                last_save: None,
+                            }));
+                            let queue = Arc::new(Mutex::new(CommandQueue::default()));
+                            (
+                                Some(Event::Connected(Connection(
+                                    Some(sender),
+                                    macropad.clone(),
+                                    id.clone(),
+                                    timings.clone(),
+                                    queue.clone(),
+                                ))),
+                                State::Connected(
+                                    api,
+                                    Session {
+                                        device: d,
+                                        macropad,
+                                        queue,
+                                        wake: receiver,
+                                        verify: false,
+                                        timings,
+                                    },
+                                ),
+                            )
+                        } else {
+                            device_watcher::wait(DeviceKind::Hid).await;
 
-                            _ => (None, State::Connected(api, device, macropad, input)),
+                            (None, State::Disconnected(api))
                         }
-                    } else {
-                        if is_connected(&mut api).await {
-                            (None, State::Connected(api, device, macropad, input))
-                        } else {
-                            (Some(Event::Disconnected), State::Disconnected(api))
+                    }
+                    State::Connected(mut api, mut session) => {
+                        // Macros are read one slot at a time in between
+                        // commands until every slot has been loaded
+                        let unloaded = session.macropad.lock().unwrap().next_unloaded_macro();
+                        let mut message = session.queue.lock().unwrap().pop();
+
+                        if message.is_none() && unloaded.is_none() {
+                            if future::timeout(
+                                Duration::from_secs(1),
+                                session.wake.select_next_some(),
+                            )
+                            .await
+                            .is_ok()
+                            {
+                                message = session.queue.lock().unwrap().pop();
+                            } else if !device_watcher::is_live()
+                                && !is_connected(&mut api, &id).await
+                            {
+                                // Removal is reported by `devices` when
+                                // hot-plug events are available
+                                return (Some(Event::Disconnected(id)), State::Disconnected(api));
+                            }
+                        }
+
+                        let event = match (message, unloaded) {
+                            (Some(message), _) => session.handle(&mut api, &id, message).await,
+                            (None, Some(slot)) => session.load_macro(&id, slot),
+                            (None, None) => Ok(None),
+                        };
+
+                        match event {
+                            Ok(event) => (event, State::Connected(api, session)),
+                            Err(_) => {
+                                drop(session);
+                                (Some(Event::Disconnected(id)), State::Disconnected(api))
+                            }
                         }
                     }
                 }
@@ -223,16 +318,170 @@ pub fn connect() -> Subscription<Event> {
     )
 }
 
+/// Runs `f` again if the device did not answer, giving up after [`RETRIES`]
+/// retries.
+fn retry<T>(mut f: impl FnMut() -> Result<T, ()>) -> Result<T, ()> {
+    let mut result = f();
+    for _ in 0..RETRIES {
+        if result.is_ok() {
+            break;
+        }
+        result = f();
+    }
+    result
+}
+
+/// An open macropad and everything the connection keeps about it.
+struct Session {
+    device: hidapi::HidDevice,
+    macropad: Arc<Mutex<macro_parser::Macropad>>,
+    queue: Arc<Mutex<CommandQueue>>,
+    /// Signalled by [`Connection::send`] whenever something is queued.
+    wake: mpsc::Receiver<()>,
+    /// Read every write back from the device before accepting it.
+    verify: bool,
+    timings: Arc<Mutex<Timings>>,
+}
+
+impl Session {
+    /// Handles a queued message. Returns `Err` if the device is gone.
+    async fn handle(
+        &mut self,
+        api: &mut HidApi,
+        id: &DeviceId,
+        message: Message,
+    ) -> Result<Option<Event>, ()> {
+        match message {
+            Message::Set(command) => self.set(api, id, command).await.map(Some),
+            Message::Transaction(mut commands) => {
+                let features = self.macropad.lock().unwrap().protocol.features();
+                commands.retain(|command| features.supports(command));
+
+                let started = Instant::now();
+                let result = transaction::apply(&self.device, &commands);
+                self.timings.lock().unwrap().last_save = Some(started.elapsed());
+
+                match result {
+                    Ok(_) => {
+                        let mut cached = self.macropad.lock().unwrap();
+                        for command in &commands {
+                            command.apply(&mut cached);
+                        }
+                        Ok(Some(Event::TransactionApplied(id.clone())))
+                    }
+                    Err(error) => {
+                        if !error.unrestored.is_empty() {
+                            let mut cached = self.macropad.lock().unwrap();
+                            for command in &error.unrestored {
+                                command.apply(&mut cached);
+                            }
+                        }
+                        Ok(Some(Event::TransactionFailed(id.clone(), error)))
+                    }
+                }
+            }
+            Message::Verify(verify) => {
+                self.verify = verify;
+                Ok(None)
+            }
+            Message::VerifyDevice => {
+                // A failed read is reported rather than dropping the device
+                let differences = macro_parser::get_macro_pad(&self.device).map(|actual| {
+                    let cached = Profile::from_macropad("", &self.macropad.lock().unwrap());
+                    cached.diff(&Profile::from_macropad("", &actual))
+                });
+
+                Ok(Some(Event::Verified(id.clone(), differences)))
+            }
+            Message::Connected | Message::Disconnected => Ok(None),
+        }
+    }
+
+    async fn set(
+        &mut self,
+        api: &mut HidApi,
+        id: &DeviceId,
+        command: MacropadCommand,
+    ) -> Result<Event, ()> {
+        // Settings the firmware does not know about are left alone
+        if !self
+            .macropad
+            .lock()
+            .unwrap()
+            .protocol
+            .features()
+            .supports(&command)
+        {
+            return Ok(Event::CommandResult(
+                id.clone(),
+                command,
+                CommandStatus::Unsupported,
+            ));
+        }
+
+        let previous = match &command {
+            MacropadCommand::Macro(i, _) => {
+                let cached = self.macropad.lock().unwrap();
+                if cached.is_macro_loaded(*i as usize) {
+                    Some(cached.get_macro(*i as usize).clone())
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        };
+
+        let started = Instant::now();
+        let result = retry(|| {
+            match &command {
+                MacropadCommand::Macro(i, macro_data) => {
+                    write_macro(&self.device, *i, previous.as_ref(), macro_data)
+                }
+                _ => write_command(&self.device, &command),
+            }?;
+
+            if self.verify {
+                verify_command(&self.device, &command)
+            } else {
+                Ok(true)
+            }
+        });
+        self.timings.lock().unwrap().last_save = Some(started.elapsed());
+
+        let status = match result {
+            Ok(true) => {
+                command.apply(&mut self.macropad.lock().unwrap());
+                CommandStatus::Written
+            }
+            Ok(false) => CommandStatus::Mismatch,
+            Err(_) => {
+                // A device which is still there only lost this command
+                if !is_connected(api, id).await {
+                    return Err(());
+                }
+                CommandStatus::Failed
+            }
+        };
+
+        Ok(Event::CommandResult(id.clone(), command, status))
+    }
+
+    fn load_macro(&mut self, id: &DeviceId, slot: usize) -> Result<Option<Event>, ()> {
+        let started = Instant::now();
+        let macro_data = retry(|| macro_parser::get_macro(&self.device, slot as u8))?;
+
+        self.macropad.lock().unwrap().set_macro(slot, macro_data);
+        self.timings.lock().unwrap().macros += started.elapsed();
+
+        Ok(Some(Event::MacroLoaded(id.clone(), slot)))
+    }
+}
+
 #[allow(clippy::large_enum_variant)]
 enum State {
     Uninitialized,
     Disconnected(hidapi::HidApi),
-    Connected(
-        hidapi::HidApi,
-        hidapi::HidDevice,
-        Arc<Mutex<macro_parser::Macropad>>,
-        mpsc::Receiver<Message>,
-    ),
+    Connected(hidapi::HidApi, Session),
 }
 
 impl fmt::Debug for State {
@@ -240,32 +489,185 @@ impl fmt::Debug for State {
         match self {
             State::Uninitialized => write!(f, " Uninitialized"),
             State::Disconnected(_) => write!(f, "Disconnected"),
-            State::Connected(_, _, _, _) => write!(f, "Connected"),
-            // State::Sent(_, _, _, _, _) => write!(f, "Sent"),
+            State::Connected(_, _) => write!(f, "Connected"),
+        }
+    }
+}
+
+/// Messages waiting to be handled by a connection. A write to a setting
+/// replaces any write to the same setting which has not been sent yet, so
+/// only the latest value reaches the device.
+#[derive(Debug, Default)]
+pub struct CommandQueue {
+    messages: VecDeque<Message>,
+    /// Messages turned away because the queue was full.
+    rejected: usize,
+}
+
+impl CommandQueue {
+    /// Queues a message, handing it back if the queue is full.
+    pub fn push(&mut self, message: Message) -> Result<(), Message> {
+        if let Message::Set(command) = &message {
+            if let Some(setting) = command.setting() {
+                // Writes are only merged back to the last message which is
+                // not a write, anything queued before that has to happen
+                // first
+                let queued = self
+                    .messages
+                    .iter_mut()
+                    .rev()
+                    .map_while(|queued| match queued {
+                        Message::Set(queued) => Some(queued),
+                        _ => None,
+                    })
+                    .find(|queued| queued.setting() == Some(setting));
+
+                if let Some(queued) = queued {
+                    *queued = command.clone();
+                    return Ok(());
+                }
+            }
+        }
+
+        if self.messages.len() >= QUEUE_SIZE {
+            self.rejected += 1;
+            return Err(message);
         }
+
+        self.messages.push_back(message);
+        Ok(())
+    }
+
+    pub fn pop(&mut self) -> Option<Message> {
+        self.messages.pop_front()
     }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+}
+
+/// What happened to a single write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandStatus {
+    Written,
+    /// The device did not report back the value which was just written.
+    Mismatch,
+    /// The device stopped answering, even after retrying.
+    Failed,
+    /// The firmware does not have the setting, nothing was written.
+    Unsupported,
 }
 
 #[derive(Debug, Clone)]
 pub enum Event {
+    DevicesChanged(Vec<DeviceId>),
     Connected(Connection),
-    Disconnected,
-    MacropadUpdated,
+    Disconnected(DeviceId),
+    /// A macro slot has been read from the device in the background.
+    MacroLoaded(DeviceId, usize),
+    /// The outcome of a [`Message::Set`].
+    CommandResult(DeviceId, MacropadCommand, CommandStatus),
+    /// The differences between the cached and on-device configuration, or
+    /// `Err` if it could not be read back.
+    Verified(DeviceId, Result<Vec<String>, ()>),
+    TransactionApplied(DeviceId),
+    TransactionFailed(DeviceId, TransactionError),
+}
+
+/// How long the device took to talk to, for spotting slow transfers.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Timings {
+    /// Reading everything but the macros when connecting.
+    pub connect: Duration,
+    /// Reading the macros in the background after connecting.
+    pub macros: Duration,
+    /// The most recent write or transaction.
+    pub last_save: Option<Duration>,
 }
 
+/// A handle to a macropad. The sender is `None` for an offline macropad,
+/// which only exists in the configurator.
 #[derive(Debug, Clone)]
-pub struct Connection(mpsc::Sender<Message>, Arc<Mutex<macro_parser::Macropad>>);
+pub struct Connection(
+    Option<mpsc::Sender<()>>,
+    Arc<Mutex<macro_parser::Macropad>>,
+    DeviceId,
+    Arc<Mutex<Timings>>,
+    Arc<Mutex<CommandQueue>>,
+);
 
 impl Connection {
-    pub fn send(&mut self, message: Message) {
-        self.0
-            .try_send(message)
-            .expect("Send message to echo server");
+    /// A macropad which is not backed by a device, writes are applied to it
+    /// straight away.
+    pub fn offline(macropad: macro_parser::Macropad) -> Self {
+        Connection(
+            None,
+            Arc::new(Mutex::new(macropad)),
+            DeviceId {
+                serial: None,
+                path: String::new(),
+            },
+            Arc::new(Mutex::new(Timings::default())),
+            Arc::new(Mutex::new(CommandQueue::default())),
+        )
+    }
+
+    pub fn is_offline(&self) -> bool {
+        self.0.is_none()
+    }
+
+    /// Queues a message for the device. Returns `false` if the device is too
+    /// far behind to take any more.
+    pub fn send(&mut self, message: Message) -> bool {
+        let Some(wake) = &mut self.0 else {
+            let mut macropad = self.1.lock().unwrap();
+            match message {
+                Message::Set(command) => command.apply(&mut macropad),
+                Message::Transaction(commands) => {
+                    for command in &commands {
+                        command.apply(&mut macropad);
+                    }
+                }
+                _ => {}
+            }
+            return true;
+        };
+
+        if self.4.lock().unwrap().push(message).is_err() {
+            return false;
+        }
+
+        // A wake up which is still pending covers this message too
+        wake.try_send(()).ok();
+        true
     }
 
     pub fn get_macropad(&self) -> Arc<Mutex<macro_parser::Macropad>> {
         self.1.clone()
     }
+
+    pub fn device(&self) -> &DeviceId {
+        &self.2
+    }
+
+    pub fn timings(&self) -> Timings {
+        *self.3.lock().unwrap()
+    }
+
+    /// The number of messages waiting to be sent.
+    pub fn pending(&self) -> usize {
+        self.4.lock().unwrap().len()
+    }
+
+    /// The number of messages dropped because the queue was full.
+    pub fn rejected(&self) -> usize {
+        self.4.lock().unwrap().rejected
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -273,6 +675,11 @@ pub enum Message {
     Connected,
     Disconnected,
     Set(MacropadCommand),
+    /// Apply every command or, if any write fails, none of them.
+    Transaction(Vec<MacropadCommand>),
+    /// Read every write back from the device before accepting it.
+    Verify(bool),
+    VerifyDevice,
 }
 
 #[derive(Debug, Clone)]
@@ -292,6 +699,55 @@ pub enum MacropadCommand {
     LedEffectOffset(f32),
 }
 
+impl MacropadCommand {
+    /// Identifies the setting a command writes, a later command with the
+    /// same setting overwrites it. `None` for commands which are not a
+    /// setting.
+    pub fn setting(&self) -> Option<(mem::Discriminant<Self>, u8)> {
+        let index = match self {
+            MacropadCommand::Bootloader => return None,
+            MacropadCommand::KeyMode(i, _)
+            | MacropadCommand::KeyboardData(i, _)
+            | MacropadCommand::ConsumerData(i, _)
+            | MacropadCommand::KeyColor(i, _)
+            | MacropadCommand::Macro(i, _) => *i,
+            _ => 0,
+        };
+
+        Some((mem::discriminant(self), index))
+    }
+
+    /// Mirrors a successfully written command into the cached copy of the
+    /// macropad.
+    pub fn apply(&self, macropad: &mut macro_parser::Macropad) {
+        match self {
+            MacropadCommand::Bootloader => {}
+            MacropadCommand::KeyMode(i, mode) => macropad.key_configs[*i as usize].key_mode = *mode,
+            MacropadCommand::KeyboardData(i, data) => {
+                macropad.key_configs[*i as usize].keyboard_data = *data
+            }
+            MacropadCommand::ConsumerData(i, data) => {
+                macropad.key_configs[*i as usize].consumer_data = *data
+            }
+            MacropadCommand::KeyColor(i, color) => {
+                macropad.key_configs[*i as usize].key_color = *color
+            }
+            MacropadCommand::Macro(i, macro_data) => {
+                macropad.set_macro(*i as usize, macro_data.clone())
+            }
+            MacropadCommand::TapSpeed(speed) => macropad.config.tap_speed = *speed,
+            MacropadCommand::HoldSpeed(speed) => macropad.config.hold_speed = *speed,
+            MacropadCommand::LedBaseColor(color) => macropad.led_config.base_color = *color,
+            MacropadCommand::LedEffect(effect) => macropad.led_config.effect = *effect,
+            MacropadCommand::LedBrightness(brightness) => {
+                macropad.led_config.brightness = *brightness
+            }
+            MacropadCommand::LedEffectPeriod(period) => macropad.led_config.effect_period = *period,
+            MacropadCommand::LedEffectOffset(offset) => macropad.led_config.effect_offset = *offset,
+        }
+    }
+}
+
 impl Message {
     pub fn new(command: MacropadCommand) -> Option<Self> {
         Some(Self::Set(command))
@@ -313,6 +769,9 @@ impl fmt::Display for Message {
             Message::Disconnected => {
                 write!(f, "Connection lost... Retrying...")
             }
+            Message::Verify(verify) => write!(f, "Verify writes: {}", verify),
+            Message::VerifyDevice => write!(f, "Verify device"),
+            Message::Transaction(commands) => write!(f, "Apply {} settings", commands.len()),
             Message::Set(command) => match command {
                 MacropadCommand::Bootloader => write!(f, "Enter bootloader"),
                 MacropadCommand::KeyMode(i, mode) => {
@@ -349,3 +808,78 @@ impl fmt::Display for Message {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn speed(message: &Message) -> Option<u32> {
+        match message {
+            Message::Set(MacropadCommand::TapSpeed(speed)) => Some(*speed),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn queue_coalesces_writes_to_the_same_setting() {
+        let mut queue = CommandQueue::default();
+
+        queue
+            .push(Message::Set(MacropadCommand::TapSpeed(100)))
+            .unwrap();
+        queue
+            .push(Message::Set(MacropadCommand::KeyColor(1, (1, 2, 3))))
+            .unwrap();
+        queue
+            .push(Message::Set(MacropadCommand::KeyColor(2, (1, 2, 3))))
+            .unwrap();
+        queue
+            .push(Message::Set(MacropadCommand::TapSpeed(200)))
+            .unwrap();
+
+        assert_eq!(queue.len(), 3);
+        assert_eq!(speed(&queue.pop().unwrap()), Some(200));
+    }
+
+    #[test]
+    fn queue_keeps_writes_on_both_sides_of_a_transaction() {
+        let mut queue = CommandQueue::default();
+
+        queue
+            .push(Message::Set(MacropadCommand::TapSpeed(100)))
+            .unwrap();
+        queue
+            .push(Message::Transaction(vec![MacropadCommand::TapSpeed(150)]))
+            .unwrap();
+        queue
+            .push(Message::Set(MacropadCommand::TapSpeed(200)))
+            .unwrap();
+        queue
+            .push(Message::Set(MacropadCommand::Bootloader))
+            .unwrap();
+        queue
+            .push(Message::Set(MacropadCommand::Bootloader))
+            .unwrap();
+
+        assert_eq!(queue.len(), 5);
+        assert_eq!(speed(&queue.pop().unwrap()), Some(100));
+        assert!(matches!(queue.pop(), Some(Message::Transaction(_))));
+        assert_eq!(speed(&queue.pop().unwrap()), Some(200));
+    }
+
+    #[test]
+    fn full_queue_rejects_messages() {
+        let mut queue = CommandQueue::default();
+
+        for _ in 0..QUEUE_SIZE {
+            queue.push(Message::VerifyDevice).unwrap();
+        }
+
+        assert!(queue.push(Message::VerifyDevice).is_err());
+        // A write with nothing to merge into needs room of its own
+        assert!(queue
+            .push(Message::Set(MacropadCommand::TapSpeed(1)))
+            .is_err());
+        assert_eq!(queue.rejected, 2);
+    }
+}
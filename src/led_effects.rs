@@ -1,20 +1,22 @@
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use iced::Color;
+use macropad_protocol::data_protocol::LedEffect;
 
 use crate::macro_parser::LedConfig;
 
+/// Plays back an LED effect in real time for the preview.
 #[derive(Debug, Clone, Copy)]
 pub struct LedRunner {
-    timer: f32,
-    last_update: Instant,
+    started: Instant,
+    elapsed: Duration,
 }
 
 impl Default for LedRunner {
     fn default() -> Self {
         Self {
-            timer: 0.0,
-            last_update: Instant::now(),
+            started: Instant::now(),
+            elapsed: Duration::ZERO,
         }
     }
 }
@@ -41,105 +43,342 @@ pub fn hsv2rgb(hue: f32, sat: f32, val: f32) -> (f32, f32, f32) {
     (r + m, g + m, b + m)
 }
 
+/// The color a key's LED is set to, with the brightness applied as alpha.
+pub fn key_led(color: (u8, u8, u8), brightness: u8) -> Color {
+    Color::from_rgba8(color.0, color.1, color.2, brightness as f32 / 255.0)
+}
+
+/// The position within the effect's period from 0 to 1000 at `t` into the
+/// effect. The effect offset is given as a fraction of the period and a
+/// negative period plays the effect backwards. With a period of zero the
+/// effect stands still.
+pub fn phase_at(config: &LedConfig, t: Duration) -> f32 {
+    let offset = config.effect_offset as f64 * 1000.0;
+    let cycles = if config.effect_period == 0.0 {
+        0.0
+    } else {
+        t.as_secs_f64() * 1000.0 / config.effect_period as f64
+    };
+
+    // Done in f64 so the phase is just as exact after hours as at the start
+    (cycles + offset).rem_euclid(1000.0) as f32
+}
+
+/// The color of each LED at `t` into the effect, the way the firmware
+/// composites them. The effect is drawn first, then every key with a color
+/// of its own is drawn over it. Black key colors leave the effect showing.
+pub fn get_leds_at(config: &LedConfig, key_colors: &[(u8, u8, u8)], t: Duration) -> [Color; 4] {
+    let mut leds = effect_at(config, phase_at(config, t));
+
+    for (led, color) in leds.iter_mut().zip(key_colors) {
+        if *color != (0, 0, 0) {
+            *led = key_led(*color, config.brightness);
+        }
+    }
+
+    leds
+}
+
+/// The effect alone at `phase`, see [`phase_at`].
+pub fn effect_at(config: &LedConfig, phase: f32) -> [Color; 4] {
+    match config.effect {
+        LedEffect::None => [Color::TRANSPARENT; 4],
+        LedEffect::Static => static_color(config),
+        LedEffect::Breathing => breathing(config, phase),
+        LedEffect::BreathingSpaced => breathing_spaced(config, phase),
+        LedEffect::ColorCycle => color_cycle(config, phase),
+        LedEffect::Rainbow => rainbow(config, phase),
+    }
+}
+
+fn static_color(config: &LedConfig) -> [Color; 4] {
+    [key_led(config.base_color, config.brightness); 4]
+}
+
+/// Every key fades in and out together.
+fn breathing(config: &LedConfig, phase: f32) -> [Color; 4] {
+    let color = config.base_color;
+
+    let mut time = phase * (100.0 / 1000.0);
+
+    if time > 50.0 {
+        time = 100.0 - time;
+    }
+
+    [Color::from_rgba8(
+        color.0,
+        color.1,
+        color.2,
+        (time / 50.0) * (config.brightness as f32 / 255.0),
+    ); 4]
+}
+
+/// The keys fade in and out one after another.
+fn breathing_spaced(config: &LedConfig, phase: f32) -> [Color; 4] {
+    let color = config.base_color;
+    let timer = phase * (400.0 / 1000.0);
+
+    let mut backlight = [Color::BLACK; 4];
+
+    for (index, led) in backlight.iter_mut().enumerate() {
+        let mut time = timer;
+        time -= index as f32 * 100.0;
+
+        if !(0.0..=100.0).contains(&time) {
+            *led = Color::TRANSPARENT;
+            continue;
+        }
+
+        if time > 50.0 {
+            time = 100.0 - time;
+        }
+
+        *led = Color::from_rgba8(
+            color.0,
+            color.1,
+            color.2,
+            (time / 50.0) * (config.brightness as f32 / 255.0),
+        );
+    }
+
+    backlight
+}
+
+/// Every key goes round the hue wheel together.
+fn color_cycle(config: &LedConfig, phase: f32) -> [Color; 4] {
+    let timer = phase * (360.0 / 1000.0);
+    let color = hsv2rgb(timer, 1.0, 1.0);
+
+    [Color::from_rgba(color.0, color.1, color.2, config.brightness as f32 / 255.0); 4]
+}
+
+/// The keys go round the hue wheel a quarter turn apart.
+fn rainbow(config: &LedConfig, phase: f32) -> [Color; 4] {
+    let timer = phase * (360.0 / 1000.0);
+    let mut backlight = [Color::BLACK; 4];
+
+    for (index, led) in backlight.iter_mut().enumerate() {
+        let color = hsv2rgb((timer + (index as f32 * 360.0 / 4.0)) % 360.0, 1.0, 1.0);
+        *led = Color::from_rgba(color.0, color.1, color.2, config.brightness as f32 / 255.0);
+    }
+
+    backlight
+}
+
 impl LedRunner {
-    pub fn get_leds(&self, config: &LedConfig) -> [Color; 4] {
-        match config.effect {
-            macropad_protocol::data_protocol::LedEffect::None => [Color::TRANSPARENT; 4],
-            macropad_protocol::data_protocol::LedEffect::Static => {
-                [Color::from_rgba8(
-                    config.base_color.0,
-                    config.base_color.1,
-                    config.base_color.2,
-                    config.brightness as f32 / 255.0,
-                ); 4]
-            }
-            macropad_protocol::data_protocol::LedEffect::Breathing => {
-                let color = config.base_color;
-
-                let mut time = self.timer * (100.0 / 1000.0);
-
-                if time > 50.0 {
-                    time = 100.0 - time;
-                }
-
-                [Color::from_rgba8(
-                    color.0,
-                    color.1,
-                    color.2,
-                    (time / 50.0) * (config.brightness as f32 / 255.0),
-                ); 4]
-            }
-            macropad_protocol::data_protocol::LedEffect::BreathingSpaced => {
-                let color = config.base_color;
-                let timer = self.timer * (400.0 / 1000.0);
-
-                let mut backlight = [Color::BLACK; 4];
-
-                for (index, led) in backlight.iter_mut().enumerate() {
-                    let mut time = timer;
-                    time -= index as f32 * 100.0;
-
-                    if !(0.0..=100.0).contains(&time) {
-                        *led = Color::TRANSPARENT;
-                        continue;
-                    }
-
-                    if time > 50.0 {
-                        time = 100.0 - time;
-                    }
-
-                    *led = Color::from_rgba8(
-                        color.0,
-                        color.1,
-                        color.2,
-                        (time / 50.0) * (config.brightness as f32 / 255.0),
-                    );
-                }
-
-                backlight
-            }
-            macropad_protocol::data_protocol::LedEffect::ColorCycle => {
-                let timer = self.timer * (360.0 / 1000.0);
-                let color = hsv2rgb(timer, 1.0, 1.0);
-
-                [Color::from_rgba(color.0, color.1, color.2, config.brightness as f32 / 255.0); 4]
-            }
-            macropad_protocol::data_protocol::LedEffect::Rainbow => {
-                let timer = self.timer * (360.0 / 1000.0);
-                let mut backlight = [Color::BLACK; 4];
-
-                for (index, led) in backlight.iter_mut().enumerate() {
-                    let color = hsv2rgb((timer + (index as f32 * 360.0 / 4.0)) % 360.0, 1.0, 1.0);
-                    *led = Color::from_rgba(
-                        color.0,
-                        color.1,
-                        color.2,
-                        config.brightness as f32 / 255.0,
-                    );
-                }
-
-                backlight
-            }
+    pub fn get_leds(&self, config: &LedConfig, key_colors: &[(u8, u8, u8)]) -> [Color; 4] {
+        get_leds_at(config, key_colors, self.elapsed)
+    }
+
+    /// How far into the effect the runner is.
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    pub fn update(&mut self, now: Instant) {
+        self.elapsed = now.saturating_duration_since(self.started);
+    }
+
+    pub fn reset(&mut self, now: Instant) {
+        self.started = now;
+        self.elapsed = Duration::ZERO;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(effect: LedEffect, color: (u8, u8, u8), brightness: u8, offset: f32) -> LedConfig {
+        LedConfig {
+            base_color: color,
+            effect,
+            brightness,
+            effect_period: 1.0,
+            effect_offset: offset,
+        }
+    }
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    fn assert_leds(actual: [Color; 4], expected: [(f32, f32, f32, f32); 4]) {
+        for (i, (a, e)) in actual.iter().zip(expected).enumerate() {
+            assert!(
+                (a.r - e.0).abs() < 1e-3
+                    && (a.g - e.1).abs() < 1e-3
+                    && (a.b - e.2).abs() < 1e-3
+                    && (a.a - e.3).abs() < 1e-3,
+                "LED {}: {:?} != {:?}",
+                i,
+                a,
+                e
+            );
+        }
+    }
+
+    #[test]
+    fn none_is_dark() {
+        let config = config(LedEffect::None, (255, 255, 255), 255, 0.0);
+
+        for t in [0, 300, 999] {
+            assert_leds(get_leds_at(&config, &[], ms(t)), [(0.0, 0.0, 0.0, 0.0); 4]);
+        }
+    }
+
+    #[test]
+    fn static_scales_by_brightness() {
+        let config = config(LedEffect::Static, (255, 0, 0), 128, 0.0);
+
+        for t in [0, 500] {
+            assert_leds(
+                get_leds_at(&config, &[], ms(t)),
+                [(1.0, 0.0, 0.0, 0.502); 4],
+            );
         }
     }
 
-    pub fn update(&mut self, config: &LedConfig) {
-        if config.effect_period != 0.0 {
-            self.timer += (1.0 / config.effect_period)
-                * ((Instant::now().duration_since(self.last_update).as_micros()) as f32 / 1000.0);
+    #[test]
+    fn breathing_timeline_with_offset() {
+        let config = config(LedEffect::Breathing, (0, 255, 0), 255, 0.25);
+        let timeline = [(0, 0.5), (250, 1.0), (500, 0.5), (900, 0.3)];
 
-            self.timer = self.timer % 1000.0;
+        for (t, level) in timeline {
+            assert_leds(
+                get_leds_at(&config, &[], ms(t)),
+                [(0.0, 1.0, 0.0, level); 4],
+            );
+        }
+    }
+
+    #[test]
+    fn breathing_spaced_offset_moves_the_wave() {
+        let config = config(LedEffect::BreathingSpaced, (0, 0, 255), 255, 0.375);
 
-            if self.timer < 0.0 {
-                self.timer += 1000.0;
-            }
+        assert_leds(
+            get_leds_at(&config, &[], ms(0)),
+            [
+                (0.0, 0.0, 0.0, 0.0),
+                (0.0, 0.0, 1.0, 1.0),
+                (0.0, 0.0, 0.0, 0.0),
+                (0.0, 0.0, 0.0, 0.0),
+            ],
+        );
+        // A quarter period later the wave has moved on by one key
+        assert_leds(
+            get_leds_at(&config, &[], ms(250)),
+            [
+                (0.0, 0.0, 0.0, 0.0),
+                (0.0, 0.0, 0.0, 0.0),
+                (0.0, 0.0, 1.0, 1.0),
+                (0.0, 0.0, 0.0, 0.0),
+            ],
+        );
+    }
+
+    #[test]
+    fn color_cycle_frames() {
+        let config = config(LedEffect::ColorCycle, (0, 0, 0), 255, 0.0);
+        let timeline = [
+            (0, (1.0, 0.0, 0.0)),
+            (250, (0.5, 1.0, 0.0)),
+            (500, (0.0, 1.0, 1.0)),
+            (750, (0.5, 0.0, 1.0)),
+        ];
 
-            self.last_update = Instant::now();
+        for (t, (r, g, b)) in timeline {
+            assert_leds(get_leds_at(&config, &[], ms(t)), [(r, g, b, 1.0); 4]);
         }
     }
 
-    pub fn reset(&mut self) {
-        self.timer = 0.0;
-        self.last_update = Instant::now();
+    #[test]
+    fn rainbow_offset_wraps_around() {
+        let config = config(LedEffect::Rainbow, (0, 0, 0), 255, -0.25);
+
+        // Three quarters of the way round the hue wheel puts the first key
+        // at 270 degrees
+        assert_leds(
+            get_leds_at(&config, &[], ms(0)),
+            [
+                (0.5, 0.0, 1.0, 1.0),
+                (1.0, 0.0, 0.0, 1.0),
+                (0.5, 1.0, 0.0, 1.0),
+                (0.0, 1.0, 1.0, 1.0),
+            ],
+        );
+    }
+
+    #[test]
+    fn period_scales_and_reverses_time() {
+        let mut config = config(LedEffect::Breathing, (255, 255, 255), 255, 0.0);
+
+        config.effect_period = 2.0;
+        assert!((phase_at(&config, ms(500)) - 250.0).abs() < 1e-3);
+
+        config.effect_period = -1.0;
+        assert!((phase_at(&config, ms(250)) - 750.0).abs() < 1e-3);
+
+        config.effect_period = 0.0;
+        config.effect_offset = 0.5;
+        assert!((phase_at(&config, ms(12345)) - 500.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn phase_stays_exact_over_long_runs() {
+        let config = config(LedEffect::Breathing, (255, 255, 255), 255, 0.0);
+        let hours = Duration::from_secs(10 * 60 * 60);
+
+        assert!((phase_at(&config, hours + ms(250)) - 250.0).abs() < 1e-3);
+    }
+
+    #[test]
+    fn key_colors_are_drawn_over_the_effect() {
+        let key_colors = [(0, 0, 0), (255, 255, 0), (0, 0, 0), (0, 255, 255)];
+
+        let leds = get_leds_at(
+            &config(LedEffect::Static, (255, 0, 0), 255, 0.0),
+            &key_colors,
+            ms(0),
+        );
+        assert_leds(
+            leds,
+            [
+                (1.0, 0.0, 0.0, 1.0),
+                (1.0, 1.0, 0.0, 1.0),
+                (1.0, 0.0, 0.0, 1.0),
+                (0.0, 1.0, 1.0, 1.0),
+            ],
+        );
+
+        // Keys keep their color even with the effect turned off
+        let leds = get_leds_at(
+            &config(LedEffect::None, (0, 0, 0), 51, 0.0),
+            &key_colors,
+            ms(0),
+        );
+        assert_leds(
+            leds,
+            [
+                (0.0, 0.0, 0.0, 0.0),
+                (1.0, 1.0, 0.0, 0.2),
+                (0.0, 0.0, 0.0, 0.0),
+                (0.0, 1.0, 1.0, 0.2),
+            ],
+        );
+    }
+
+    #[test]
+    fn runner_follows_the_given_clock() {
+        let start = Instant::now();
+        let mut runner = LedRunner::default();
+        runner.reset(start);
+
+        runner.update(start + ms(250));
+        assert_eq!(runner.elapsed(), ms(250));
+
+        // A clock from before the start counts as the start
+        runner.update(start - ms(10));
+        assert_eq!(runner.elapsed(), Duration::ZERO);
     }
 }
@@ -0,0 +1,323 @@
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use iced::{Color, Rectangle};
+use image::codecs::gif::{GifEncoder, Repeat};
+use image::{Delay, Frame, Rgba, RgbaImage};
+
+use crate::app_config;
+use crate::color_pipeline::ColorPipeline;
+use crate::led_effects;
+use crate::macro_parser::LedConfig;
+use crate::macropad::{self, Shape, BOARD_COLOR, PLUG_COLOR};
+
+/// The dark theme's background, which the pad is drawn on.
+const BACKGROUND: Rgba<u8> = Rgba([0x20, 0x22, 0x25, 0xFF]);
+/// Samples per pixel along each axis, for smooth edges.
+const SAMPLES: u32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Gif,
+    /// Every frame side by side in a grid in one PNG.
+    SpriteSheet,
+}
+
+impl ExportFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Gif => "gif",
+            ExportFormat::SpriteSheet => "png",
+        }
+    }
+}
+
+/// How long one loop of the effect takes. Effects which stand still are
+/// shown for a second.
+pub fn loop_length(config: &LedConfig) -> Duration {
+    if config.effect_period == 0.0 || !config.effect_period.is_finite() {
+        Duration::from_secs(1)
+    } else {
+        Duration::from_secs_f32(config.effect_period.abs())
+    }
+}
+
+/// Whether the point lies within the rounded rectangle.
+fn contains(shape: &Shape, x: f32, y: f32) -> bool {
+    let b = shape.bounds;
+    if x < b.x || y < b.y || x > b.x + b.width || y > b.y + b.height {
+        return false;
+    }
+
+    let [top_left, top_right, bottom_right, bottom_left] = shape.radius;
+    let (radius, cx, cy) = if x < b.x + top_left && y < b.y + top_left {
+        (top_left, b.x + top_left, b.y + top_left)
+    } else if x > b.x + b.width - top_right && y < b.y + top_right {
+        (top_right, b.x + b.width - top_right, b.y + top_right)
+    } else if x > b.x + b.width - bottom_right && y > b.y + b.height - bottom_right {
+        (
+            bottom_right,
+            b.x + b.width - bottom_right,
+            b.y + b.height - bottom_right,
+        )
+    } else if x < b.x + bottom_left && y > b.y + b.height - bottom_left {
+        (bottom_left, b.x + bottom_left, b.y + b.height - bottom_left)
+    } else {
+        return true;
+    };
+
+    (x - cx).powi(2) + (y - cy).powi(2) <= radius.powi(2)
+}
+
+/// Blends `color` over everything drawn so far within the shape.
+fn fill(image: &mut RgbaImage, shape: &Shape, color: Color) {
+    let b = shape.bounds;
+    let x_end = ((b.x + b.width).ceil() as u32).min(image.width());
+    let y_end = ((b.y + b.height).ceil() as u32).min(image.height());
+
+    for y in (b.y.floor().max(0.0) as u32)..y_end {
+        for x in (b.x.floor().max(0.0) as u32)..x_end {
+            let mut covered = 0;
+            for sy in 0..SAMPLES {
+                for sx in 0..SAMPLES {
+                    let px = x as f32 + (sx as f32 + 0.5) / SAMPLES as f32;
+                    let py = y as f32 + (sy as f32 + 0.5) / SAMPLES as f32;
+                    if contains(shape, px, py) {
+                        covered += 1;
+                    }
+                }
+            }
+
+            let alpha = color.a * covered as f32 / (SAMPLES * SAMPLES) as f32;
+            if alpha <= 0.0 {
+                continue;
+            }
+
+            let pixel = image.get_pixel_mut(x, y);
+            for (channel, value) in pixel.0[..3].iter_mut().zip([color.r, color.g, color.b]) {
+                *channel = (value * 255.0 * alpha + *channel as f32 * (1.0 - alpha)).round() as u8;
+            }
+        }
+    }
+}
+
+/// Draws the macropad the same way the preview widget does, without a GPU.
+pub fn render_frame(glow: [Color; 4], size: u32) -> RgbaImage {
+    let mut image = RgbaImage::from_pixel(size, size, BACKGROUND);
+    let outline = macropad::outline(&Rectangle {
+        x: 0.0,
+        y: 0.0,
+        width: size as f32,
+        height: size as f32,
+    });
+
+    fill(
+        &mut image,
+        &outline.plug,
+        Color::from_rgb8(PLUG_COLOR.0, PLUG_COLOR.1, PLUG_COLOR.2),
+    );
+    fill(
+        &mut image,
+        &outline.board,
+        Color::from_rgb8(BOARD_COLOR.0, BOARD_COLOR.1, BOARD_COLOR.2),
+    );
+    for (glow, color) in outline.glows.iter().zip(glow) {
+        fill(&mut image, glow, color);
+    }
+    for key in &outline.keys {
+        fill(&mut image, key, Color::WHITE);
+    }
+
+    image
+}
+
+/// Renders `frames` evenly spaced frames covering one loop of the effect.
+pub fn render_frames(
+    config: &LedConfig,
+    key_colors: &[(u8, u8, u8)],
+    pipeline: &ColorPipeline,
+    frames: usize,
+    size: u32,
+) -> Vec<RgbaImage> {
+    let step = loop_length(config) / frames.max(1) as u32;
+
+    (0..frames)
+        .map(|i| {
+            let glow = led_effects::get_leds_at(config, key_colors, step * i as u32);
+            render_frame(pipeline.screen_leds(glow), size)
+        })
+        .collect()
+}
+
+pub fn write_gif(path: &Path, frames: &[RgbaImage], delay: Duration) -> Result<(), ()> {
+    let file = File::create(path).map_err(|_| ())?;
+    let mut encoder = GifEncoder::new(file);
+    encoder.set_repeat(Repeat::Infinite).map_err(|_| ())?;
+
+    encoder
+        .encode_frames(frames.iter().map(|frame| {
+            Frame::from_parts(frame.clone(), 0, 0, Delay::from_saturating_duration(delay))
+        }))
+        .map_err(|_| ())
+}
+
+/// Lays the frames out left to right, top to bottom in a roughly square
+/// grid.
+pub fn sprite_sheet(frames: &[RgbaImage]) -> RgbaImage {
+    let Some(first) = frames.first() else {
+        return RgbaImage::new(0, 0);
+    };
+    let (width, height) = first.dimensions();
+    let columns = (frames.len() as f32).sqrt().ceil() as u32;
+    let rows = (frames.len() as u32 + columns - 1) / columns;
+
+    let mut sheet = RgbaImage::from_pixel(width * columns, height * rows, BACKGROUND);
+    for (i, frame) in frames.iter().enumerate() {
+        let i = i as u32;
+        image::imageops::replace(
+            &mut sheet,
+            frame,
+            ((i % columns) * width) as i64,
+            ((i / columns) * height) as i64,
+        );
+    }
+
+    sheet
+}
+
+/// Renders one loop of the effect and writes it to `path`.
+pub fn export(
+    path: &Path,
+    format: ExportFormat,
+    config: &LedConfig,
+    key_colors: &[(u8, u8, u8)],
+    pipeline: &ColorPipeline,
+    frames: usize,
+    size: u32,
+) -> Result<(), ()> {
+    let rendered = render_frames(config, key_colors, pipeline, frames, size);
+
+    match format {
+        ExportFormat::Gif => write_gif(path, &rendered, loop_length(config) / frames.max(1) as u32),
+        ExportFormat::SpriteSheet => sprite_sheet(&rendered).save(path).map_err(|_| ()),
+    }
+}
+
+/// Exports to a new file in the exports directory, returning where it was
+/// written.
+pub fn export_to_data_dir(
+    format: ExportFormat,
+    config: &LedConfig,
+    key_colors: &[(u8, u8, u8)],
+    pipeline: &ColorPipeline,
+    frames: usize,
+    size: u32,
+) -> Result<PathBuf, ()> {
+    let dir = app_config::data_dir().ok_or(())?.join("exports");
+    fs::create_dir_all(&dir).map_err(|_| ())?;
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs());
+    let path = dir.join(format!("leds-{}.{}", timestamp, format.extension()));
+    export(&path, format, config, key_colors, pipeline, frames, size)?;
+
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::codecs::gif::GifDecoder;
+    use image::AnimationDecoder;
+    use macropad_protocol::data_protocol::LedEffect;
+
+    use super::*;
+
+    fn config(effect: LedEffect) -> LedConfig {
+        LedConfig {
+            base_color: (255, 0, 0),
+            effect,
+            brightness: 255,
+            effect_period: 1.0,
+            effect_offset: 0.0,
+        }
+    }
+
+    #[test]
+    fn frame_matches_the_widget_layout() {
+        let size = 140;
+        let image = render_frame([Color::from_rgb8(255, 0, 0); 4], size);
+        let outline = macropad::outline(&Rectangle {
+            x: 0.0,
+            y: 0.0,
+            width: size as f32,
+            height: size as f32,
+        });
+
+        // The glow shows in the ring between the key and the edge of the
+        // glow, the key itself is white
+        let key = outline.keys[0].bounds;
+        let glow = outline.glows[0].bounds;
+        assert_eq!(
+            image.get_pixel(key.center_x() as u32, key.center_y() as u32),
+            &Rgba([255, 255, 255, 255])
+        );
+        assert_eq!(
+            image.get_pixel((glow.x + 0.5) as u32, glow.center_y() as u32),
+            &Rgba([255, 0, 0, 255])
+        );
+        assert_eq!(image.get_pixel(0, 0), &BACKGROUND);
+    }
+
+    #[test]
+    fn frames_cover_one_loop() {
+        let frames = render_frames(
+            &config(LedEffect::Breathing),
+            &[],
+            &ColorPipeline::default(),
+            4,
+            32,
+        );
+
+        assert_eq!(frames.len(), 4);
+        // Breathing starts dark and is brightest half way through
+        assert_ne!(frames[0], frames[2]);
+        assert_eq!(frames[1], frames[3]);
+    }
+
+    #[test]
+    fn sprite_sheet_is_a_grid() {
+        let frames = render_frames(
+            &config(LedEffect::Rainbow),
+            &[],
+            &ColorPipeline::default(),
+            5,
+            16,
+        );
+        let sheet = sprite_sheet(&frames);
+
+        assert_eq!(sheet.dimensions(), (48, 32));
+        assert_eq!(sheet.get_pixel(16 + 8, 8), frames[1].get_pixel(8, 8));
+    }
+
+    #[test]
+    fn gif_holds_every_frame() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("leds.gif");
+
+        export(
+            &path,
+            ExportFormat::Gif,
+            &config(LedEffect::ColorCycle),
+            &[],
+            &ColorPipeline::default(),
+            6,
+            32,
+        )
+        .unwrap();
+
+        let decoder = GifDecoder::new(File::open(&path).unwrap()).unwrap();
+        assert_eq!(decoder.into_frames().count(), 6);
+    }
+}
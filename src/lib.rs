@@ -1,12 +1,29 @@
+pub mod app_config;
+pub mod brightness_schedule;
+pub mod color_pipeline;
+pub mod control_api;
+pub mod device_watcher;
+pub mod firmware_cache;
 pub mod font;
 pub mod hid_manager;
 pub mod led_effects;
+pub mod led_export;
 pub mod macro_editor;
 pub mod macro_parser;
+pub mod macro_preview;
 pub mod macropad;
 pub mod macropad_updater;
 pub mod macropad_wrapper;
+pub mod palette;
+pub mod profile;
+pub mod protocol_log;
+pub mod protocol_version;
+pub mod provisioning;
+pub mod reactive_lighting;
+pub mod transaction;
+pub mod transport;
 pub mod type_wrapper;
+pub mod write_scheduler;
 
 #[cfg(test)]
 mod tests {
@@ -1,14 +1,14 @@
 use std::time::Duration;
 
-use hidapi::HidDevice;
 use macropad_protocol::{
     data_protocol::{KeyMode, LedEffect},
     macro_protocol::MacroCommand,
 };
-use semver::Version;
 use usbd_human_interface_device::page::{Consumer, Keyboard};
 
 use crate::macropad_wrapper::{self, prime_device};
+use crate::protocol_version::{self, Features, Negotiated};
+use crate::transport::Transport;
 
 #[derive(Debug, Clone)]
 pub enum ActionType {
@@ -227,16 +227,34 @@ pub struct BuildInfo {
 
 #[derive(Debug, Clone)]
 pub struct Macropad {
-    pub version: Version,
+    pub protocol: Negotiated,
     pub macros: Vec<MacroCollection>,
     pub config: MacroConfig,
     pub key_configs: Vec<KeyConfig>,
     pub led_config: LedConfig,
     pub build_info: BuildInfo,
+    /// Which macro slots have been read from the device.
+    pub loaded_macros: Vec<bool>,
 }
 
 impl Macropad {
+    /// A macropad with default settings which is not backed by a device,
+    /// for editing offline. Every macro slot counts as loaded.
+    pub fn offline() -> Self {
+        Self {
+            protocol: Negotiated::default(),
+            macros: vec![MacroCollection::default(); 4],
+            config: MacroConfig::default(),
+            key_configs: vec![KeyConfig::default(); 4],
+            led_config: LedConfig::default(),
+            build_info: BuildInfo::default(),
+            loaded_macros: vec![true; 16],
+        }
+    }
+
     pub fn set_macro(&mut self, index: usize, macro_data: Macro) {
+        self.loaded_macros[index] = true;
+
         match index & 0b11 {
             0 => self.macros[index >> 2].tap = macro_data,
             1 => self.macros[index >> 2].hold = macro_data,
@@ -245,13 +263,39 @@ impl Macropad {
             _ => (),
         }
     }
+
+    pub fn is_macro_loaded(&self, index: usize) -> bool {
+        self.loaded_macros[index]
+    }
+
+    /// The first macro slot which has not been read from the device yet.
+    pub fn next_unloaded_macro(&self) -> Option<usize> {
+        self.loaded_macros.iter().position(|loaded| !loaded)
+    }
+
+    pub fn get_macro(&self, index: usize) -> &Macro {
+        match index & 0b11 {
+            0 => &self.macros[index >> 2].tap,
+            1 => &self.macros[index >> 2].hold,
+            2 => &self.macros[index >> 2].double_tap,
+            _ => &self.macros[index >> 2].tap_hold,
+        }
+    }
 }
 
-pub fn get_key_config(device: &HidDevice, index: u8) -> Result<KeyConfig, ()> {
+pub fn get_key_config(
+    device: &dyn Transport,
+    index: u8,
+    features: Features,
+) -> Result<KeyConfig, ()> {
     let key_mode = macropad_wrapper::get_key_mode(device, index)?;
     let keyboard_data = macropad_wrapper::get_keyboard_data(device, index)?;
     let consumer_data = macropad_wrapper::get_consumer_data(device, index)?;
-    let key_color = macropad_wrapper::get_key_color(device, index)?;
+    let key_color = if features.key_colors {
+        macropad_wrapper::get_key_color(device, index)?
+    } else {
+        KeyConfig::default().key_color
+    };
 
     Ok(KeyConfig {
         key_mode,
@@ -261,12 +305,12 @@ pub fn get_key_config(device: &HidDevice, index: u8) -> Result<KeyConfig, ()> {
     })
 }
 
-pub fn get_macro(device: &HidDevice, index: u8) -> Result<Macro, ()> {
+pub fn get_macro(device: &dyn Transport, index: u8) -> Result<Macro, ()> {
     let data = macropad_wrapper::get_macro(device, index)?;
     Ok(parse_macro(&data))
 }
 
-pub fn get_macro_collection(device: &HidDevice, index: u8) -> Result<MacroCollection, ()> {
+pub fn get_macro_collection(device: &dyn Transport, index: u8) -> Result<MacroCollection, ()> {
     let mut collection = MacroCollection::default();
 
     for m in 0..4 {
@@ -284,7 +328,7 @@ pub fn get_macro_collection(device: &HidDevice, index: u8) -> Result<MacroCollec
     Ok(collection)
 }
 
-pub fn get_config(device: &HidDevice) -> Result<MacroConfig, ()> {
+pub fn get_config(device: &dyn Transport) -> Result<MacroConfig, ()> {
     let tap_speed = macropad_wrapper::get_tap_speed(device)?;
     let hold_speed = macropad_wrapper::get_hold_speed(device)?;
 
@@ -294,12 +338,16 @@ pub fn get_config(device: &HidDevice) -> Result<MacroConfig, ()> {
     })
 }
 
-pub fn get_led_config(device: &HidDevice) -> Result<LedConfig, ()> {
+pub fn get_led_config(device: &dyn Transport, features: Features) -> Result<LedConfig, ()> {
     let base_color = macropad_wrapper::get_led_base_color(device)?;
     let effect = macropad_wrapper::get_led_effect(device)?;
     let brightness = macropad_wrapper::get_led_brightness(device)?;
     let effect_period = macropad_wrapper::get_led_effect_period(device)?;
-    let effect_offset = macropad_wrapper::get_led_effect_offset(device)?;
+    let effect_offset = if features.led_effect_offset {
+        macropad_wrapper::get_led_effect_offset(device)?
+    } else {
+        LedConfig::default().effect_offset
+    };
 
     Ok(LedConfig {
         base_color,
@@ -310,7 +358,7 @@ pub fn get_led_config(device: &HidDevice) -> Result<LedConfig, ()> {
     })
 }
 
-pub fn get_build_info(device: &HidDevice) -> Result<BuildInfo, ()> {
+pub fn get_build_info(device: &dyn Transport) -> Result<BuildInfo, ()> {
     let firmware_version = macropad_wrapper::get_firmware_version(device)?;
     let build_date = macropad_wrapper::get_build_date(device)?;
     let build_timestamp = macropad_wrapper::get_build_timestamp(device)?;
@@ -330,31 +378,101 @@ pub fn get_build_info(device: &HidDevice) -> Result<BuildInfo, ()> {
     })
 }
 
-pub fn get_macro_pad(device: &HidDevice) -> Result<Macropad, ()> {
+/// Reads everything except the macros, which are slow to transfer and can
+/// be filled in afterwards with [`get_macro`].
+pub fn get_macro_pad_settings(device: &dyn Transport) -> Result<Macropad, ()> {
     prime_device(device)?;
-    let mut macros = Vec::new();
+    // The version decides how everything else is read
+    let build_info = get_build_info(device)?;
+    let protocol = protocol_version::negotiate(&build_info.git_semver);
+    let features = protocol.features();
+
     let config = get_config(device)?;
     let mut key_configs = Vec::new();
-    let led_config = get_led_config(device)?;
-    let build_info = get_build_info(device)?;
+    let led_config = get_led_config(device, features)?;
 
     for index in 0..4 {
-        macros.push(get_macro_collection(device, index)?);
-        key_configs.push(get_key_config(device, index)?);
+        key_configs.push(get_key_config(device, index, features)?);
     }
 
-    let version = Version::parse(&build_info.git_semver).unwrap();
-
     Ok(Macropad {
-        version,
-        macros,
+        protocol,
+        macros: vec![MacroCollection::default(); 4],
         config,
         key_configs,
         led_config,
         build_info,
+        loaded_macros: vec![false; 16],
     })
 }
 
+pub fn get_macro_pad(device: &dyn Transport) -> Result<Macropad, ()> {
+    let mut macropad = get_macro_pad_settings(device)?;
+
+    for index in 0..16 {
+        macropad.set_macro(index, get_macro(device, index as u8)?);
+    }
+
+    Ok(macropad)
+}
+
+/// Returns the packed length of the command at `offset`, including its
+/// delay bytes, or `None` if it does not fit in `data`.
+fn command_len(data: &[u8], offset: usize) -> Option<usize> {
+    let command = MacroCommand::from(*data.get(offset)? >> 2);
+    let header = 1 + ((data[offset] & 0b11) + 1) as usize;
+    let payload = offset + header;
+
+    let len = header
+        + match command {
+            MacroCommand::Empty | MacroCommand::LoopBegin | MacroCommand::ClearLed => 0,
+            MacroCommand::LoopEnd | MacroCommand::KeyDown | MacroCommand::KeyUp => 1,
+            MacroCommand::SetLed => 3,
+            MacroCommand::KeyPress => 5,
+            MacroCommand::ConsumerPress => 6,
+            MacroCommand::TypeString | MacroCommand::Chord => {
+                4 + data.get((payload + 4)..)?.iter().position(|b| *b == 0)? + 1
+            }
+        };
+
+    Some(len)
+}
+
+/// Returns the packed length of the macro in `data` including its end
+/// marker, or `None` if the end marker is not within `data`.
+pub fn macro_len(data: &[u8]) -> Option<usize> {
+    let mut offset = 0;
+
+    while *data.get(offset)? != MacroCommand::Empty as u8 {
+        offset += command_len(data, offset)?;
+    }
+
+    Some(offset + 1)
+}
+
+/// Whether `parse_macro` can read `data`: the macro ends within it and
+/// every loop end closes a loop begin.
+pub fn is_valid_macro(data: &[u8; 4092]) -> bool {
+    let mut offset = 0;
+    let mut depth = 0usize;
+
+    while data[offset] != MacroCommand::Empty as u8 {
+        match MacroCommand::from(data[offset] >> 2) {
+            MacroCommand::LoopBegin => depth += 1,
+            MacroCommand::LoopEnd if depth == 0 => return false,
+            MacroCommand::LoopEnd => depth -= 1,
+            _ => {}
+        }
+
+        match command_len(data, offset) {
+            Some(len) if offset + len < data.len() => offset += len,
+            _ => return false,
+        }
+    }
+
+    true
+}
+
 pub fn parse_macro(data: &[u8; 4092]) -> Macro {
     let mut frames = Vec::new();
     let mut parents = Vec::new();
@@ -613,3 +731,51 @@ impl Macro {
         Ok(data)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn macro_len_finds_end_marker() {
+        let mut macro_data = Macro::new();
+        macro_data.add_frame(MacroFrame {
+            action: ActionType::String(String::from("hello"), Duration::from_millis(5)),
+            delay: Duration::from_millis(10),
+        });
+        macro_data.add_frame(MacroFrame {
+            action: ActionType::KeyPress(Keyboard::A, Duration::from_millis(20)),
+            delay: Duration::from_millis(10),
+        });
+        macro_data.add_frame(MacroFrame {
+            action: ActionType::SetLed((1, 2, 3)),
+            delay: Duration::from_secs(1),
+        });
+        let data = macro_data.pack().unwrap();
+
+        assert_eq!(macro_len(&data), Some(macro_data.size() + 1));
+        assert_eq!(macro_len(&data[..macro_data.size()]), None);
+        assert_eq!(macro_len(&[0u8; 59]), Some(1));
+    }
+
+    #[test]
+    fn malformed_macros_are_invalid() {
+        let mut data = [0u8; 4092];
+        assert!(is_valid_macro(&data));
+
+        // Loop end without a loop begin
+        data[0] = (MacroCommand::LoopEnd as u8) << 2;
+        data[2] = 1;
+        assert!(!is_valid_macro(&data));
+
+        data[0] = (MacroCommand::LoopBegin as u8) << 2;
+        data[2] = (MacroCommand::LoopEnd as u8) << 2;
+        data[4] = 1;
+        assert!(is_valid_macro(&data));
+
+        // String without an end
+        let mut data = [b'a'; 4092];
+        data[0] = (MacroCommand::TypeString as u8) << 2;
+        assert!(!is_valid_macro(&data));
+    }
+}
@@ -0,0 +1,160 @@
+use std::time::Duration;
+
+use crate::macro_parser::{ActionType, Macro, MacroFrame};
+
+/// A change a macro makes to its key's LED. `None` hands the LED back to the
+/// effect.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LedChange {
+    pub at: Duration,
+    pub color: Option<(u8, u8, u8)>,
+}
+
+/// When a macro sets and clears its key's LED, worked out from the delays
+/// of its frames without running it. Typing takes as long as the key delays
+/// add up to, the time the firmware spends on each report is not counted.
+#[derive(Debug, Clone, Default)]
+pub struct MacroTimeline {
+    changes: Vec<LedChange>,
+    length: Duration,
+}
+
+impl MacroTimeline {
+    pub fn new(macro_data: &Macro) -> Self {
+        let mut timeline = Self::default();
+        timeline.add_frames(&macro_data.frames);
+        timeline
+    }
+
+    fn add_frames(&mut self, frames: &[MacroFrame]) {
+        for frame in frames {
+            match &frame.action {
+                ActionType::SetLed(color) => self.push(Some(*color)),
+                ActionType::ClearLed => self.push(None),
+                ActionType::Empty | ActionType::KeyDown(_) | ActionType::KeyUp(_) => {}
+                ActionType::KeyPress(_, delay)
+                | ActionType::ConsumerPress(_, delay)
+                | ActionType::Chord(_, delay) => self.length += *delay,
+                ActionType::String(string, delay) => {
+                    self.length += *delay * string.chars().count() as u32
+                }
+                ActionType::Loop(frames, delay, count) => {
+                    self.length += *delay;
+                    for _ in 0..*count {
+                        self.add_frames(frames);
+                    }
+                }
+            }
+
+            self.length += frame.delay;
+        }
+    }
+
+    fn push(&mut self, color: Option<(u8, u8, u8)>) {
+        self.changes.push(LedChange {
+            at: self.length,
+            color,
+        });
+    }
+
+    /// How long the macro takes to run.
+    pub fn length(&self) -> Duration {
+        self.length
+    }
+
+    pub fn changes(&self) -> &[LedChange] {
+        &self.changes
+    }
+
+    /// The color the macro has set the LED to at `t` into the macro, `None`
+    /// if the effect is showing. The last color stays once the macro is done.
+    pub fn color_at(&self, t: Duration) -> Option<(u8, u8, u8)> {
+        self.changes
+            .iter()
+            .take_while(|change| change.at <= t)
+            .last()
+            .and_then(|change| change.color)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use usbd_human_interface_device::page::Keyboard;
+
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    fn frame(action: ActionType, delay: u64) -> MacroFrame {
+        MacroFrame {
+            action,
+            delay: ms(delay),
+        }
+    }
+
+    #[test]
+    fn delays_and_key_presses_take_time() {
+        let timeline = MacroTimeline::new(&Macro {
+            frames: vec![
+                frame(ActionType::SetLed((255, 0, 0)), 100),
+                frame(ActionType::KeyPress(Keyboard::A, ms(20)), 10),
+                frame(ActionType::String(String::from("abc"), ms(5)), 0),
+                frame(ActionType::ClearLed, 50),
+            ],
+        });
+
+        assert_eq!(
+            timeline.changes(),
+            &[
+                LedChange {
+                    at: ms(0),
+                    color: Some((255, 0, 0)),
+                },
+                LedChange {
+                    at: ms(145),
+                    color: None,
+                },
+            ]
+        );
+        assert_eq!(timeline.length(), ms(195));
+    }
+
+    #[test]
+    fn loops_repeat_their_frames() {
+        let timeline = MacroTimeline::new(&Macro {
+            frames: vec![frame(
+                ActionType::Loop(
+                    vec![
+                        frame(ActionType::SetLed((0, 0, 255)), 100),
+                        frame(ActionType::ClearLed, 100),
+                    ],
+                    ms(10),
+                    3,
+                ),
+                0,
+            )],
+        });
+
+        assert_eq!(timeline.changes().len(), 6);
+        assert_eq!(timeline.changes()[4].at, ms(410));
+        assert_eq!(timeline.length(), ms(610));
+    }
+
+    #[test]
+    fn color_follows_the_latest_change() {
+        let timeline = MacroTimeline::new(&Macro {
+            frames: vec![
+                frame(ActionType::Empty, 100),
+                frame(ActionType::SetLed((1, 2, 3)), 100),
+                frame(ActionType::SetLed((4, 5, 6)), 0),
+            ],
+        });
+
+        assert_eq!(timeline.color_at(ms(50)), None);
+        assert_eq!(timeline.color_at(ms(100)), Some((1, 2, 3)));
+        assert_eq!(timeline.color_at(ms(199)), Some((1, 2, 3)));
+        assert_eq!(timeline.color_at(ms(5000)), Some((4, 5, 6)));
+    }
+}
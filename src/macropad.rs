@@ -81,35 +81,82 @@ impl<'a, Message> Macropad<'a, Message> {
     }
 
     fn get_keys(&self, b: &Rectangle) -> Vec<Rectangle<f32>> {
-        let len = b.width.min(b.height);
-        let offset = 30.0 / 70.0 * len;
+        outline(b).keys.iter().map(|key| key.bounds).collect()
+    }
+}
 
-        vec![
-            Rectangle {
-                x: b.center_x() - offset + ((35.0 - 16.525) / 70.0 * len),
-                y: b.center_y() - offset + ((35.0 - 16.525) / 70.0 * len),
-                width: 14.0 / 70.0 * len,
-                height: 14.0 / 70.0 * len,
+pub const BOARD_COLOR: (u8, u8, u8) = (0x8D, 0x36, 0xCB);
+pub const PLUG_COLOR: (u8, u8, u8) = (0x7B, 0x7B, 0x7B);
+
+/// How far the glow sticks out around each key.
+const GLOW_D: f32 = 1.0;
+
+/// A rounded rectangle, the radii go clockwise from the top left corner.
+#[derive(Debug, Clone, Copy)]
+pub struct Shape {
+    pub bounds: Rectangle,
+    pub radius: [f32; 4],
+}
+
+/// Where every part of the macropad goes when drawn into `b`, shared by the
+/// widget and [`crate::led_export`].
+#[derive(Debug, Clone, Copy)]
+pub struct Outline {
+    pub board: Shape,
+    pub plug: Shape,
+    pub glows: [Shape; 4],
+    pub keys: [Shape; 4],
+}
+
+pub fn outline(b: &Rectangle) -> Outline {
+    let len = b.width.min(b.height);
+    let offset = 30.0 / 70.0 * len;
+
+    // Top left corners of the keys in a 70 unit square
+    let corners = [
+        (35.0 - 16.525, 35.0 - 16.525),
+        (35.0 - 16.525, 35.0 + 2.525),
+        (35.0 + 2.525, 35.0 + 2.525),
+        (35.0 + 2.525, 35.0 - 16.525),
+    ];
+
+    Outline {
+        board: Shape {
+            bounds: Rectangle {
+                x: b.center_x() - offset + 5.0 / 70.0 * len,
+                y: b.center_y() - offset + 5.0 / 70.0 * len,
+                width: 60.0 / 70.0 * len,
+                height: 60.0 / 70.0 * len,
             },
-            Rectangle {
-                x: b.center_x() - offset + ((35.0 - 16.525) / 70.0 * len),
-                y: b.center_y() - offset + ((35.0 + 2.525) / 70.0 * len),
-                width: 14.0 / 70.0 * len,
-                height: 14.0 / 70.0 * len,
+            radius: [5.0 / 70.0 * len; 4],
+        },
+        plug: Shape {
+            bounds: Rectangle {
+                x: b.center_x() - offset + 16.9 / 70.0 * len,
+                y: b.center_y() - offset + 2.9 / 70.0 * len,
+                width: 11.9 / 70.0 * len,
+                height: 2.1 / 70.0 * len,
             },
-            Rectangle {
-                x: b.center_x() - offset + ((35.0 + 2.525) / 70.0 * len),
-                y: b.center_y() - offset + ((35.0 + 2.525) / 70.0 * len),
-                width: 14.0 / 70.0 * len,
-                height: 14.0 / 70.0 * len,
+            radius: [1.0 / 70.0 * len, 1.0 / 70.0 * len, 0.0, 0.0],
+        },
+        glows: corners.map(|(x, y)| Shape {
+            bounds: Rectangle {
+                x: b.center_x() - offset + ((x - GLOW_D) / 70.0 * len),
+                y: b.center_y() - offset + ((y - GLOW_D) / 70.0 * len),
+                width: (14.0 + (GLOW_D * 2.0)) / 70.0 * len,
+                height: (14.0 + (GLOW_D * 2.0)) / 70.0 * len,
             },
-            Rectangle {
-                x: b.center_x() - offset + ((35.0 + 2.525) / 70.0 * len),
-                y: b.center_y() - offset + ((35.0 - 16.525) / 70.0 * len),
+            radius: [(0.5 + GLOW_D) / 70.0 * len; 4],
+        }),
+        keys: corners.map(|(x, y)| Shape {
+            bounds: Rectangle {
+                x: b.center_x() - offset + (x / 70.0 * len),
+                y: b.center_y() - offset + (y / 70.0 * len),
                 width: 14.0 / 70.0 * len,
                 height: 14.0 / 70.0 * len,
             },
-        ]
+            radius: [0.5 / 70.0 * len; 4],
+        }),
     }
 }
 
@@ -224,91 +271,38 @@ where
         _cursor_position: Point,
         _viewport: &Rectangle,
     ) {
-        let b = layout.bounds();
-
-        let len = b.width.min(b.height);
-        let offset = 30.0 / 70.0 * len;
+        let outline = outline(&layout.bounds());
 
-        let board = Primitive::Quad {
-            bounds: Rectangle {
-                x: b.center_x() - offset + 5.0 / 70.0 * len,
-                y: b.center_y() - offset + 5.0 / 70.0 * len,
-                width: 60.0 / 70.0 * len,
-                height: 60.0 / 70.0 * len,
-            },
-            background: Background::Color(Color::from_rgb8(0x8D, 0x36, 0xCB)),
-            border_radius: [5.0 / 70.0 * len; 4],
-            border_width: 0.0,
-            border_color: Color::TRANSPARENT,
-        };
-
-        let plug = Primitive::Quad {
-            bounds: Rectangle {
-                x: b.center_x() - offset + 16.9 / 70.0 * len,
-                y: b.center_y() - offset + 2.9 / 70.0 * len,
-                width: 11.9 / 70.0 * len,
-                height: 2.1 / 70.0 * len,
-            },
-            background: Background::Color(Color::from_rgb8(0x7B, 0x7B, 0x7B)),
-            border_radius: [1.0 / 70.0 * len, 1.0 / 70.0 * len, 0.0, 0.0],
+        let quad = |shape: &Shape, color: Color| Primitive::Quad {
+            bounds: shape.bounds,
+            background: Background::Color(color),
+            border_radius: shape.radius,
             border_width: 0.0,
             border_color: Color::TRANSPARENT,
         };
 
-        const GLOW_D: f32 = 1.0;
-
-        let glows = vec![
-            Rectangle {
-                x: b.center_x() - offset + ((35.0 - 16.525 - GLOW_D) / 70.0 * len),
-                y: b.center_y() - offset + ((35.0 - 16.525 - GLOW_D) / 70.0 * len),
-                width: (14.0 + (GLOW_D * 2.0)) / 70.0 * len,
-                height: (14.0 + (GLOW_D * 2.0)) / 70.0 * len,
-            },
-            Rectangle {
-                x: b.center_x() - offset + ((35.0 - 16.525 - GLOW_D) / 70.0 * len),
-                y: b.center_y() - offset + ((35.0 + 2.525 - GLOW_D) / 70.0 * len),
-                width: (14.0 + (GLOW_D * 2.0)) / 70.0 * len,
-                height: (14.0 + (GLOW_D * 2.0)) / 70.0 * len,
-            },
-            Rectangle {
-                x: b.center_x() - offset + ((35.0 + 2.525 - GLOW_D) / 70.0 * len),
-                y: b.center_y() - offset + ((35.0 + 2.525 - GLOW_D) / 70.0 * len),
-                width: (14.0 + (GLOW_D * 2.0)) / 70.0 * len,
-                height: (14.0 + (GLOW_D * 2.0)) / 70.0 * len,
-            },
-            Rectangle {
-                x: b.center_x() - offset + ((35.0 + 2.525 - GLOW_D) / 70.0 * len),
-                y: b.center_y() - offset + ((35.0 - 16.525 - GLOW_D) / 70.0 * len),
-                width: (14.0 + (GLOW_D * 2.0)) / 70.0 * len,
-                height: (14.0 + (GLOW_D * 2.0)) / 70.0 * len,
-            },
-        ];
-
-        renderer.draw_primitive(plug);
-        renderer.draw_primitive(board);
+        renderer.draw_primitive(quad(
+            &outline.plug,
+            Color::from_rgb8(PLUG_COLOR.0, PLUG_COLOR.1, PLUG_COLOR.2),
+        ));
+        renderer.draw_primitive(quad(
+            &outline.board,
+            Color::from_rgb8(BOARD_COLOR.0, BOARD_COLOR.1, BOARD_COLOR.2),
+        ));
 
-        for (i, glow) in glows.iter().enumerate() {
-            renderer.draw_primitive(Primitive::Quad {
-                bounds: *glow,
-                background: Background::Color(self.glow[i]),
-                border_radius: [(0.5 + GLOW_D) / 70.0 * len; 4],
-                border_width: 0.0,
-                border_color: Color::TRANSPARENT,
-            });
+        for (i, glow) in outline.glows.iter().enumerate() {
+            renderer.draw_primitive(quad(glow, self.glow[i]));
         }
 
-        for (i, key) in self.get_keys(&b).iter().enumerate() {
-            renderer.draw_primitive(Primitive::Quad {
-                bounds: *key,
-                background: if !self.clicked && self.selected == Some(i) {
-                    Background::Color(Color::from_rgb8(0xA0, 0xA0, 0xA0))
+        for (i, key) in outline.keys.iter().enumerate() {
+            renderer.draw_primitive(quad(
+                key,
+                if !self.clicked && self.selected == Some(i) {
+                    Color::from_rgb8(0xA0, 0xA0, 0xA0)
                 } else {
-                    Background::Color(Color::WHITE)
+                    Color::WHITE
                 },
-                border_radius: [0.5 / 70.0 * len; 4],
-                border_width: 0.0,
-                border_color: Color::TRANSPARENT,
-            });
+            ));
         }
     }
 }
@@ -7,79 +7,341 @@ use async_std::future;
 use futures::stream::StreamExt;
 
 use std::fmt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use serde::Deserialize;
 use serde::Serialize;
 use std::fs::File;
 use std::io::prelude::*;
 use sysinfo::DiskExt;
+use sysinfo::RefreshKind;
 use sysinfo::SystemExt;
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Author {
-    login: String,
-    id: u32,
-    node_id: String,
-    avatar_url: String,
-    gravatar_id: String,
-    url: String,
-    html_url: String,
-    followers_url: String,
-    following_url: String,
-    gists_url: String,
-    starred_url: String,
-    subscriptions_url: String,
-    organizations_url: String,
-    repos_url: String,
-    events_url: String,
-    received_events_url: String,
-    #[serde(rename = "type")]
-    type_: String,
-    site_admin: bool,
+use crate::device_watcher::{self, DeviceKind};
+use crate::firmware_cache::{self, FirmwareCache};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Asset {
+    pub name: String,
+    #[serde(default)]
+    pub size: u32,
+    pub browser_download_url: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Release {
+    pub tag_name: String,
+    #[serde(default)]
+    pub name: String,
+    #[serde(default)]
+    pub draft: bool,
+    #[serde(default)]
+    pub prerelease: bool,
+    #[serde(default)]
+    pub published_at: String,
+    pub assets: Vec<Asset>,
+}
+
+impl Release {
+    /// The UF2 image, `None` if the release has no firmware attached.
+    pub fn firmware(&self) -> Option<&Asset> {
+        self.assets
+            .iter()
+            .find(|asset| asset.name.ends_with(".uf2"))
+    }
+
+    /// The checksum file published next to `firmware`, either as
+    /// `<name>.sha256` or as a shared `SHA256SUMS` file.
+    pub fn checksum(&self, firmware: &Asset) -> Option<&Asset> {
+        let sidecar = format!("{}.sha256", firmware.name);
+        self.assets
+            .iter()
+            .find(|asset| asset.name == sidecar)
+            .or_else(|| {
+                self.assets.iter().find(|asset| {
+                    asset.name.eq_ignore_ascii_case("SHA256SUMS")
+                        || asset.name.eq_ignore_ascii_case("SHA256SUMS.txt")
+                })
+            })
+    }
+}
+
+pub trait ReleaseSource: Send + Sync {
+    /// Identifies where the releases come from, so firmware cached from one
+    /// source is never served for another.
+    fn id(&self) -> String;
+
+    /// Returns the available releases, newest first.
+    fn releases(&self) -> Result<Vec<Release>, ()>;
+
+    fn download(&self, asset: &Asset) -> Result<Vec<u8>, ()>;
+
+    fn find(&self, version: Option<&str>) -> Result<Release, ()> {
+        let releases = self.releases()?;
+
+        match version {
+            Some(version) => releases
+                .into_iter()
+                .find(|release| release.tag_name == version),
+            None => releases
+                .into_iter()
+                .find(|release| !release.draft && !release.prerelease),
+        }
+        .ok_or(())
+    }
+}
+
+fn http_client() -> Result<reqwest::blocking::Client, ()> {
+    reqwest::blocking::Client::builder()
+        .user_agent("2x2macropad_configurator firmware updater")
+        .build()
+        .map_err(|_| ())
+}
+
+fn fetch_index(url: &str) -> Result<Vec<Release>, ()> {
+    http_client()?
+        .get(url)
+        .send()
+        .and_then(|response| response.error_for_status())
+        .and_then(|response| response.json::<Vec<Release>>())
+        .map_err(|_| ())
+}
+
+fn fetch_asset(asset: &Asset) -> Result<Vec<u8>, ()> {
+    http_client()?
+        .get(&asset.browser_download_url)
+        .send()
+        .and_then(|response| response.error_for_status())
+        .and_then(|response| response.bytes())
+        .map(|bytes| bytes.to_vec())
+        .map_err(|_| ())
+}
+
+#[derive(Debug, Clone)]
+pub struct GithubSource {
+    pub owner: String,
+    pub repo: String,
+}
+
+impl ReleaseSource for GithubSource {
+    fn id(&self) -> String {
+        format!("github:{}/{}", self.owner, self.repo)
+    }
+
+    fn releases(&self) -> Result<Vec<Release>, ()> {
+        fetch_index(&format!(
+            "https://api.github.com/repos/{}/{}/releases",
+            self.owner, self.repo
+        ))
+    }
+
+    fn download(&self, asset: &Asset) -> Result<Vec<u8>, ()> {
+        fetch_asset(asset)
+    }
+}
+
+/// A server hosting a JSON index in the same shape as the GitHub releases API.
+#[derive(Debug, Clone)]
+pub struct UrlSource {
+    pub url: String,
+}
+
+impl ReleaseSource for UrlSource {
+    fn id(&self) -> String {
+        format!("url:{}", self.url)
+    }
+
+    fn releases(&self) -> Result<Vec<Release>, ()> {
+        fetch_index(&self.url)
+    }
+
+    fn download(&self, asset: &Asset) -> Result<Vec<u8>, ()> {
+        fetch_asset(asset)
+    }
+}
+
+/// A local folder of UF2 images, each file being treated as its own release
+/// tagged with the file stem.
+#[derive(Debug, Clone)]
+pub struct DirectorySource {
+    pub path: PathBuf,
+}
+
+impl ReleaseSource for DirectorySource {
+    fn id(&self) -> String {
+        format!("directory:{}", self.path.display())
+    }
+
+    fn releases(&self) -> Result<Vec<Release>, ()> {
+        let mut releases = Vec::new();
+
+        for entry in std::fs::read_dir(&self.path).map_err(|_| ())? {
+            let path = entry.map_err(|_| ())?.path();
+            if !path.is_file() || path.extension().map_or(true, |ext| ext != "uf2") {
+                continue;
+            }
+
+            let tag_name = path.file_stem().unwrap().to_string_lossy().to_string();
+            let name = path.file_name().unwrap().to_string_lossy().to_string();
+            let size = std::fs::metadata(&path).map_or(0, |meta| meta.len() as u32);
+
+            let mut assets = vec![Asset {
+                name: name.clone(),
+                size,
+                browser_download_url: path.to_string_lossy().to_string(),
+            }];
+
+            let checksum = path.with_file_name(format!("{}.sha256", name));
+            if checksum.is_file() {
+                assets.push(Asset {
+                    name: format!("{}.sha256", name),
+                    size: 0,
+                    browser_download_url: checksum.to_string_lossy().to_string(),
+                });
+            }
+
+            releases.push(Release {
+                name: tag_name.clone(),
+                tag_name,
+                draft: false,
+                prerelease: false,
+                published_at: String::new(),
+                assets,
+            });
+        }
+
+        // Newest first, with tags that are not versions after every version
+        releases.sort_by_key(|release| {
+            let version = semver::Version::parse(release.tag_name.trim_start_matches('v'));
+            std::cmp::Reverse((version.is_ok(), version.ok(), release.tag_name.clone()))
+        });
+
+        Ok(releases)
+    }
+
+    fn download(&self, asset: &Asset) -> Result<Vec<u8>, ()> {
+        std::fs::read(&asset.browser_download_url).map_err(|_| ())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ReleaseSourceConfig {
+    Github { owner: String, repo: String },
+    Directory { path: PathBuf },
+    Url { url: String },
+}
+
+impl Default for ReleaseSourceConfig {
+    fn default() -> Self {
+        Self::Github {
+            owner: String::from("arfrie22"),
+            repo: String::from("2x2macropad_firmware"),
+        }
+    }
+}
+
+impl ReleaseSourceConfig {
+    pub fn source(&self) -> Arc<dyn ReleaseSource> {
+        match self {
+            ReleaseSourceConfig::Github { owner, repo } => Arc::new(GithubSource {
+                owner: owner.clone(),
+                repo: repo.clone(),
+            }),
+            ReleaseSourceConfig::Directory { path } => {
+                Arc::new(DirectorySource { path: path.clone() })
+            }
+            ReleaseSourceConfig::Url { url } => Arc::new(UrlSource { url: url.clone() }),
+        }
+    }
+}
+
+impl fmt::Display for ReleaseSourceConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReleaseSourceConfig::Github { owner, repo } => write!(f, "GitHub ({}/{})", owner, repo),
+            ReleaseSourceConfig::Directory { path } => write!(f, "{}", path.display()),
+            ReleaseSourceConfig::Url { url } => write!(f, "{}", url),
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Asset {
-    url: String,
-    id: u32,
-    node_id: String,
-    name: String,
-    label: String,
-    uploader: Author,
-    content_type: String,
-    state: String,
-    size: u32,
-    download_count: u32,
-    created_at: String,
-    updated_at: String,
-    browser_download_url: String,
+fn fetch_firmware(
+    source: &dyn ReleaseSource,
+    release: &Release,
+    verify_checksum: bool,
+) -> Result<Vec<u8>, ()> {
+    let asset = release.firmware().ok_or(())?;
+    let firmware = source.download(asset)?;
+
+    if verify_checksum {
+        let checksum = source.download(release.checksum(asset).ok_or(())?)?;
+        let expected =
+            firmware_cache::parse_checksum_file(&String::from_utf8_lossy(&checksum), &asset.name)
+                .ok_or(())?;
+
+        if expected != firmware_cache::sha256_hex(&firmware) {
+            return Err(());
+        }
+    }
+
+    Ok(firmware)
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Release {
-    url: String,
-    assets_url: String,
-    upload_url: String,
-    html_url: String,
-    id: u32,
-    author: Author,
-    node_id: String,
-    tag_name: String,
-    target_commitish: String,
-    name: String,
-    draft: bool,
-    prerelease: bool,
-    created_at: String,
-    published_at: String,
-    assets: Vec<Asset>,
-    tarball_url: String,
-    zipball_url: String,
-    body: String,
+/// Resolves the firmware image for `version`, preferring the local cache so
+/// that a tag which has been flashed before can be flashed again offline.
+/// With `verify_checksum` only cached images which were verified when they
+/// were downloaded are used.
+pub async fn download_firmware(
+    source: Arc<dyn ReleaseSource>,
+    version: Option<String>,
+    verify_checksum: bool,
+) -> Result<Vec<u8>, ()> {
+    tokio::task::spawn_blocking(move || {
+        let cache = FirmwareCache::open();
+        let id = source.id();
+
+        if let (Some(cache), Some(version)) = (&cache, &version) {
+            if let Some(firmware) = cache.get(&id, version, verify_checksum) {
+                return Ok(firmware);
+            }
+        }
+
+        let release = match source.find(version.as_deref()) {
+            Ok(release) => release,
+            Err(_) => {
+                let cache = cache.ok_or(())?;
+                return cache
+                    .latest(&id)
+                    .filter(|_| version.is_none())
+                    .and_then(|tag| cache.get(&id, &tag, verify_checksum))
+                    .ok_or(());
+            }
+        };
+
+        if let Some(firmware) = cache
+            .as_ref()
+            .and_then(|cache| cache.get(&id, &release.tag_name, verify_checksum))
+        {
+            return Ok(firmware);
+        }
+
+        let firmware = fetch_firmware(source.as_ref(), &release, verify_checksum)?;
+        if let Some(cache) = &cache {
+            cache
+                .insert(&id, &release.tag_name, &firmware, verify_checksum)
+                .ok();
+        }
+
+        Ok(firmware)
+    })
+    .await
+    .map_err(|_| ())?
 }
 
-async fn scan_devices() -> Option<PathBuf> {
-    let sys = sysinfo::System::new_all();
+pub async fn scan_devices() -> Option<PathBuf> {
+    let sys = sysinfo::System::new_with_specifics(RefreshKind::new().with_disks_list());
     let mut pico_drive = None;
     for disk in sys.disks() {
         let mount = disk.mount_point();
@@ -94,28 +356,39 @@ async fn scan_devices() -> Option<PathBuf> {
     pico_drive
 }
 
-pub fn connect() -> Subscription<Event> {
+/// Copies a UF2 image onto a mounted bootloader drive.
+pub fn write_firmware(pico: &Path, firmware: &[u8]) -> Result<(), ()> {
+    File::create(pico.join("out.uf2"))
+        .and_then(|mut f| f.write_all(firmware))
+        .map_err(|_| ())
+}
+
+pub fn connect(source: ReleaseSourceConfig, verify_checksum: bool) -> Subscription<Event> {
     struct Connect;
 
     subscription::unfold(
-        std::any::TypeId::of::<Connect>(),
-        State::NoDeviceFound,
-        |state| async move {
+        (
+            std::any::TypeId::of::<Connect>(),
+            source.clone(),
+            verify_checksum,
+        ),
+        State::NoDeviceFound(source.source()),
+        move |state| async move {
             match state {
-                State::NoDeviceFound => {
+                State::NoDeviceFound(source) => {
                     if let Some(pico) = scan_devices().await {
                         let (sender, receiver) = mpsc::channel(100);
                         (
                             Some(Event::Connected(Connection(sender))),
-                            State::DeviceFound(pico, receiver),
+                            State::DeviceFound(source, pico, receiver),
                         )
                     } else {
-                        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+                        device_watcher::wait(DeviceKind::Drive).await;
 
-                        (None, State::NoDeviceFound)
+                        (None, State::NoDeviceFound(source))
                     }
                 }
-                State::DeviceFound(pico, mut input) => {
+                State::DeviceFound(source, pico, mut input) => {
                     let command = future::timeout(
                         std::time::Duration::from_secs(1),
                         input.select_next_some(),
@@ -124,58 +397,46 @@ pub fn connect() -> Subscription<Event> {
                     if let Ok(command) = command {
                         match command {
                             Message::UploadToDevice(version) => {
-                                let client = reqwest::Client::builder()
-                                    .user_agent("2x2macropad_configurator firmware updater")
-                                    .build()
-                                    .unwrap();
-
-                                let releases = client.get("https://api.github.com/repos/arfrie22/2x2macropad_firmware/releases")
-                                    .send()
-                                    .await
-                                    .unwrap()
-                                    .json::<Vec<Release>>()
-                                    .await
-                                    .unwrap();
-
-                                let firmware = if version.is_some() {
-                                    unimplemented!()
-                                } else {
-                                    client
-                                        .get(
-                                            releases
-                                                .first()
-                                                .unwrap()
-                                                .assets
-                                                .first()
-                                                .unwrap()
-                                                .browser_download_url
-                                                .clone(),
+                                let firmware = match download_firmware(
+                                    source.clone(),
+                                    version,
+                                    verify_checksum,
+                                )
+                                .await
+                                {
+                                    Ok(firmware) => firmware,
+                                    Err(_) => {
+                                        return (
+                                            Some(Event::UploadFailed(String::from(
+                                                "Could not download the firmware",
+                                            ))),
+                                            State::DeviceFound(source, pico, input),
                                         )
-                                        .send()
-                                        .await
-                                        .unwrap()
-                                        .bytes()
-                                        .await
-                                        .unwrap()
+                                    }
                                 };
 
-                                let deployed_path = pico.join("out.uf2");
-                                let mut f = File::create(&deployed_path).unwrap();
-
-                                f.write_all(&firmware).unwrap();
-
-                                (Some(Event::Disconnected), State::NoDeviceFound)
+                                if write_firmware(&pico, &firmware).is_err() {
+                                    (
+                                        Some(Event::UploadFailed(format!(
+                                            "Could not write the firmware to {}",
+                                            pico.display()
+                                        ))),
+                                        State::DeviceFound(source, pico, input),
+                                    )
+                                } else {
+                                    (Some(Event::Disconnected), State::NoDeviceFound(source))
+                                }
                             }
 
-                            Message::Close => (None, State::NoDeviceFound),
+                            Message::Close => (None, State::NoDeviceFound(source)),
 
-                            _ => (None, State::DeviceFound(pico, input)),
+                            _ => (None, State::DeviceFound(source, pico, input)),
                         }
                     } else {
                         if pico.exists() {
-                            (None, State::DeviceFound(pico, input))
+                            (None, State::DeviceFound(source, pico, input))
                         } else {
-                            (Some(Event::Disconnected), State::NoDeviceFound)
+                            (Some(Event::Disconnected), State::NoDeviceFound(source))
                         }
                     }
                 }
@@ -186,15 +447,15 @@ pub fn connect() -> Subscription<Event> {
 
 #[allow(clippy::large_enum_variant)]
 enum State {
-    NoDeviceFound,
-    DeviceFound(PathBuf, mpsc::Receiver<Message>),
+    NoDeviceFound(Arc<dyn ReleaseSource>),
+    DeviceFound(Arc<dyn ReleaseSource>, PathBuf, mpsc::Receiver<Message>),
 }
 
 impl fmt::Debug for State {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            State::NoDeviceFound => write!(f, "NoDeviceFound"),
-            State::DeviceFound(path, _) => write!(f, "DeviceFound({:?})", path),
+            State::NoDeviceFound(_) => write!(f, "NoDeviceFound"),
+            State::DeviceFound(_, path, _) => write!(f, "DeviceFound({:?})", path),
         }
     }
 }
@@ -203,6 +464,8 @@ impl fmt::Debug for State {
 pub enum Event {
     Connected(Connection),
     Disconnected,
+    /// Why the firmware could not be flashed.
+    UploadFailed(String),
 }
 
 #[derive(Debug, Clone)]
@@ -256,3 +519,86 @@ impl fmt::Display for Message {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn directory_source_lists_newest_first() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("v1.2.0.uf2"), b"old").unwrap();
+        std::fs::write(dir.path().join("v1.10.0.uf2"), b"new").unwrap();
+        std::fs::write(dir.path().join("nightly.uf2"), b"nightly").unwrap();
+        std::fs::write(dir.path().join("notes.txt"), b"ignored").unwrap();
+
+        let source = DirectorySource {
+            path: dir.path().to_owned(),
+        };
+
+        let releases = source.releases().unwrap();
+        let tags = releases
+            .iter()
+            .map(|release| release.tag_name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(tags, ["v1.10.0", "v1.2.0", "nightly"]);
+
+        let latest = source.find(None).unwrap();
+        assert_eq!(source.download(latest.firmware().unwrap()).unwrap(), b"new");
+        assert!(source.find(Some("v2.0.0")).is_err());
+    }
+
+    #[test]
+    fn release_without_uf2_has_no_firmware() {
+        let release: Release = serde_json::from_str(
+            r#"{"tag_name": "v1.0.0", "assets": [{"name": "notes.txt", "browser_download_url": "x"}]}"#,
+        )
+        .unwrap();
+
+        assert!(release.firmware().is_none());
+    }
+
+    #[test]
+    fn url_source_reads_github_compatible_index() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let index = format!(
+            r#"[{{"tag_name": "v1.0.0", "assets": [{{"name": "firmware.uf2", "browser_download_url": "{}/firmware.uf2"}}]}}]"#,
+            base
+        );
+
+        let server = std::thread::spawn(move || {
+            for body in [index.into_bytes(), b"firmware".to_vec()] {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    line.clear();
+                }
+
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                )
+                .unwrap();
+                stream.write_all(&body).unwrap();
+            }
+        });
+
+        let source = UrlSource {
+            url: format!("{}/releases", base),
+        };
+        let release = source.find(None).unwrap();
+        assert_eq!(release.tag_name, "v1.0.0");
+        assert_eq!(
+            source.download(release.firmware().unwrap()).unwrap(),
+            b"firmware"
+        );
+
+        server.join().unwrap();
+    }
+}
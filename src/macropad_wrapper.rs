@@ -1,11 +1,13 @@
 use crc::{Crc, CRC_32_CKSUM};
-use hidapi::HidDevice;
 use macropad_protocol::data_protocol::{
     BuildInfoElements, ConfigElements, DataCommand, KeyConfigElements, KeyMode, LedCommand,
     LedEffect,
 };
 use usbd_human_interface_device::page::{Consumer, Keyboard};
 
+use crate::macro_parser;
+use crate::transport::Transport;
+
 pub const CKSUM: Crc<u32> = Crc::<u32>::new(&CRC_32_CKSUM);
 pub const MACRO_SIZE: usize = 4092;
 
@@ -18,22 +20,16 @@ pub const EFFECTS: [LedEffect; 6] = [
     LedEffect::Rainbow,
 ];
 
-pub fn send_command(device: &HidDevice, command: [u8; 65]) -> Result<[u8; 64], ()> {
-    let mut response = [0u8; 64];
-    device.write(&command).unwrap();
-    if (device.read_timeout(&mut response, 1000)).is_err() {
-        Err(())
-    } else {
-        Ok(response)
-    }
+pub fn send_command(device: &dyn Transport, command: [u8; 65]) -> Result<[u8; 64], ()> {
+    device.send(&command)
 }
 
-pub fn prime_device(device: &HidDevice) -> Result<(), ()> {
+pub fn prime_device(device: &dyn Transport) -> Result<(), ()> {
     send_command(device, [0u8; 65])?;
     Ok(())
 }
 
-pub fn get_build_version(device: &HidDevice) -> Result<String, ()> {
+pub fn get_build_version(device: &dyn Transport) -> Result<String, ()> {
     let mut data = [0u8; 65];
     data[1] = DataCommand::GetBuildVersion as u8;
     let buf = send_command(device, data)?;
@@ -46,7 +42,7 @@ pub fn get_build_version(device: &HidDevice) -> Result<String, ()> {
     }
 }
 
-pub fn enter_bootloader(device: &HidDevice) -> Result<(), ()> {
+pub fn enter_bootloader(device: &dyn Transport) -> Result<(), ()> {
     let mut data = [0u8; 65];
     data[1] = DataCommand::EnterBootloader as u8;
     send_command(device, data)?;
@@ -54,7 +50,7 @@ pub fn enter_bootloader(device: &HidDevice) -> Result<(), ()> {
     Ok(())
 }
 
-pub fn get_key_mode(device: &HidDevice, index: u8) -> Result<KeyMode, ()> {
+pub fn get_key_mode(device: &dyn Transport, index: u8) -> Result<KeyMode, ()> {
     let mut data = [0u8; 65];
     data[1] = DataCommand::ReadKeyConfig as u8;
     data[2] = KeyConfigElements::KeyMode as u8;
@@ -68,7 +64,7 @@ pub fn get_key_mode(device: &HidDevice, index: u8) -> Result<KeyMode, ()> {
     }
 }
 
-pub fn set_key_mode(device: &HidDevice, index: u8, mode: KeyMode) -> Result<(), ()> {
+pub fn set_key_mode(device: &dyn Transport, index: u8, mode: KeyMode) -> Result<(), ()> {
     let mut data = [0u8; 65];
     data[1] = DataCommand::WriteKeyConfig as u8;
     data[2] = KeyConfigElements::KeyMode as u8;
@@ -83,7 +79,7 @@ pub fn set_key_mode(device: &HidDevice, index: u8, mode: KeyMode) -> Result<(),
     }
 }
 
-pub fn get_keyboard_data(device: &HidDevice, index: u8) -> Result<Keyboard, ()> {
+pub fn get_keyboard_data(device: &dyn Transport, index: u8) -> Result<Keyboard, ()> {
     let mut data = [0u8; 65];
     data[1] = DataCommand::ReadKeyConfig as u8;
     data[2] = KeyConfigElements::KeyboardData as u8;
@@ -97,7 +93,7 @@ pub fn get_keyboard_data(device: &HidDevice, index: u8) -> Result<Keyboard, ()>
     }
 }
 
-pub fn set_keyboard_data(device: &HidDevice, index: u8, keyboard: Keyboard) -> Result<(), ()> {
+pub fn set_keyboard_data(device: &dyn Transport, index: u8, keyboard: Keyboard) -> Result<(), ()> {
     let mut data = [0u8; 65];
     data[1] = DataCommand::WriteKeyConfig as u8;
     data[2] = KeyConfigElements::KeyboardData as u8;
@@ -112,7 +108,7 @@ pub fn set_keyboard_data(device: &HidDevice, index: u8, keyboard: Keyboard) -> R
     }
 }
 
-pub fn get_consumer_data(device: &HidDevice, index: u8) -> Result<Consumer, ()> {
+pub fn get_consumer_data(device: &dyn Transport, index: u8) -> Result<Consumer, ()> {
     let mut data = [0u8; 65];
     data[1] = DataCommand::ReadKeyConfig as u8;
     data[2] = KeyConfigElements::ConsumerData as u8;
@@ -126,7 +122,7 @@ pub fn get_consumer_data(device: &HidDevice, index: u8) -> Result<Consumer, ()>
     }
 }
 
-pub fn set_consumer_data(device: &HidDevice, index: u8, consumer: Consumer) -> Result<(), ()> {
+pub fn set_consumer_data(device: &dyn Transport, index: u8, consumer: Consumer) -> Result<(), ()> {
     let mut data = [0u8; 65];
     data[1] = DataCommand::WriteKeyConfig as u8;
     data[2] = KeyConfigElements::ConsumerData as u8;
@@ -141,7 +137,7 @@ pub fn set_consumer_data(device: &HidDevice, index: u8, consumer: Consumer) -> R
     }
 }
 
-pub fn get_key_color(device: &HidDevice, index: u8) -> Result<(u8, u8, u8), ()> {
+pub fn get_key_color(device: &dyn Transport, index: u8) -> Result<(u8, u8, u8), ()> {
     let mut data = [0u8; 65];
     data[1] = DataCommand::ReadKeyConfig as u8;
     data[2] = KeyConfigElements::KeyColor as u8;
@@ -155,7 +151,7 @@ pub fn get_key_color(device: &HidDevice, index: u8) -> Result<(u8, u8, u8), ()>
     }
 }
 
-pub fn set_key_color(device: &HidDevice, index: u8, color: (u8, u8, u8)) -> Result<(), ()> {
+pub fn set_key_color(device: &dyn Transport, index: u8, color: (u8, u8, u8)) -> Result<(), ()> {
     let mut data = [0u8; 65];
     data[1] = DataCommand::WriteKeyConfig as u8;
     data[2] = KeyConfigElements::KeyColor as u8;
@@ -172,60 +168,76 @@ pub fn set_key_color(device: &HidDevice, index: u8, color: (u8, u8, u8)) -> Resu
     }
 }
 
-pub fn get_macro(device: &HidDevice, index: u8) -> Result<[u8; 4092], ()> {
+/// The offset and size of every chunk a macro is transferred in.
+fn macro_chunks() -> impl Iterator<Item = (usize, usize)> {
+    (0..MACRO_SIZE)
+        .step_by(59)
+        .map(|offset| (offset, (MACRO_SIZE - offset).min(59)))
+}
+
+/// Reads a macro, stopping once its end marker has been received.
+pub fn get_macro(device: &dyn Transport, index: u8) -> Result<[u8; 4092], ()> {
     let mut output = [0u8; 4092];
-    for i in 0..(MACRO_SIZE / 59) {
+    for (offset, size) in macro_chunks() {
         let mut data = [0u8; 65];
-        let offset: u16 = i as u16 * 59;
-        let size = if offset as usize + 59 > MACRO_SIZE {
-            MACRO_SIZE % 59
-        } else {
-            59
-        };
         data[1] = DataCommand::ReadMacro as u8;
         data[2] = index;
-        data[3..5].copy_from_slice(&offset.to_le_bytes());
+        data[3..5].copy_from_slice(&(offset as u16).to_le_bytes());
         data[5] = size as u8;
 
         let buf = send_command(device, data)?;
-        if buf[5..(5 + size)] == [0u8; 64][5..(5 + size)] {
+        output[offset..(offset + size)].copy_from_slice(&buf[5..(5 + size)]);
+
+        if macro_parser::macro_len(&output[..(offset + size)]).is_some() {
             break;
-        } else {
-            output[(i * 59)..((i * 59) + size)].copy_from_slice(&buf[5..(5 + size)]);
         }
     }
 
     Ok(output)
 }
 
-pub fn set_macro(device: &HidDevice, index: u8, macro_data: &[u8; 4092]) -> Result<(), ()> {
-    for i in 0..(MACRO_SIZE / 59) {
+/// Writes a macro to a slot which has just been cleared.
+pub fn set_macro(device: &dyn Transport, index: u8, macro_data: &[u8; 4092]) -> Result<(), ()> {
+    set_macro_delta(device, index, &[0u8; MACRO_SIZE], macro_data)?;
+    Ok(())
+}
+
+/// Writes only the chunks of `macro_data` which differ from `previous`, the
+/// macro currently stored in the slot. Returns the number of chunks written.
+pub fn set_macro_delta(
+    device: &dyn Transport,
+    index: u8,
+    previous: &[u8; 4092],
+    macro_data: &[u8; 4092],
+) -> Result<usize, ()> {
+    let mut written = 0;
+    for (offset, size) in macro_chunks() {
+        if previous[offset..(offset + size)] == macro_data[offset..(offset + size)] {
+            continue;
+        }
+
         let mut data = [0u8; 65];
-        let offset: u16 = i as u16 * 59;
-        let size = if offset as usize + 59 > MACRO_SIZE {
-            MACRO_SIZE % 59
-        } else {
-            59
-        };
         data[1] = DataCommand::WriteMacro as u8;
         data[2] = index;
-        data[3..5].copy_from_slice(&offset.to_le_bytes());
+        data[3..5].copy_from_slice(&(offset as u16).to_le_bytes());
         data[5] = size as u8;
-        data[6..(6 + size)].copy_from_slice(&macro_data[(i * 59)..((i * 59) + size)]);
-        if data[6..(6 + size)] == [0u8; 65][6..(6 + size)] {
-            break;
-        }
+        data[6..(6 + size)].copy_from_slice(&macro_data[offset..(offset + size)]);
 
         let buf = send_command(device, data)?;
         if data[1..65] != buf {
             return Err(());
         }
+        written += 1;
     }
 
-    Ok(())
+    Ok(written)
 }
 
-pub fn validate_macro(device: &HidDevice, index: u8, macro_data: &[u8; 4092]) -> Result<(), ()> {
+pub fn validate_macro(
+    device: &dyn Transport,
+    index: u8,
+    macro_data: &[u8; 4092],
+) -> Result<(), ()> {
     let mut data = [0u8; 65];
     data[1] = DataCommand::ValidateMacro as u8;
     data[2] = index;
@@ -239,7 +251,7 @@ pub fn validate_macro(device: &HidDevice, index: u8, macro_data: &[u8; 4092]) ->
     }
 }
 
-pub fn clear_macro(device: &HidDevice, index: u8) -> Result<(), ()> {
+pub fn clear_macro(device: &dyn Transport, index: u8) -> Result<(), ()> {
     let mut data = [0u8; 65];
     data[1] = DataCommand::ClearMacro as u8;
     data[2] = index;
@@ -252,7 +264,7 @@ pub fn clear_macro(device: &HidDevice, index: u8) -> Result<(), ()> {
     }
 }
 
-pub fn get_tap_speed(device: &HidDevice) -> Result<u32, ()> {
+pub fn get_tap_speed(device: &dyn Transport) -> Result<u32, ()> {
     let mut data = [0u8; 65];
     data[1] = DataCommand::ReadConfig as u8;
     data[2] = ConfigElements::TapSpeed as u8;
@@ -265,7 +277,7 @@ pub fn get_tap_speed(device: &HidDevice) -> Result<u32, ()> {
     }
 }
 
-pub fn set_tap_speed(device: &HidDevice, speed: u32) -> Result<(), ()> {
+pub fn set_tap_speed(device: &dyn Transport, speed: u32) -> Result<(), ()> {
     let mut data = [0u8; 65];
     data[1] = DataCommand::WriteConfig as u8;
     data[2] = ConfigElements::TapSpeed as u8;
@@ -279,7 +291,7 @@ pub fn set_tap_speed(device: &HidDevice, speed: u32) -> Result<(), ()> {
     }
 }
 
-pub fn get_hold_speed(device: &HidDevice) -> Result<u32, ()> {
+pub fn get_hold_speed(device: &dyn Transport) -> Result<u32, ()> {
     let mut data = [0u8; 65];
     data[1] = DataCommand::ReadConfig as u8;
     data[2] = ConfigElements::HoldSpeed as u8;
@@ -292,7 +304,7 @@ pub fn get_hold_speed(device: &HidDevice) -> Result<u32, ()> {
     }
 }
 
-pub fn set_hold_speed(device: &HidDevice, speed: u32) -> Result<(), ()> {
+pub fn set_hold_speed(device: &dyn Transport, speed: u32) -> Result<(), ()> {
     let mut data = [0u8; 65];
     data[1] = DataCommand::WriteConfig as u8;
     data[2] = ConfigElements::HoldSpeed as u8;
@@ -306,7 +318,7 @@ pub fn set_hold_speed(device: &HidDevice, speed: u32) -> Result<(), ()> {
     }
 }
 
-pub fn get_led_base_color(device: &HidDevice) -> Result<(u8, u8, u8), ()> {
+pub fn get_led_base_color(device: &dyn Transport) -> Result<(u8, u8, u8), ()> {
     let mut data = [0u8; 65];
     data[1] = DataCommand::GetLed as u8;
     data[2] = LedCommand::BaseColor as u8;
@@ -319,7 +331,7 @@ pub fn get_led_base_color(device: &HidDevice) -> Result<(u8, u8, u8), ()> {
     }
 }
 
-pub fn set_led_base_color(device: &HidDevice, color: (u8, u8, u8)) -> Result<(), ()> {
+pub fn set_led_base_color(device: &dyn Transport, color: (u8, u8, u8)) -> Result<(), ()> {
     let mut data = [0u8; 65];
     data[1] = DataCommand::SetLed as u8;
     data[2] = LedCommand::BaseColor as u8;
@@ -335,7 +347,7 @@ pub fn set_led_base_color(device: &HidDevice, color: (u8, u8, u8)) -> Result<(),
     }
 }
 
-pub fn get_led_effect(device: &HidDevice) -> Result<LedEffect, ()> {
+pub fn get_led_effect(device: &dyn Transport) -> Result<LedEffect, ()> {
     let mut data = [0u8; 65];
     data[1] = DataCommand::GetLed as u8;
     data[2] = LedCommand::Effect as u8;
@@ -348,7 +360,7 @@ pub fn get_led_effect(device: &HidDevice) -> Result<LedEffect, ()> {
     }
 }
 
-pub fn set_led_effect(device: &HidDevice, effect: LedEffect) -> Result<(), ()> {
+pub fn set_led_effect(device: &dyn Transport, effect: LedEffect) -> Result<(), ()> {
     let mut data = [0u8; 65];
     data[1] = DataCommand::SetLed as u8;
     data[2] = LedCommand::Effect as u8;
@@ -362,7 +374,7 @@ pub fn set_led_effect(device: &HidDevice, effect: LedEffect) -> Result<(), ()> {
     }
 }
 
-pub fn get_led_brightness(device: &HidDevice) -> Result<u8, ()> {
+pub fn get_led_brightness(device: &dyn Transport) -> Result<u8, ()> {
     let mut data = [0u8; 65];
     data[1] = DataCommand::GetLed as u8;
     data[2] = LedCommand::Brightness as u8;
@@ -375,7 +387,7 @@ pub fn get_led_brightness(device: &HidDevice) -> Result<u8, ()> {
     }
 }
 
-pub fn set_led_brightness(device: &HidDevice, brightness: u8) -> Result<(), ()> {
+pub fn set_led_brightness(device: &dyn Transport, brightness: u8) -> Result<(), ()> {
     let mut data = [0u8; 65];
     data[1] = DataCommand::SetLed as u8;
     data[2] = LedCommand::Brightness as u8;
@@ -389,7 +401,7 @@ pub fn set_led_brightness(device: &HidDevice, brightness: u8) -> Result<(), ()>
     }
 }
 
-pub fn get_led_effect_period(device: &HidDevice) -> Result<f32, ()> {
+pub fn get_led_effect_period(device: &dyn Transport) -> Result<f32, ()> {
     let mut data = [0u8; 65];
     data[1] = DataCommand::GetLed as u8;
     data[2] = LedCommand::EffectPeriod as u8;
@@ -402,7 +414,7 @@ pub fn get_led_effect_period(device: &HidDevice) -> Result<f32, ()> {
     }
 }
 
-pub fn set_led_effect_period(device: &HidDevice, period: f32) -> Result<(), ()> {
+pub fn set_led_effect_period(device: &dyn Transport, period: f32) -> Result<(), ()> {
     let mut data = [0u8; 65];
     data[1] = DataCommand::SetLed as u8;
     data[2] = LedCommand::EffectPeriod as u8;
@@ -416,7 +428,7 @@ pub fn set_led_effect_period(device: &HidDevice, period: f32) -> Result<(), ()>
     }
 }
 
-pub fn get_led_effect_offset(device: &HidDevice) -> Result<f32, ()> {
+pub fn get_led_effect_offset(device: &dyn Transport) -> Result<f32, ()> {
     let mut data = [0u8; 65];
     data[1] = DataCommand::GetLed as u8;
     data[2] = LedCommand::EffectOffset as u8;
@@ -429,7 +441,7 @@ pub fn get_led_effect_offset(device: &HidDevice) -> Result<f32, ()> {
     }
 }
 
-pub fn set_led_effect_offset(device: &HidDevice, offset: f32) -> Result<(), ()> {
+pub fn set_led_effect_offset(device: &dyn Transport, offset: f32) -> Result<(), ()> {
     let mut data = [0u8; 65];
     data[1] = DataCommand::SetLed as u8;
     data[2] = LedCommand::EffectOffset as u8;
@@ -443,7 +455,7 @@ pub fn set_led_effect_offset(device: &HidDevice, offset: f32) -> Result<(), ()>
     }
 }
 
-pub fn get_firmware_version(device: &HidDevice) -> Result<String, ()> {
+pub fn get_firmware_version(device: &dyn Transport) -> Result<String, ()> {
     let mut data = [0u8; 65];
     data[1] = DataCommand::GetBuildInfo as u8;
     data[2] = BuildInfoElements::FirmwareVersion as u8;
@@ -457,7 +469,7 @@ pub fn get_firmware_version(device: &HidDevice) -> Result<String, ()> {
     }
 }
 
-pub fn get_build_date(device: &HidDevice) -> Result<String, ()> {
+pub fn get_build_date(device: &dyn Transport) -> Result<String, ()> {
     let mut data = [0u8; 65];
     data[1] = DataCommand::GetBuildInfo as u8;
     data[2] = BuildInfoElements::BuildDate as u8;
@@ -471,7 +483,7 @@ pub fn get_build_date(device: &HidDevice) -> Result<String, ()> {
     }
 }
 
-pub fn get_build_timestamp(device: &HidDevice) -> Result<String, ()> {
+pub fn get_build_timestamp(device: &dyn Transport) -> Result<String, ()> {
     let mut data = [0u8; 65];
     data[1] = DataCommand::GetBuildInfo as u8;
     data[2] = BuildInfoElements::BuildTimestamp as u8;
@@ -485,7 +497,7 @@ pub fn get_build_timestamp(device: &HidDevice) -> Result<String, ()> {
     }
 }
 
-pub fn get_build_profile(device: &HidDevice) -> Result<String, ()> {
+pub fn get_build_profile(device: &dyn Transport) -> Result<String, ()> {
     let mut data = [0u8; 65];
     data[1] = DataCommand::GetBuildInfo as u8;
     data[2] = BuildInfoElements::BuildProfile as u8;
@@ -499,7 +511,7 @@ pub fn get_build_profile(device: &HidDevice) -> Result<String, ()> {
     }
 }
 
-pub fn get_git_hash(device: &HidDevice) -> Result<String, ()> {
+pub fn get_git_hash(device: &dyn Transport) -> Result<String, ()> {
     let mut data = [0u8; 65];
     data[1] = DataCommand::GetBuildInfo as u8;
     data[2] = BuildInfoElements::GitHash as u8;
@@ -513,7 +525,7 @@ pub fn get_git_hash(device: &HidDevice) -> Result<String, ()> {
     }
 }
 
-pub fn get_git_branch(device: &HidDevice) -> Result<String, ()> {
+pub fn get_git_branch(device: &dyn Transport) -> Result<String, ()> {
     let mut data = [0u8; 65];
     data[1] = DataCommand::GetBuildInfo as u8;
     data[2] = BuildInfoElements::GitBranch as u8;
@@ -527,7 +539,7 @@ pub fn get_git_branch(device: &HidDevice) -> Result<String, ()> {
     }
 }
 
-pub fn get_git_semver(device: &HidDevice) -> Result<String, ()> {
+pub fn get_git_semver(device: &dyn Transport) -> Result<String, ()> {
     let mut data = [0u8; 65];
     data[1] = DataCommand::GetBuildInfo as u8;
     data[2] = BuildInfoElements::GitSemver as u8;
@@ -1,31 +1,45 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
 use iced::theme::Button;
 use iced::widget::{
-    button, column, container, pick_list, radio, row, slider, text, text_input, Column, Container,
-    Space, Text,
+    button, column, container, pick_list, radio, row, scrollable, slider, text, text_input, Column,
+    Container, Row, Space, Text,
 };
 use iced::{alignment, executor, window, Padding};
 use iced::{Application, Command, Element, Length, Settings, Subscription, Theme};
 use iced_aw::style::{BadgeStyles, TabBarStyles};
 use iced_aw::{Badge, ColorPicker, TabLabel, Tabs};
 use iced_native::widget::checkbox;
+use macropad_configurator::app_config::AppConfig;
+use macropad_configurator::brightness_schedule::{BrightnessSchedule, ScheduleRunner, Transition};
+use macropad_configurator::color_pipeline::ColorPipeline;
 use macropad_configurator::font::{Icon, ICON_FONT, ROBOTO_BYTES};
 use macropad_configurator::hid_manager::Connection;
-use macropad_configurator::led_effects::LedRunner;
+use macropad_configurator::led_effects::{self, LedRunner};
+use macropad_configurator::led_export::{self, ExportFormat};
 use macropad_configurator::macro_editor::{Action, ActionOptions, SelectedAction};
 use macropad_configurator::macro_parser::LedConfig;
+use macropad_configurator::macro_preview::MacroTimeline;
+use macropad_configurator::palette::{self, Harmony, Palette};
+use macropad_configurator::protocol_version::{Adapter, Features};
+use macropad_configurator::reactive_lighting::{self, RateLimiter, ReactiveMode, ReactiveSource};
 use macropad_configurator::type_wrapper::{Chord, ConsumerWrapper, KeyboardWrapper};
+use macropad_configurator::write_scheduler::WriteScheduler;
 use macropad_configurator::{
-    hid_manager, macro_editor, macro_parser, macropad, macropad_updater, type_wrapper,
+    control_api, device_watcher, hid_manager, macro_editor, macro_parser, macropad,
+    macropad_updater, profile, protocol_log, provisioning, type_wrapper,
 };
 use macropad_protocol::data_protocol::LedEffect;
 use num_enum::{FromPrimitive, IntoPrimitive, TryFromPrimitive};
 use iced_core::Color;
 
-const ACTION_DELAY: u64 = 200;
+/// Number of exchanges shown in the protocol console.
+const PROTOCOL_CONSOLE_SIZE: usize = 500;
+/// Number of frames and size in pixels of exported LED previews.
+const EXPORT_FRAMES: usize = 30;
+const EXPORT_SIZE: u32 = 256;
 
 const HEADER_SIZE: u16 = 32;
 const TAB_PADDING: u16 = 16;
@@ -53,20 +67,55 @@ pub fn main() -> iced::Result {
 struct Configurator {
     state: State,
     theme: Theme,
+    config: AppConfig,
+    devices: Vec<hid_manager::DeviceId>,
+    connections: HashMap<hid_manager::DeviceId, Connection>,
+    provision_runs: usize,
     key_tab: KeyTab,
     led_tab: LedTab,
     settings_tab: SettingsTab,
+    protocol_tab: ProtocolTab,
+    /// Edits waiting to be written to the selected macropad.
+    scheduler: WriteScheduler,
+    /// The last write the device failed to take, shown in the status bar.
+    command_error: Option<String>,
+    /// Profiles which can be opened for editing offline.
+    offline_profiles: Vec<(String, std::path::PathBuf)>,
+    offline_selected: Option<String>,
+    offline_error: Option<String>,
+    /// A macropad which connected while editing offline, waiting for the
+    /// edits to be pushed to it or discarded.
+    pending_device: Option<Connection>,
+    /// Settings changed for a while through the control API, waiting to be
+    /// put back.
+    control_restore: control_api::Restore,
+    /// Whether firmware is being flashed to the bootloader drive.
+    uploading: bool,
+    /// Why the last firmware upload failed.
+    upload_error: Option<String>,
+    reactive_limiter: RateLimiter,
+    schedule_runner: ScheduleRunner,
+    /// Set when the macropad was too far behind to take a message, handled
+    /// once the current message has been.
+    write_dropped: bool,
+    /// The last device plugged in while disconnected, until it is found.
+    device_attached: Option<device_watcher::DeviceKind>,
 }
 
 #[derive(Debug, Clone)]
 pub enum Message {
     HidMessage(hid_manager::Message),
     HidEvent(hid_manager::Event),
+    DeviceSelected(hid_manager::DeviceId),
     UpdaterEvent(macropad_updater::Event),
+    DeviceChanged(device_watcher::Change),
     EditorMessage(macro_editor::Message),
-    CommandSent(macropad_protocol::data_protocol::DataCommand, [u8; 64]),
-    CommandReceived(macropad_protocol::data_protocol::DataCommand, [u8; 64]),
-    CommandErrored,
+    ProtocolExchange(protocol_log::Exchange),
+    ProtocolFilterSelected(String),
+    ClearProtocolLog,
+    ExportTrace,
+    StartCapture,
+    FinishCapture,
     MacropadBootloader,
     UploadLatestFirmware,
     ButtonPressed(usize),
@@ -78,6 +127,7 @@ pub enum Message {
     TabSelected(usize),
     KeyModeChanged(macropad_protocol::data_protocol::KeyMode),
     LoadMacro(macro_parser::MacroType),
+    PlayMacro(macro_parser::MacroType),
     SaveMacro,
     KeyboardDataChanged(KeyboardWrapper),
     ConsumerDataChanged(ConsumerWrapper),
@@ -109,6 +159,51 @@ pub enum Message {
     MacroActionChordAlt(bool),
     MacroActionChordGui(bool),
     MacroActionLoopCountChangedText(String),
+    ProfileNameChangedText(String),
+    SaveProfile,
+    LoadProfileSelected(String),
+    LoadProfile,
+    OpenProvisioning,
+    CloseProvisioning,
+    ProvisionProfileSelected(String),
+    ProvisionFlashToggled(bool),
+    StartProvisioning,
+    StopProvisioning,
+    ProvisionEvent(provisioning::Event),
+    VerifyWritesToggled(bool),
+    VerifyDevice,
+    OfflineProfileSelected(String),
+    ExportLeds(ExportFormat),
+    ControlRequest(control_api::Request),
+    ControlApiToggled(bool),
+    GammaChanged(f32),
+    ColorCorrectionChanged(usize, f32),
+    SaveColorPipeline,
+    LedPresetSelected(String),
+    LedPresetNameChangedText(String),
+    SaveLedPreset,
+    ApplyLedPreset,
+    DeleteLedPreset,
+    ColorTextChanged(ColorTarget, String),
+    ColorTextSubmitted(ColorTarget),
+    PaletteColorSelected(ColorTarget, (u8, u8, u8)),
+    SaveSwatch((u8, u8, u8)),
+    RemoveSwatch((u8, u8, u8)),
+    ApplyHarmony(Harmony),
+    ReactiveModeSelected(ReactiveMode),
+    ReactiveImagePathChangedText(String),
+    ReactiveImagePathSubmitted,
+    ReactiveColors(Result<[(u8, u8, u8); 4], String>),
+    ScheduleToggled(bool),
+    ScheduleWeekendsToggled(bool),
+    ScheduleTimeChangedText(String),
+    ScheduleLevelChangedText(String),
+    AddScheduleTransition,
+    RemoveScheduleTransition(usize),
+    EditOffline,
+    CloseOffline,
+    PushOfflineEdits,
+    DiscardOfflineEdits,
 }
 
 impl Application for Configurator {
@@ -118,6 +213,8 @@ impl Application for Configurator {
     type Flags = ();
 
     fn new(_flags: ()) -> (Configurator, Command<Message>) {
+        let config = AppConfig::load();
+
         (
             Configurator {
                 state: State::Disconnected(None),
@@ -126,9 +223,27 @@ impl Application for Configurator {
                     dark_light::Mode::Dark => Theme::Dark,
                     dark_light::Mode::Light => Theme::Light,
                 },
+                scheduler: WriteScheduler::new(config.write_delay()),
+                config,
+                devices: Vec::new(),
+                connections: HashMap::new(),
+                provision_runs: 0,
                 key_tab: KeyTab::default(),
                 led_tab: LedTab::default(),
                 settings_tab: SettingsTab::default(),
+                protocol_tab: ProtocolTab::default(),
+                command_error: None,
+                offline_profiles: profile::list_profiles(),
+                offline_selected: None,
+                offline_error: None,
+                pending_device: None,
+                control_restore: control_api::Restore::default(),
+                uploading: false,
+                upload_error: None,
+                reactive_limiter: RateLimiter::default(),
+                schedule_runner: ScheduleRunner::default(),
+                write_dropped: false,
+                device_attached: None,
             },
             Command::none(),
         )
@@ -199,39 +314,517 @@ impl Application for Configurator {
                 self.key_tab.editor.request_redraw();
             }
             Message::HidMessage(_) => {}
-            Message::HidEvent(hid_manager::Event::Connected(connection)) => {
-                if let State::Disconnected(Some(con)) = &mut self.state {
-                    con.send(macropad_updater::Message::Close)
+            Message::HidEvent(hid_manager::Event::DevicesChanged(devices)) => {
+                self.connections.retain(|id, _| devices.contains(id));
+                self.devices = devices;
+
+                if let Some(pending) = &self.pending_device {
+                    if !self.connections.contains_key(pending.device()) {
+                        self.pending_device = None;
+                    }
                 }
 
-                self.key_tab = KeyTab::new(connection.get_macropad());
-                self.led_tab = LedTab::new(connection.get_macropad(), LedRunner::default());
-                self.settings_tab = SettingsTab::new(connection.get_macropad(), self.theme.clone());
-                self.state = State::Connected(connection, Page::MainPage(0));
+                if let State::Connected(con, _) = &self.state {
+                    if !con.is_offline() && !self.connections.contains_key(con.device()) {
+                        match self.connections.values().next().cloned() {
+                            Some(connection) => self.select_device(connection),
+                            None => {
+                                // Nowhere left to write the pending edits to
+                                self.scheduler.flush();
+                                self.state = State::Disconnected(None)
+                            }
+                        }
+                    }
+                }
             }
-            Message::HidEvent(hid_manager::Event::Disconnected) => {
-                self.state = State::Disconnected(None);
+            Message::HidEvent(hid_manager::Event::Connected(mut connection)) => {
+                self.device_attached = None;
+                if !connection.send(hid_manager::Message::Verify(self.config.verify_writes)) {
+                    self.write_dropped = true;
+                }
+                self.connections
+                    .insert(connection.device().clone(), connection.clone());
+
+                match &mut self.state {
+                    State::Disconnected(con) => {
+                        if let Some(con) = con {
+                            con.send(macropad_updater::Message::Close)
+                        }
+
+                        self.select_device(connection);
+                    }
+                    State::Connected(con, _)
+                        if con.is_offline() && self.pending_device.is_none() =>
+                    {
+                        self.pending_device = Some(connection);
+                    }
+                    _ => {}
+                }
+            }
+            Message::HidEvent(hid_manager::Event::Disconnected(id)) => {
+                self.connections.remove(&id);
+
+                if let Some(pending) = &self.pending_device {
+                    if pending.device() == &id {
+                        self.pending_device = None;
+                    }
+                }
+
+                if let State::Connected(con, _) = &self.state {
+                    if con.device() == &id {
+                        match self.connections.values().next().cloned() {
+                            Some(connection) => self.select_device(connection),
+                            None => {
+                                // Nowhere left to write the pending edits to
+                                self.scheduler.flush();
+                                self.state = State::Disconnected(None)
+                            }
+                        }
+                    }
+                }
+            }
+            Message::HidEvent(hid_manager::Event::CommandResult(id, command, status)) => {
+                if let State::Connected(con, _) = &self.state {
+                    if con.device() == &id {
+                        let failure = match status {
+                            hid_manager::CommandStatus::Written => {
+                                self.command_error = None;
+                                None
+                            }
+                            hid_manager::CommandStatus::Unsupported => None,
+                            hid_manager::CommandStatus::Mismatch => Some("Device did not accept"),
+                            hid_manager::CommandStatus::Failed => Some("Device did not answer"),
+                        };
+                        self.settings_tab.timings = Some(con.timings());
+
+                        // The cache still holds the old value, so the tabs
+                        // go back to what the device has
+                        if let Some(failure) = failure {
+                            let error =
+                                format!("{}: {}", failure, hid_manager::Message::Set(command));
+                            self.settings_tab.verify_log.push(error.clone());
+                            self.command_error = Some(error);
+                            self.key_tab.update_config(con.get_macropad());
+                            self.led_tab.update_config(con.get_macropad());
+                            self.settings_tab.update_config(con.get_macropad());
+                        }
+                    }
+                }
+            }
+            Message::HidEvent(hid_manager::Event::TransactionApplied(id)) => {
+                if let State::Connected(con, _) = &self.state {
+                    if con.device() == &id {
+                        if std::mem::take(&mut self.led_tab.applying_preset) {
+                            self.led_tab.preset_status = Some(String::from("Preset applied"));
+                        } else {
+                            self.settings_tab.load_status = Some(String::from("Profile applied"));
+                        }
+                        self.settings_tab.timings = Some(con.timings());
+                        self.key_tab.update_config(con.get_macropad());
+                        self.led_tab.update_config(con.get_macropad());
+                        self.settings_tab.update_config(con.get_macropad());
+                    }
+                }
+            }
+            Message::HidEvent(hid_manager::Event::TransactionFailed(id, error)) => {
+                if let State::Connected(con, _) = &self.state {
+                    if con.device() == &id {
+                        if std::mem::take(&mut self.led_tab.applying_preset) {
+                            self.led_tab.preset_status = Some(error.to_string());
+                        } else {
+                            self.settings_tab.load_status = Some(error.to_string());
+                        }
+                        self.settings_tab.timings = Some(con.timings());
+                        self.key_tab.update_config(con.get_macropad());
+                        self.led_tab.update_config(con.get_macropad());
+                        self.settings_tab.update_config(con.get_macropad());
+                    }
+                }
+            }
+            Message::HidEvent(hid_manager::Event::Verified(id, differences)) => {
+                if let State::Connected(con, _) = &self.state {
+                    if con.device() == &id {
+                        match differences {
+                            Ok(differences) if differences.is_empty() => self
+                                .settings_tab
+                                .verify_log
+                                .push(String::from("Device matches the configurator")),
+                            Ok(differences) => self.settings_tab.verify_log.extend(differences),
+                            Err(_) => self.settings_tab.verify_log.push(String::from(
+                                "Could not read the configuration back from the device",
+                            )),
+                        }
+                    }
+                }
+            }
+            Message::ControlRequest(request) => {
+                self.flush_writes();
+                let result = match &mut self.state {
+                    State::Connected(con, _) if !con.is_offline() => {
+                        let commands: Vec<_> = request
+                            .request
+                            .commands()
+                            .into_iter()
+                            .map(|command| self.config.color_pipeline.to_device_command(command))
+                            .collect();
+                        let macropad = con.get_macropad().lock().unwrap().clone();
+                        let features = macropad.protocol.features();
+
+                        if let Some(command) = commands.iter().find(|command| {
+                            control_api::current_value(command, &macropad).is_none()
+                                || !features.supports(command)
+                        }) {
+                            Err(format!(
+                                "Not supported by the macropad: {}",
+                                hid_manager::Message::Set(command.clone())
+                            ))
+                        } else {
+                            request.request.restore_after().and_then(|restore_after| {
+                                match restore_after {
+                                    Some(after) => self.control_restore.hold(
+                                        &commands,
+                                        &macropad,
+                                        Instant::now() + after,
+                                    ),
+                                    None => self.control_restore.release(&commands),
+                                }
+
+                                for command in commands {
+                                    self.key_tab.show(&command);
+                                    self.led_tab.show(&command);
+                                    if !con.send(hid_manager::Message::Set(command)) {
+                                        self.write_dropped = true;
+                                    }
+                                }
+
+                                if self.write_dropped {
+                                    Err(String::from("The macropad is too busy, try again"))
+                                } else {
+                                    Ok(())
+                                }
+                            })
+                        }
+                    }
+                    _ => Err(String::from("No macropad is connected")),
+                };
+
+                request.respond(result);
+            }
+            Message::ControlApiToggled(enabled) => {
+                self.config.control_api = enabled;
+                self.settings_tab.control_api = enabled;
+                self.config.save().ok();
+            }
+            Message::ScheduleToggled(enabled) => {
+                self.config.brightness_schedule.enabled = enabled;
+                self.save_schedule();
+            }
+            Message::ScheduleWeekendsToggled(off) => {
+                self.config.brightness_schedule.off_on_weekends = off;
+                self.save_schedule();
+            }
+            Message::ScheduleTimeChangedText(text) => {
+                self.settings_tab.schedule_time_text = text;
+            }
+            Message::ScheduleLevelChangedText(text) => {
+                self.settings_tab.schedule_level_text = text;
+            }
+            Message::AddScheduleTransition => {
+                match Transition::parse(
+                    &self.settings_tab.schedule_time_text,
+                    &self.settings_tab.schedule_level_text,
+                ) {
+                    Ok(transition) => {
+                        self.config.brightness_schedule.add(transition);
+                        self.settings_tab.schedule_time_text.clear();
+                        self.settings_tab.schedule_level_text.clear();
+                        self.settings_tab.schedule_error = None;
+                        self.save_schedule();
+                    }
+                    Err(()) => {
+                        self.settings_tab.schedule_error = Some(String::from(
+                            "Enter a time as hh:mm and a brightness from 0 to 255, on or off",
+                        ))
+                    }
+                }
+            }
+            Message::RemoveScheduleTransition(i) => {
+                if i < self.config.brightness_schedule.transitions.len() {
+                    self.config.brightness_schedule.transitions.remove(i);
+                    self.save_schedule();
+                }
+            }
+            Message::GammaChanged(gamma) => {
+                self.config.color_pipeline.gamma = gamma;
+                self.set_color_pipeline();
+            }
+            Message::ColorCorrectionChanged(channel, value) => {
+                let correction = &mut self.config.color_pipeline.correction;
+                match channel {
+                    0 => correction.0 = value,
+                    1 => correction.1 = value,
+                    _ => correction.2 = value,
+                }
+                self.set_color_pipeline();
+            }
+            Message::SaveColorPipeline => {
+                self.config.save().ok();
+            }
+            Message::VerifyWritesToggled(verify) => {
+                self.config.verify_writes = verify;
+                self.settings_tab.verify_writes = verify;
+                self.config.save().ok();
+
+                for connection in self.connections.values_mut() {
+                    if !connection.send(hid_manager::Message::Verify(verify)) {
+                        self.write_dropped = true;
+                    }
+                }
+            }
+            Message::VerifyDevice => {
+                if let State::Connected(con, _) = &mut self.state {
+                    self.settings_tab.verify_log.clear();
+                    if !con.send(hid_manager::Message::VerifyDevice) {
+                        self.write_dropped = true;
+                    }
+                }
+            }
+            Message::ExportLeds(format) => {
+                self.led_tab.export_status = Some(
+                    match led_export::export_to_data_dir(
+                        format,
+                        &self.led_tab.config,
+                        &self.led_tab.key_colors,
+                        &self.led_tab.pipeline,
+                        EXPORT_FRAMES,
+                        EXPORT_SIZE,
+                    ) {
+                        Ok(path) => format!("Exported to {}", path.display()),
+                        Err(_) => String::from("Could not export the preview"),
+                    },
+                );
+            }
+            Message::LedPresetSelected(name) => {
+                self.led_tab.preset_name_text = name.clone();
+                self.led_tab.selected_preset = Some(name);
+                self.led_tab.preset_status = None;
+            }
+            Message::LedPresetNameChangedText(text) => {
+                self.led_tab.preset_name_text = text;
+                self.led_tab.preset_status = None;
+            }
+            Message::SaveLedPreset => {
+                // Taken from the tabs rather than the device so writes which
+                // are still pending are included
+                let name = self.led_tab.preset_name_text.trim().to_owned();
+                let key_colors = if self.key_tab.features.key_colors {
+                    match self.config.saved_key_colors() {
+                        Some(colors) => colors.to_vec(),
+                        None => self
+                            .key_tab
+                            .key_configs
+                            .iter()
+                            .map(|config| config.key_color)
+                            .collect(),
+                    }
+                } else {
+                    Vec::new()
+                };
+
+                self.config.save_led_preset(profile::LedPreset {
+                    name: name.clone(),
+                    led: profile::LedProfile::from_config(&self.led_tab.config),
+                    key_colors,
+                });
+                self.led_tab.preset_status = Some(match self.config.save() {
+                    Ok(_) => String::from("Preset saved"),
+                    Err(_) => String::from("Could not save the preset"),
+                });
+                self.led_tab.presets = self.config.led_preset_names();
+                self.led_tab.selected_preset = Some(name);
+            }
+            Message::ApplyLedPreset => {
+                let preset = self
+                    .led_tab
+                    .selected_preset
+                    .as_ref()
+                    .and_then(|name| self.config.led_preset(name))
+                    .cloned();
+
+                if let Some(preset) = preset {
+                    self.flush_writes();
+                    if let State::Connected(con, _) = &mut self.state {
+                        let commands = preset.commands();
+                        self.control_restore.release(&commands);
+                        if !con.send(hid_manager::Message::Transaction(commands)) {
+                            self.write_dropped = true;
+                        }
+
+                        // An offline macropad takes the preset at once
+                        if con.is_offline() {
+                            self.led_tab.preset_status = Some(String::from("Preset applied"));
+                            self.key_tab.update_config(con.get_macropad());
+                            self.led_tab.update_config(con.get_macropad());
+                        } else {
+                            self.led_tab.preset_status = Some(String::from("Applying..."));
+                            self.led_tab.applying_preset = true;
+                        }
+                    }
+                }
+            }
+            Message::DeleteLedPreset => {
+                if let Some(name) = self.led_tab.selected_preset.take() {
+                    self.config.remove_led_preset(&name);
+                    self.config.save().ok();
+                    self.led_tab.presets = self.config.led_preset_names();
+                    self.led_tab.preset_status = None;
+                }
+            }
+            Message::OfflineProfileSelected(name) => {
+                self.offline_selected = Some(name);
+            }
+            Message::EditOffline => {
+                let path = self.offline_selected.as_ref().and_then(|name| {
+                    self.offline_profiles
+                        .iter()
+                        .find(|(n, _)| n == name)
+                        .map(|(_, path)| path.clone())
+                });
+
+                // Without a profile editing starts from the defaults
+                let macropad = match path.map(|path| profile::Profile::load(&path)) {
+                    Some(Ok(profile)) => Some(profile.to_macropad()),
+                    Some(Err(_)) => None,
+                    None => Some(macro_parser::Macropad::offline()),
+                };
+
+                match macropad {
+                    Some(macropad) => {
+                        if let State::Disconnected(Some(con)) = &mut self.state {
+                            con.send(macropad_updater::Message::Close);
+                        }
+
+                        self.offline_error = None;
+                        self.select_device(Connection::offline(macropad));
+                        self.settings_tab.profile_name_text =
+                            self.offline_selected.clone().unwrap_or_default();
+                    }
+                    None => {
+                        self.offline_error = Some(String::from("Could not load the profile"));
+                    }
+                }
+            }
+            Message::CloseOffline => {
+                // Unsaved edits go away with the offline macropad
+                self.scheduler.flush();
+                self.offline_profiles = profile::list_profiles();
+
+                match self.pending_device.take() {
+                    Some(connection) => self.select_device(connection),
+                    None => self.state = State::Disconnected(None),
+                }
+            }
+            Message::PushOfflineEdits => {
+                self.flush_writes();
+
+                if let Some(mut connection) = self.pending_device.take() {
+                    if let State::Connected(con, _) = &self.state {
+                        let edited = profile::Profile::from_macropad(
+                            "",
+                            &con.get_macropad().lock().unwrap(),
+                        );
+                        if !connection.send(hid_manager::Message::Transaction(edited.commands())) {
+                            self.write_dropped = true;
+                        }
+                    }
+
+                    self.select_device(connection);
+                    self.settings_tab.load_status = Some(String::from("Applying..."));
+                }
+            }
+            Message::DiscardOfflineEdits => {
+                if let Some(connection) = self.pending_device.take() {
+                    self.select_device(connection);
+                }
+            }
+            Message::DeviceSelected(id) => {
+                if let Some(connection) = self.connections.get(&id).cloned() {
+                    self.select_device(connection);
+                }
+            }
+            Message::DeviceChanged(change) => {
+                self.device_attached = if change.attached {
+                    Some(change.kind)
+                } else {
+                    None
+                };
             }
             Message::UpdaterEvent(macropad_updater::Event::Connected(connection)) => {
+                self.device_attached = None;
                 if let State::Disconnected(_) = self.state {
                     self.state = State::Disconnected(Some(connection));
                 }
             }
             Message::UpdaterEvent(macropad_updater::Event::Disconnected) => {
+                self.uploading = false;
                 if let State::Disconnected(_) = self.state {
                     self.state = State::Disconnected(None);
                 }
             }
+            Message::UpdaterEvent(macropad_updater::Event::UploadFailed(error)) => {
+                self.uploading = false;
+                self.upload_error = Some(error);
+            }
+            Message::HidEvent(hid_manager::Event::MacroLoaded(id, _)) => {
+                if let State::Connected(con, _) = &self.state {
+                    if con.device() == &id {
+                        self.key_tab.update_config(con.get_macropad());
+                        self.settings_tab.update_config(con.get_macropad());
+                        self.settings_tab.timings = Some(con.timings());
+                    }
+                }
+            }
             Message::HidEvent(_) => {}
-            Message::CommandSent(_, _) => {}
-            Message::CommandReceived(_, _) => {}
-            Message::CommandErrored => {}
+            Message::ProtocolExchange(exchange) => {
+                if self.protocol_tab.exchanges.len() == PROTOCOL_CONSOLE_SIZE {
+                    self.protocol_tab.exchanges.pop_front();
+                }
+                self.protocol_tab.exchanges.push_back(exchange);
+            }
+            Message::ProtocolFilterSelected(filter) => {
+                self.protocol_tab.filter = if filter == "All" { None } else { Some(filter) };
+            }
+            Message::ClearProtocolLog => {
+                protocol_log::clear();
+                self.protocol_tab.exchanges.clear();
+                self.protocol_tab.status = None;
+            }
+            Message::StartCapture => {
+                protocol_log::start_capture();
+                self.protocol_tab.capturing = true;
+                self.protocol_tab.status = Some(String::from("Capturing..."));
+            }
+            Message::FinishCapture => {
+                self.protocol_tab.capturing = false;
+                self.protocol_tab.status = Some(match protocol_log::finish_capture() {
+                    Ok(path) => format!("Capture saved to {}", path.display()),
+                    Err(_) => String::from("Could not save the capture"),
+                });
+            }
+            Message::ExportTrace => {
+                self.protocol_tab.status = Some(match protocol_log::export_trace() {
+                    Ok(path) => format!("Exported to {}", path.display()),
+                    Err(_) => String::from("Could not export the trace"),
+                });
+            }
             Message::MacropadBootloader => {
                 match &mut self.state {
                     State::Connected(connection, _) => {
-                        connection.send(hid_manager::Message::Set(
+                        if !connection.send(hid_manager::Message::Set(
                             hid_manager::MacropadCommand::Bootloader,
-                        ));
+                        )) {
+                            self.write_dropped = true;
+                        }
                     }
                     _ => unreachable!(),
                 };
@@ -239,12 +832,15 @@ impl Application for Configurator {
             Message::UploadLatestFirmware => {
                 match &mut self.state {
                     State::Disconnected(Some(connection)) => {
+                        self.uploading = true;
+                        self.upload_error = None;
                         connection.send(macropad_updater::Message::UploadToDevice(None));
                     }
                     _ => unreachable!(),
                 };
             }
             Message::ButtonPressed(i) => {
+                self.flush_writes();
                 self.state = State::Connected(
                     match &self.state {
                         State::Connected(connection, _) => {
@@ -265,6 +861,7 @@ impl Application for Configurator {
                 self.key_tab.clicked = state;
             }
             Message::ReturnToMainPage => {
+                self.flush_writes();
                 self.state = State::Connected(
                     match &mut self.state {
                         State::Connected(connection, _) => connection.clone(),
@@ -275,24 +872,61 @@ impl Application for Configurator {
                 self.key_tab.selected_key = None;
                 self.key_tab.show_picker = false;
                 self.key_tab.clicked = false;
+                self.key_tab.playback = None;
             }
-            Message::LedUpdate(_) => {
-                if let State::Connected(con, Page::MainPage(id)) = &mut self.state {
+            Message::LedUpdate(now) => match &self.state {
+                State::Connected(_, Page::MainPage(id)) => {
                     if TabId::try_from(*id).unwrap() == TabId::ModifyLed {
-                        self.led_tab
-                            .led_runner
-                            .update(&con.get_macropad().lock().unwrap().led_config);
+                        self.led_tab.led_runner.update(now);
                     }
                 }
-            }
+                State::Connected(_, Page::ModifyKey(_)) => {
+                    self.key_tab.led_runner.update(now);
+                    if let Some((_, runner)) = &mut self.key_tab.playback {
+                        runner.update(now);
+                    }
+                }
+                _ => {}
+            },
             Message::UpdateTick(_) => {
                 if let State::Connected(con, _) = &mut self.state {
-                    self.key_tab.run_actions(con);
-                    self.led_tab.run_actions(con);
-                    self.settings_tab.run_actions(con);
+                    for command in self.scheduler.due(Instant::now()) {
+                        if !con.send(hid_manager::Message::Set(command)) {
+                            self.write_dropped = true;
+                        }
+                    }
+
+                    for command in self.control_restore.due(Instant::now()) {
+                        self.key_tab.show(&command);
+                        self.led_tab.show(&command);
+                        if !con.send(hid_manager::Message::Set(command)) {
+                            self.write_dropped = true;
+                        }
+                    }
+
+                    if !con.is_offline() {
+                        let effect_before_off = self.config.brightness_schedule.effect_before_off;
+                        for command in self.schedule_runner.update(
+                            &mut self.config.brightness_schedule,
+                            chrono::Local::now().naive_local(),
+                            &self.led_tab.config,
+                        ) {
+                            let command = self.config.color_pipeline.to_device_command(command);
+                            self.led_tab.show(&command);
+                            if !con.send(hid_manager::Message::Set(command)) {
+                                self.write_dropped = true;
+                            }
+                        }
+
+                        if self.config.brightness_schedule.effect_before_off != effect_before_off {
+                            self.config.save().ok();
+                        }
+                    }
+                    self.settings_tab.schedule_overridden = self.schedule_runner.is_overridden();
                 }
             }
             Message::TabSelected(i) => {
+                self.flush_writes();
                 self.state = State::Connected(
                     match &mut self.state {
                         State::Connected(connection, _) => {
@@ -301,12 +935,13 @@ impl Application for Configurator {
                                     self.key_tab.update_config(connection.get_macropad());
                                 }
                                 TabId::ModifyLed => {
-                                    self.led_tab.led_runner.reset();
+                                    self.led_tab.led_runner.reset(Instant::now());
                                     self.led_tab.update_config(connection.get_macropad());
                                 }
                                 TabId::ModyifySettings => {
                                     self.settings_tab.update_config(connection.get_macropad());
                                 }
+                                TabId::Protocol => {}
                             }
                             connection.clone()
                         }
@@ -317,11 +952,15 @@ impl Application for Configurator {
             }
             Message::KeyModeChanged(mode) => {
                 if let State::Connected(_, Page::ModifyKey(i)) = &mut self.state {
-                    self.key_tab
-                        .queue_action(hid_manager::MacropadCommand::KeyMode(*i as u8, mode));
+                    self.key_tab.queue_action(
+                        &mut self.scheduler,
+                        &mut self.control_restore,
+                        hid_manager::MacropadCommand::KeyMode(*i as u8, mode),
+                    );
                 }
             }
             Message::LoadMacro(macro_type) => {
+                self.flush_writes();
                 if let State::Connected(con, Page::ModifyKey(i)) = &mut self.state {
                     let macros = con.get_macropad().lock().unwrap().macros[*i as usize].clone();
                     self.key_tab.editor_actions =
@@ -340,32 +979,49 @@ impl Application for Configurator {
                         State::Connected(con.clone(), Page::EditMacro(*i, macro_type.clone()));
                 }
             }
+            Message::PlayMacro(macro_type) => {
+                if let State::Connected(con, Page::ModifyKey(i)) = &self.state {
+                    let macros = con.get_macropad().lock().unwrap().macros[*i].clone();
+                    let timeline = MacroTimeline::new(&match macro_type {
+                        macro_parser::MacroType::Tap => macros.tap,
+                        macro_parser::MacroType::Hold => macros.hold,
+                        macro_parser::MacroType::DoubleTap => macros.double_tap,
+                        macro_parser::MacroType::TapHold => macros.tap_hold,
+                    });
+
+                    let mut runner = LedRunner::default();
+                    runner.reset(Instant::now());
+                    self.key_tab.playback = Some((timeline, runner));
+                }
+            }
             Message::SaveMacro => {
                 if let State::Connected(con, Page::EditMacro(i, macro_type)) = &mut self.state {
-                    con.send(hid_manager::Message::Set(
+                    if !con.send(hid_manager::Message::Set(
                         hid_manager::MacropadCommand::Macro(
                             ((*i as u8) << 2) + (macro_type.clone() as u8),
                             macro_editor::Action::to_macro(self.key_tab.editor_actions.as_slice()),
                         ),
-                    ));
+                    )) {
+                        self.write_dropped = true;
+                    }
                 }
             }
             Message::KeyboardDataChanged(data) => {
                 if let State::Connected(_, Page::ModifyKey(i)) = &mut self.state {
-                    self.key_tab
-                        .queue_action(hid_manager::MacropadCommand::KeyboardData(
-                            *i as u8,
-                            data.into(),
-                        ));
+                    self.key_tab.queue_action(
+                        &mut self.scheduler,
+                        &mut self.control_restore,
+                        hid_manager::MacropadCommand::KeyboardData(*i as u8, data.into()),
+                    );
                 }
             }
             Message::ConsumerDataChanged(data) => {
                 if let State::Connected(_, Page::ModifyKey(i)) = &mut self.state {
-                    self.key_tab
-                        .queue_action(hid_manager::MacropadCommand::ConsumerData(
-                            *i as u8,
-                            data.into(),
-                        ));
+                    self.key_tab.queue_action(
+                        &mut self.scheduler,
+                        &mut self.control_restore,
+                        hid_manager::MacropadCommand::ConsumerData(*i as u8, data.into()),
+                    );
                 }
             }
             Message::KeyPickColor => {
@@ -379,47 +1035,71 @@ impl Application for Configurator {
             Message::KeySubmitColor(color) => {
                 if let State::Connected(_, Page::ModifyKey(i)) = &mut self.state {
                     let c = color.into_rgba8();
-                    self.key_tab
-                        .queue_action(hid_manager::MacropadCommand::KeyColor(
+                    self.key_tab.queue_action(
+                        &mut self.scheduler,
+                        &mut self.control_restore,
+                        hid_manager::MacropadCommand::KeyColor(
                             *i as u8,
-                            (c[0], c[1], c[2]),
-                        ));
+                            self.key_tab.pipeline.to_device((c[0], c[1], c[2])),
+                        ),
+                    );
+                    self.use_color((c[0], c[1], c[2]));
                 }
                 self.key_tab.show_picker = false;
             }
             Message::LedEffectChanged(effect) => {
-                self.led_tab
-                    .queue_action(hid_manager::MacropadCommand::LedEffect(effect));
+                self.schedule_runner.override_level();
+                self.led_tab.queue_action(
+                    &mut self.scheduler,
+                    &mut self.control_restore,
+                    hid_manager::MacropadCommand::LedEffect(effect),
+                );
             }
             Message::LedPeriodChanged(period) => {
                 self.led_tab.period_text = (period / 10.0).to_string();
-                self.led_tab
-                    .queue_action(hid_manager::MacropadCommand::LedEffectPeriod(period / 10.0));
+                self.led_tab.queue_action(
+                    &mut self.scheduler,
+                    &mut self.control_restore,
+                    hid_manager::MacropadCommand::LedEffectPeriod(period / 10.0),
+                );
             }
             Message::LedPeriodChangedText(text) => {
                 if let Ok(period) = text.parse::<f32>() {
                     if (-5.0..=5.0).contains(&period) {
                         self.led_tab.period_text = text.clone();
-                        self.led_tab
-                            .queue_action(hid_manager::MacropadCommand::LedEffectPeriod(period));
+                        self.led_tab.queue_action(
+                            &mut self.scheduler,
+                            &mut self.control_restore,
+                            hid_manager::MacropadCommand::LedEffectPeriod(period),
+                        );
                     }
                 } else if text == "" {
                     self.led_tab.period_text = text;
                 }
             }
             Message::LedBrightnessChanged(brightness) => {
+                self.schedule_runner.override_level();
                 self.led_tab.brightness_text = brightness.to_string();
-                self.led_tab
-                    .queue_action(hid_manager::MacropadCommand::LedBrightness(
-                        brightness as u8,
-                    ));
+                self.led_tab.queue_action(
+                    &mut self.scheduler,
+                    &mut self.control_restore,
+                    hid_manager::MacropadCommand::LedBrightness(
+                        self.led_tab.pipeline.brightness_to_device(brightness as u8),
+                    ),
+                );
             }
             Message::LedBrightnessChangedText(text) => {
                 if let Ok(brightness) = text.parse::<u8>() {
                     if (0..=255).contains(&brightness) {
+                        self.schedule_runner.override_level();
                         self.led_tab.brightness_text = text;
-                        self.led_tab
-                            .queue_action(hid_manager::MacropadCommand::LedBrightness(brightness));
+                        self.led_tab.queue_action(
+                            &mut self.scheduler,
+                            &mut self.control_restore,
+                            hid_manager::MacropadCommand::LedBrightness(
+                                self.led_tab.pipeline.brightness_to_device(brightness),
+                            ),
+                        );
                     }
                 } else if text == "" {
                     self.led_tab.brightness_text = text;
@@ -433,17 +1113,24 @@ impl Application for Configurator {
             }
             Message::LedSubmitColor(color) => {
                 let c = color.into_rgba8();
-                self.led_tab
-                    .queue_action(hid_manager::MacropadCommand::LedBaseColor((
-                        c[0], c[1], c[2],
-                    )));
+                self.led_tab.queue_action(
+                    &mut self.scheduler,
+                    &mut self.control_restore,
+                    hid_manager::MacropadCommand::LedBaseColor(
+                        self.led_tab.pipeline.to_device((c[0], c[1], c[2])),
+                    ),
+                );
+                self.use_color((c[0], c[1], c[2]));
                 self.led_tab.show_picker = false;
             }
             Message::PressTimeChangedText(text) => {
                 if let Ok(speed) = text.parse::<u32>() {
                     self.settings_tab.press_time_text = text;
-                    self.settings_tab
-                        .queue_action(hid_manager::MacropadCommand::TapSpeed(speed * 1000));
+                    self.settings_tab.queue_action(
+                        &mut self.scheduler,
+                        &mut self.control_restore,
+                        hid_manager::MacropadCommand::TapSpeed(speed * 1000),
+                    );
                 } else if text == "" {
                     self.settings_tab.press_time_text = text;
                 }
@@ -451,8 +1138,11 @@ impl Application for Configurator {
             Message::HoldTimeChangedText(text) => {
                 if let Ok(speed) = text.parse::<u32>() {
                     self.settings_tab.hold_time_text = text;
-                    self.settings_tab
-                        .queue_action(hid_manager::MacropadCommand::HoldSpeed(speed * 1000));
+                    self.settings_tab.queue_action(
+                        &mut self.scheduler,
+                        &mut self.control_restore,
+                        hid_manager::MacropadCommand::HoldSpeed(speed * 1000),
+                    );
                 } else if text == "" {
                     self.settings_tab.hold_time_text = text;
                 }
@@ -498,8 +1188,9 @@ impl Application for Configurator {
                 let c = color.into_rgba8();
                 if let Some(action) = self.key_tab.selected_action.as_mut() {
                     match &mut action.action_options {
+                        // Macros store the value written, like key colors
                         macro_editor::ActionOptions::SetLed(color) => {
-                            *color = (c[0], c[1], c[2]);
+                            *color = self.key_tab.pipeline.to_device((c[0], c[1], c[2]));
                         }
 
                         _ => unreachable!(),
@@ -507,9 +1198,92 @@ impl Application for Configurator {
 
                     action.update_action(&self.key_tab.editor_actions.as_slice());
                     self.key_tab.editor.request_redraw();
+                    self.use_color((c[0], c[1], c[2]));
                 }
                 self.key_tab.action_option_controls.show_color_picker = false;
             }
+            Message::ColorTextChanged(target, text) => {
+                *self.color_text(target) = text;
+            }
+            Message::ColorTextSubmitted(target) => {
+                if let Ok(color) = palette::parse_color(self.color_text(target)) {
+                    self.color_text(target).clear();
+                    return self.update(target.submit(color));
+                }
+            }
+            Message::PaletteColorSelected(target, color) => {
+                return self.update(target.submit(color));
+            }
+            Message::SaveSwatch(color) => {
+                self.config.palette.save_swatch(color);
+                self.save_palette();
+            }
+            Message::RemoveSwatch(color) => {
+                self.config.palette.remove_swatch(color);
+                self.save_palette();
+            }
+            Message::ApplyHarmony(harmony) => {
+                if self.key_tab.features.key_colors {
+                    let pipeline = self.led_tab.pipeline;
+                    let base = pipeline.to_screen(self.led_tab.config.base_color);
+                    let keys = self.key_tab.key_configs.len();
+
+                    for (i, color) in palette::harmony(base, harmony)
+                        .into_iter()
+                        .take(keys)
+                        .enumerate()
+                    {
+                        let command = hid_manager::MacropadCommand::KeyColor(
+                            i as u8,
+                            pipeline.to_device(color),
+                        );
+                        self.led_tab.show(&command);
+                        self.key_tab.queue_action(
+                            &mut self.scheduler,
+                            &mut self.control_restore,
+                            command,
+                        );
+                    }
+                }
+            }
+            Message::ReactiveModeSelected(mode) => {
+                self.led_tab.reactive_mode = mode;
+                let path = self.led_tab.reactive_path_text.trim();
+                let source = match mode {
+                    ReactiveMode::Off => None,
+                    ReactiveMode::CpuLoad => Some(ReactiveSource::CpuLoad),
+                    ReactiveMode::Image if path.is_empty() => None,
+                    ReactiveMode::Image => Some(ReactiveSource::Image { path: path.into() }),
+                };
+                self.set_reactive_source(source);
+            }
+            Message::ReactiveImagePathChangedText(text) => {
+                self.led_tab.reactive_path_text = text;
+            }
+            Message::ReactiveImagePathSubmitted => {
+                let path = self.led_tab.reactive_path_text.trim();
+                if !path.is_empty() {
+                    let source = ReactiveSource::Image { path: path.into() };
+                    self.set_reactive_source(Some(source));
+                }
+            }
+            Message::ReactiveColors(Ok(colors)) => {
+                self.led_tab.reactive_status = None;
+                if let State::Connected(con, _) = &mut self.state {
+                    if !con.is_offline() && self.key_tab.features.key_colors {
+                        for command in self.reactive_limiter.update(colors, Instant::now()) {
+                            let command = self.config.color_pipeline.to_device_command(command);
+                            self.led_tab.show(&command);
+                            if !con.send(hid_manager::Message::Set(command)) {
+                                self.write_dropped = true;
+                            }
+                        }
+                    }
+                }
+            }
+            Message::ReactiveColors(Err(error)) => {
+                self.led_tab.reactive_status = Some(error);
+            }
             Message::MacroActionChooseKey(keyboard) => {
                 if let Some(action) = self.key_tab.selected_action.as_mut() {
                     match &mut action.action_options {
@@ -705,16 +1479,169 @@ impl Application for Configurator {
                     self.key_tab.action_option_controls.loop_count_text = count;
                 }
             }
+            Message::ProfileNameChangedText(text) => {
+                self.settings_tab.profile_name_text = text;
+                self.settings_tab.profile_saved = None;
+            }
+            Message::SaveProfile => {
+                if let State::Connected(con, _) = &self.state {
+                    let name = self.settings_tab.profile_name_text.trim().to_owned();
+                    if !profile::is_valid_name(&name) {
+                        self.settings_tab.profile_saved = Some(false);
+                    } else if let Some(dir) = profile::profiles_dir() {
+                        let mut profile = profile::Profile::from_macropad(
+                            &name,
+                            &con.get_macropad().lock().unwrap(),
+                        );
+                        if let Some(colors) = self.config.saved_key_colors() {
+                            profile.set_key_colors(colors);
+                        }
+                        self.settings_tab.profile_saved =
+                            Some(profile.save(&dir.join(format!("{}.json", name))).is_ok());
+                        self.settings_tab.profiles = profile::list_profiles();
+                    }
+                }
+            }
+            Message::LoadProfileSelected(name) => {
+                self.settings_tab.selected_profile = Some(name);
+                self.settings_tab.load_status = None;
+            }
+            Message::LoadProfile => {
+                if let State::Connected(con, _) = &mut self.state {
+                    let path = self
+                        .settings_tab
+                        .selected_profile
+                        .as_ref()
+                        .and_then(|name| {
+                            self.settings_tab
+                                .profiles
+                                .iter()
+                                .find(|(n, _)| n == name)
+                                .map(|(_, path)| path.clone())
+                        });
+
+                    match path.map(|path| profile::Profile::load(&path)) {
+                        Some(Ok(profile)) => {
+                            self.settings_tab.load_status = Some(String::from("Applying..."));
+                            let commands = profile.commands();
+                            self.control_restore.release(&commands);
+                            if !con.send(hid_manager::Message::Transaction(commands)) {
+                                self.write_dropped = true;
+                            }
+
+                            // An offline macropad takes the whole profile at once
+                            if con.is_offline() {
+                                self.settings_tab.load_status =
+                                    Some(String::from("Profile applied"));
+                                self.key_tab.update_config(con.get_macropad());
+                                self.led_tab.update_config(con.get_macropad());
+                                self.settings_tab.update_config(con.get_macropad());
+                            }
+                        }
+                        _ => {
+                            self.settings_tab.load_status =
+                                Some(String::from("Could not load the profile"))
+                        }
+                    }
+                }
+            }
+            Message::OpenProvisioning => {
+                self.devices.clear();
+                self.connections.clear();
+                self.state = State::Provisioning(ProvisioningPage {
+                    profiles: profile::list_profiles(),
+                    ..ProvisioningPage::default()
+                });
+            }
+            Message::CloseProvisioning => {
+                self.devices.clear();
+                self.connections.clear();
+                self.state = State::Disconnected(None);
+            }
+            Message::ProvisionProfileSelected(name) => {
+                if let State::Provisioning(page) = &mut self.state {
+                    page.selected = Some(name);
+                }
+            }
+            Message::ProvisionFlashToggled(flash) => {
+                if let State::Provisioning(page) = &mut self.state {
+                    page.flash = flash;
+                }
+            }
+            Message::StartProvisioning => {
+                if let State::Provisioning(page) = &mut self.state {
+                    let path = page
+                        .profiles
+                        .iter()
+                        .find(|(name, _)| Some(name) == page.selected.as_ref())
+                        .map(|(_, path)| path.clone());
+
+                    match path.map(|path| profile::Profile::load(&path)) {
+                        Some(Ok(profile)) => {
+                            self.provision_runs += 1;
+                            page.error = None;
+                            page.running = Some(self.provision_runs);
+                            page.job = Some(provisioning::Job {
+                                profile,
+                                firmware: if page.flash {
+                                    Some(self.config.release_source.clone())
+                                } else {
+                                    None
+                                },
+                                verify_checksum: self.config.verify_firmware_checksum,
+                            });
+                        }
+                        _ => page.error = Some(String::from("Could not load the profile")),
+                    }
+                }
+            }
+            Message::StopProvisioning => {
+                if let State::Provisioning(page) = &mut self.state {
+                    page.running = None;
+                }
+            }
+            Message::ProvisionEvent(event) => {
+                if let State::Provisioning(page) = &mut self.state {
+                    match event {
+                        provisioning::Event::Started => {}
+                        provisioning::Event::FirmwareUnavailable => {
+                            page.running = None;
+                            page.error = Some(String::from("Could not download the firmware"));
+                        }
+                        provisioning::Event::Record(record) => page.records.push(record),
+                    }
+                }
+            }
         };
 
+        if std::mem::take(&mut self.write_dropped) {
+            self.reload_after_dropped_write();
+        }
+
         Command::none()
     }
 
     fn subscription(&self) -> Subscription<Message> {
+        if let State::Provisioning(page) = &self.state {
+            return match (page.running, &page.job) {
+                (Some(run), Some(job)) => {
+                    provisioning::provision(run, job.clone()).map(Message::ProvisionEvent)
+                }
+                _ => Subscription::none(),
+            };
+        }
+
         Subscription::batch([
-            hid_manager::connect().map(Message::HidEvent),
+            hid_manager::devices().map(Message::HidEvent),
+            device_watcher::changes().map(Message::DeviceChanged),
+            protocol_log::exchanges().map(Message::ProtocolExchange),
+            Subscription::batch(
+                self.devices
+                    .iter()
+                    .map(|id| hid_manager::connect(id.clone()).map(Message::HidEvent)),
+            ),
             match &self.state {
-                State::Connected(_, Page::MainPage(_)) => {
+                State::Connected(_, Page::MainPage(_) | Page::ModifyKey(_)) => {
                     iced::time::every(Duration::from_millis(16)).map(Message::LedUpdate)
                 }
                 _ => Subscription::none(),
@@ -725,8 +1652,23 @@ impl Application for Configurator {
                 }
                 _ => Subscription::none(),
             },
+            if self.config.control_api {
+                control_api::requests().map(Message::ControlRequest)
+            } else {
+                Subscription::none()
+            },
+            match (&self.state, &self.config.reactive_lighting) {
+                (State::Connected(con, _), Some(source)) if !con.is_offline() => {
+                    reactive_lighting::colors(source.clone()).map(Message::ReactiveColors)
+                }
+                _ => Subscription::none(),
+            },
             match &self.state {
-                State::Disconnected(_) => macropad_updater::connect().map(Message::UpdaterEvent),
+                State::Disconnected(_) => macropad_updater::connect(
+                    self.config.release_source.clone(),
+                    self.config.verify_firmware_checksum,
+                )
+                .map(Message::UpdaterEvent),
                 _ => Subscription::none(),
             },
         ])
@@ -734,15 +1676,39 @@ impl Application for Configurator {
 
     fn view(&self) -> Element<Message> {
         match &self.state {
+            State::Provisioning(page) => page.view(),
             State::Disconnected(con) => {
                 // TODO: Add ability to flash firmware
                 let flash_button = container(if let Some(_) = con {
-                    column![button("Flash Keyboard").on_press(Message::UploadLatestFirmware),]
+                    column![
+                        if self.uploading {
+                            button("Flashing...")
+                        } else {
+                            button("Flash Keyboard").on_press(Message::UploadLatestFirmware)
+                        },
+                        text(match &self.upload_error {
+                            Some(error) => error.clone(),
+                            None => format!("Firmware from {}", self.config.release_source),
+                        })
+                        .size(16),
+                        button("Provisioning Mode").on_press(Message::OpenProvisioning),
+                    ]
                 } else {
-                    column![text("No device found")
+                    column![
+                        text(match self.device_attached {
+                            Some(device_watcher::DeviceKind::Hid) => {
+                                "Macropad plugged in, connecting..."
+                            }
+                            Some(device_watcher::DeviceKind::Drive) => {
+                                "Drive plugged in, looking for the bootloader..."
+                            }
+                            None => "No device found",
+                        })
                         .size(16)
                         .horizontal_alignment(alignment::Horizontal::Center)
-                        .vertical_alignment(alignment::Vertical::Bottom),]
+                        .vertical_alignment(alignment::Vertical::Bottom),
+                        button("Provisioning Mode").on_press(Message::OpenProvisioning),
+                    ]
                 })
                 .width(Length::Fill)
                 .height(Length::Shrink)
@@ -750,16 +1716,35 @@ impl Application for Configurator {
                 .align_y(alignment::Vertical::Bottom);
 
                 let message = column![
-                    container(column![
-                        text("Disconnected")
-                            .size(60)
-                            .width(Length::Fill)
-                            .horizontal_alignment(iced::alignment::Horizontal::Center),
-                        text("Connect your macropad to get started")
-                            .size(30)
-                            .width(Length::Fill)
-                            .horizontal_alignment(iced::alignment::Horizontal::Center),
-                    ])
+                    container(
+                        column![
+                            text("Disconnected")
+                                .size(60)
+                                .width(Length::Fill)
+                                .horizontal_alignment(iced::alignment::Horizontal::Center),
+                            text("Connect your macropad to get started")
+                                .size(30)
+                                .width(Length::Fill)
+                                .horizontal_alignment(iced::alignment::Horizontal::Center),
+                            Space::with_height(Length::Fixed(20.0)),
+                            row![
+                                pick_list(
+                                    self.offline_profiles
+                                        .iter()
+                                        .map(|(name, _)| name.clone())
+                                        .collect::<Vec<_>>(),
+                                    self.offline_selected.clone(),
+                                    Message::OfflineProfileSelected,
+                                )
+                                .placeholder("New profile"),
+                                Space::with_width(Length::Fixed(10.0)),
+                                button("Edit Offline").on_press(Message::EditOffline),
+                            ]
+                            .align_items(alignment::Alignment::Center),
+                            text(self.offline_error.clone().unwrap_or_default()).size(16),
+                        ]
+                        .align_items(alignment::Alignment::Center)
+                    )
                     .width(Length::Fill)
                     .height(Length::Fill)
                     .center_x()
@@ -775,73 +1760,119 @@ impl Application for Configurator {
                     .padding(20)
                     .into()
             }
-            State::Connected(_, Page::MainPage(i)) => Tabs::new(*i, Message::TabSelected)
-                .push(self.key_tab.tab_label(), self.key_tab.view())
-                .push(self.led_tab.tab_label(), self.led_tab.view())
-                .push(self.settings_tab.tab_label(), self.settings_tab.view())
-                .tab_bar_style(TabBarStyles::Purple)
-                .icon_font(ICON_FONT)
-                .tab_bar_position(iced_aw::TabBarPosition::Bottom)
-                .text_size(20.0)
-                .into(),
+            State::Connected(con, Page::MainPage(i)) => {
+                let tabs = Tabs::new(*i, Message::TabSelected)
+                    .push(self.key_tab.tab_label(), self.key_tab.view())
+                    .push(self.led_tab.tab_label(), self.led_tab.view())
+                    .push(self.settings_tab.tab_label(), self.settings_tab.view())
+                    .push(self.protocol_tab.tab_label(), self.protocol_tab.view())
+                    .tab_bar_style(TabBarStyles::Purple)
+                    .icon_font(ICON_FONT)
+                    .tab_bar_position(iced_aw::TabBarPosition::Bottom)
+                    .text_size(20.0);
+
+                let mut page = Column::new();
+
+                if self.connections.len() > 1 {
+                    let mut devices = self.connections.keys().cloned().collect::<Vec<_>>();
+                    devices.sort_by_key(|id| id.to_string());
+
+                    page = page.push(
+                        container(pick_list(
+                            devices,
+                            Some(con.device().clone()),
+                            Message::DeviceSelected,
+                        ))
+                        .width(Length::Fill)
+                        .align_x(alignment::Horizontal::Center)
+                        .padding(10),
+                    );
+                }
+
+                if con.is_offline() {
+                    let banner =
+                        match &self.pending_device {
+                            Some(device) => row![
+                                text(format!(
+                                    "{} connected, push the offline edits to it?",
+                                    device.device()
+                                ))
+                                .size(16),
+                                Space::with_width(Length::Fixed(10.0)),
+                                button("Push").on_press(Message::PushOfflineEdits),
+                                Space::with_width(Length::Fixed(10.0)),
+                                button("Discard").on_press(Message::DiscardOfflineEdits),
+                            ],
+                            None => row![
+                            text("Editing offline, save the profile in the settings tab to keep it")
+                                .size(16),
+                            Space::with_width(Length::Fixed(10.0)),
+                            button("Close").on_press(Message::CloseOffline),
+                        ],
+                        };
+
+                    page = page.push(
+                        container(banner.align_items(alignment::Alignment::Center))
+                            .width(Length::Fill)
+                            .align_x(alignment::Horizontal::Center)
+                            .padding(10),
+                    );
+                }
+
+                if let Some(warning) = &con.get_macropad().lock().unwrap().protocol.warning {
+                    page = page.push(
+                        container(text(warning).size(16))
+                            .width(Length::Fill)
+                            .align_x(alignment::Horizontal::Center)
+                            .padding(10),
+                    );
+                }
+
+                page = page.push(tabs);
+
+                let pending = con.pending();
+                let rejected = con.rejected();
+                let mut status = Vec::new();
+                if pending > 0 {
+                    status.push(format!("{} changes pending", pending));
+                }
+                if rejected > 0 {
+                    status.push(format!(
+                        "{} changes dropped while the macropad was busy",
+                        rejected
+                    ));
+                }
+                if let Some(error) = &self.command_error {
+                    status.push(error.clone());
+                }
+
+                if !status.is_empty() {
+                    page = page.push(
+                        container(text(status.join(" | ")).size(16))
+                            .width(Length::Fill)
+                            .padding(5),
+                    );
+                }
+
+                page.into()
+            }
             State::Connected(_, Page::ModifyKey(i)) => {
                 let key_settings = match self.key_tab.key_configs[*i].key_mode {
                     macropad_protocol::data_protocol::KeyMode::MacroMode => {
-                        column![container(column![
-                            text("Key Mode").size(30),
-                            row![
-                                button("Tap Macro")
-                                    .on_press(Message::LoadMacro(macro_parser::MacroType::Tap)),
-                                Space::with_width(Length::Fixed(20.0)),
-                                button("Hold Macro")
-                                    .on_press(Message::LoadMacro(macro_parser::MacroType::Hold)),
-                                Space::with_width(Length::Fixed(20.0)),
-                                button("Double Tap Macro").on_press(Message::LoadMacro(
-                                    macro_parser::MacroType::DoubleTap
-                                )),
-                                Space::with_width(Length::Fixed(20.0)),
-                                button("Tap and Hold Macro")
-                                    .on_press(Message::LoadMacro(macro_parser::MacroType::TapHold)),
-                            ],
-                        ])
-                        .padding(Padding {
-                            top: 20.0,
-                            right: 0.0,
-                            bottom: 20.0,
-                            left: 0.0,
-                        }),]
-                    }
-                    macropad_protocol::data_protocol::KeyMode::SingleTapMode => {
-                        column![container(column![
-                            text("Key Mode").size(30),
-                            row![
-                                button("Tap Macro")
-                                    .on_press(Message::LoadMacro(macro_parser::MacroType::Tap)),
-                                Space::with_width(Length::Fixed(20.0)),
-                                button("Hold Macro")
-                                    .on_press(Message::LoadMacro(macro_parser::MacroType::Hold)),
-                                Space::with_width(Length::Fixed(20.0)),
-                                button("Double Tap Macro"),
-                                Space::with_width(Length::Fixed(20.0)),
-                                button("Tap and Hold Macro"),
-                            ],
-                        ])
-                        .padding(Padding {
-                            top: 20.0,
-                            right: 0.0,
-                            bottom: 20.0,
-                            left: 0.0,
-                        }),]
-                    }
-                    macropad_protocol::data_protocol::KeyMode::KeyboardMode => {
                         column![
                             container(column![
-                                text("Key").size(30),
-                                pick_list(
-                                    &type_wrapper::KeyboardWrapper::KEYS[..],
-                                    Some(self.key_tab.key_configs[*i].keyboard_data.into()),
-                                    Message::KeyboardDataChanged
-                                ),
+                                text("Key Mode").size(30),
+                                row![
+                                    self.key_tab.macro_button(*i, macro_parser::MacroType::Tap),
+                                    Space::with_width(Length::Fixed(20.0)),
+                                    self.key_tab.macro_button(*i, macro_parser::MacroType::Hold),
+                                    Space::with_width(Length::Fixed(20.0)),
+                                    self.key_tab
+                                        .macro_button(*i, macro_parser::MacroType::DoubleTap),
+                                    Space::with_width(Length::Fixed(20.0)),
+                                    self.key_tab
+                                        .macro_button(*i, macro_parser::MacroType::TapHold),
+                                ],
                             ])
                             .padding(Padding {
                                 top: 20.0,
@@ -849,19 +1880,30 @@ impl Application for Configurator {
                                 bottom: 20.0,
                                 left: 0.0,
                             }),
+                            self.key_tab.macro_preview(
+                                *i,
+                                &[
+                                    macro_parser::MacroType::Tap,
+                                    macro_parser::MacroType::Hold,
+                                    macro_parser::MacroType::DoubleTap,
+                                    macro_parser::MacroType::TapHold,
+                                ]
+                            ),
+                        ]
+                    }
+                    macropad_protocol::data_protocol::KeyMode::SingleTapMode => {
+                        column![
                             container(column![
-                                text("Key Color").size(30),
-                                ColorPicker::new(
-                                    self.key_tab.show_picker,
-                                    Color::from_rgb8(
-                                        self.key_tab.key_configs[*i].key_color.0,
-                                        self.key_tab.key_configs[*i].key_color.1,
-                                        self.key_tab.key_configs[*i].key_color.2
-                                    ),
-                                    button("Pick Color").on_press(Message::KeyPickColor),
-                                    Message::KeyCancelColor,
-                                    Message::KeySubmitColor,
-                                )
+                                text("Key Mode").size(30),
+                                row![
+                                    self.key_tab.macro_button(*i, macro_parser::MacroType::Tap),
+                                    Space::with_width(Length::Fixed(20.0)),
+                                    self.key_tab.macro_button(*i, macro_parser::MacroType::Hold),
+                                    Space::with_width(Length::Fixed(20.0)),
+                                    button("Double Tap Macro"),
+                                    Space::with_width(Length::Fixed(20.0)),
+                                    button("Tap and Hold Macro"),
+                                ],
                             ])
                             .padding(Padding {
                                 top: 20.0,
@@ -869,16 +1911,20 @@ impl Application for Configurator {
                                 bottom: 20.0,
                                 left: 0.0,
                             }),
+                            self.key_tab.macro_preview(
+                                *i,
+                                &[macro_parser::MacroType::Tap, macro_parser::MacroType::Hold]
+                            ),
                         ]
                     }
-                    macropad_protocol::data_protocol::KeyMode::ConsumerMode => {
+                    macropad_protocol::data_protocol::KeyMode::KeyboardMode => {
                         column![
                             container(column![
-                                text("Consumer").size(30),
+                                text("Key").size(30),
                                 pick_list(
-                                    &type_wrapper::ConsumerWrapper::KEYS[..],
-                                    Some(self.key_tab.key_configs[*i].consumer_data.into()),
-                                    Message::ConsumerDataChanged
+                                    &type_wrapper::KeyboardWrapper::KEYS[..],
+                                    Some(self.key_tab.key_configs[*i].keyboard_data.into()),
+                                    Message::KeyboardDataChanged
                                 ),
                             ])
                             .padding(Padding {
@@ -887,19 +1933,18 @@ impl Application for Configurator {
                                 bottom: 20.0,
                                 left: 0.0,
                             }),
+                            self.key_tab.key_color_picker(*i, &self.config.palette),
+                        ]
+                    }
+                    macropad_protocol::data_protocol::KeyMode::ConsumerMode => {
+                        column![
                             container(column![
-                                text("Key Color").size(30),
-                                ColorPicker::new(
-                                    self.key_tab.show_picker,
-                                    Color::from_rgb8(
-                                        self.key_tab.key_configs[*i].key_color.0,
-                                        self.key_tab.key_configs[*i].key_color.1,
-                                        self.key_tab.key_configs[*i].key_color.2
-                                    ),
-                                    button("Pick Color").on_press(Message::KeyPickColor),
-                                    Message::KeyCancelColor,
-                                    Message::KeySubmitColor,
-                                )
+                                text("Consumer").size(30),
+                                pick_list(
+                                    &type_wrapper::ConsumerWrapper::KEYS[..],
+                                    Some(self.key_tab.key_configs[*i].consumer_data.into()),
+                                    Message::ConsumerDataChanged
+                                ),
                             ])
                             .padding(Padding {
                                 top: 20.0,
@@ -907,6 +1952,7 @@ impl Application for Configurator {
                                 bottom: 20.0,
                                 left: 0.0,
                             }),
+                            self.key_tab.key_color_picker(*i, &self.config.palette),
                         ]
                     }
                 };
@@ -1004,6 +2050,7 @@ impl Application for Configurator {
                             column![action_delay,]
                         }
                         macro_editor::ActionOptions::SetLed(color) => {
+                            let color = self.key_tab.pipeline.to_screen(*color);
                             column![
                                 action_delay,
                                 Space::with_height(Length::Fixed(20.0)),
@@ -1014,7 +2061,13 @@ impl Application for Configurator {
                                     button("Pick Color").on_press(Message::MacroActionPickColor),
                                     Message::MacroActionCancelColor,
                                     Message::MacroActionSubmitColor,
-                                )
+                                ),
+                                color_tools(
+                                    ColorTarget::MacroLed,
+                                    color,
+                                    &self.key_tab.action_option_controls.color_text,
+                                    &self.config.palette,
+                                ),
                             ]
                         }
                         macro_editor::ActionOptions::ClearLed => {
@@ -1244,6 +2297,127 @@ impl Application for Configurator {
     }
 }
 
+impl Configurator {
+    /// Sends every pending edit right away, before leaving the page or
+    /// device it was made on.
+    fn flush_writes(&mut self) {
+        let commands = self.scheduler.flush();
+
+        if let State::Connected(con, _) = &mut self.state {
+            for command in commands {
+                if !con.send(hid_manager::Message::Set(command)) {
+                    self.write_dropped = true;
+                }
+            }
+        }
+    }
+
+    /// Shows what the macropad really has after it could not take a change.
+    fn reload_after_dropped_write(&mut self) {
+        if let State::Connected(con, _) = &self.state {
+            self.command_error = Some(String::from(
+                "The macropad is too far behind, a change was not written",
+            ));
+            self.key_tab.update_config(con.get_macropad());
+            self.led_tab.update_config(con.get_macropad());
+            self.settings_tab.update_config(con.get_macropad());
+        }
+    }
+
+    /// Puts a color at the front of the recently used colors.
+    fn use_color(&mut self, color: (u8, u8, u8)) {
+        self.config.palette.use_color(color);
+        self.save_palette();
+    }
+
+    fn save_palette(&mut self) {
+        self.config.save().ok();
+        self.led_tab.palette = self.config.palette.clone();
+    }
+
+    /// The text typed into a color's text box.
+    fn color_text(&mut self, target: ColorTarget) -> &mut String {
+        match target {
+            ColorTarget::LedBase => &mut self.led_tab.color_text,
+            ColorTarget::Key => &mut self.key_tab.color_text,
+            ColorTarget::MacroLed => &mut self.key_tab.action_option_controls.color_text,
+        }
+    }
+
+    /// Switches what the key colors follow. The colors are saved when
+    /// reactive lighting is first turned on and put back when it is turned
+    /// off, even if the app was restarted in between.
+    fn set_reactive_source(&mut self, source: Option<ReactiveSource>) {
+        if let State::Connected(con, _) = &mut self.state {
+            if source.is_none() {
+                let saved = self.config.reactive_saved_colors.take();
+                for (i, color) in saved.into_iter().flatten().enumerate() {
+                    let command = hid_manager::MacropadCommand::KeyColor(i as u8, color);
+                    self.led_tab.show(&command);
+                    if !con.send(hid_manager::Message::Set(command)) {
+                        self.write_dropped = true;
+                    }
+                }
+            } else if self.config.reactive_lighting.is_none() {
+                let macropad = con.get_macropad().lock().unwrap().clone();
+                self.config.reactive_saved_colors = Some(key_colors(&macropad));
+            }
+        }
+
+        self.reactive_limiter.reset();
+        self.led_tab.reactive_status = None;
+        if self.config.reactive_lighting != source {
+            self.config.reactive_lighting = source;
+            self.config.save().ok();
+        }
+    }
+
+    /// Saves a changed brightness schedule and applies it straight away.
+    fn save_schedule(&mut self) {
+        self.config.save().ok();
+        self.settings_tab.schedule = self.config.brightness_schedule.clone();
+        self.schedule_runner.reset();
+    }
+
+    /// Hands a changed color pipeline to every tab.
+    fn set_color_pipeline(&mut self) {
+        let pipeline = self.config.color_pipeline;
+        self.key_tab.pipeline = pipeline;
+        self.led_tab.pipeline = pipeline;
+        self.led_tab.brightness_text = pipeline
+            .brightness_to_screen(self.led_tab.config.brightness)
+            .to_string();
+        self.settings_tab.pipeline = pipeline;
+    }
+
+    fn select_device(&mut self, connection: Connection) {
+        self.flush_writes();
+        // Put back what the control API changed before leaving the macropad
+        if let State::Connected(con, _) = &mut self.state {
+            for command in self.control_restore.take() {
+                if !con.send(hid_manager::Message::Set(command)) {
+                    self.write_dropped = true;
+                }
+            }
+        }
+        self.key_tab = KeyTab::new(connection.get_macropad(), self.config.color_pipeline);
+        self.led_tab = LedTab::new(
+            connection.get_macropad(),
+            LedRunner::default(),
+            &self.config,
+        );
+        self.settings_tab =
+            SettingsTab::new(connection.get_macropad(), self.theme.clone(), &self.config);
+        if !connection.is_offline() {
+            self.settings_tab.timings = Some(connection.timings());
+        }
+        self.command_error = None;
+        self.reactive_limiter.reset();
+        self.schedule_runner = ScheduleRunner::default();
+        self.state = State::Connected(connection, Page::MainPage(0));
+    }
+}
+
 #[derive(Debug)]
 enum Page {
     MainPage(usize),
@@ -1260,12 +2434,109 @@ enum TabId {
     MainPage = 0,
     ModifyLed = 1,
     ModyifySettings = 2,
+    Protocol = 3,
 }
 
 #[derive(Debug)]
 enum State {
     Disconnected(Option<macropad_updater::Connection>),
     Connected(hid_manager::Connection, Page),
+    Provisioning(ProvisioningPage),
+}
+
+#[derive(Debug, Default)]
+struct ProvisioningPage {
+    profiles: Vec<(String, std::path::PathBuf)>,
+    selected: Option<String>,
+    flash: bool,
+    running: Option<usize>,
+    job: Option<provisioning::Job>,
+    records: Vec<provisioning::ProvisionRecord>,
+    error: Option<String>,
+}
+
+impl ProvisioningPage {
+    fn view(&self) -> Element<Message> {
+        let controls = if self.running.is_some() {
+            row![
+                text(format!(
+                    "Applying {} to every macropad plugged in",
+                    self.selected.clone().unwrap_or_default()
+                ))
+                .size(20),
+                Space::with_width(Length::Fixed(20.0)),
+                button("Stop").on_press(Message::StopProvisioning),
+            ]
+        } else {
+            let start = button("Start");
+            row![
+                pick_list(
+                    self.profiles
+                        .iter()
+                        .map(|(name, _)| name.clone())
+                        .collect::<Vec<_>>(),
+                    self.selected.clone(),
+                    Message::ProvisionProfileSelected
+                ),
+                Space::with_width(Length::Fixed(20.0)),
+                checkbox(
+                    "Flash latest firmware first",
+                    self.flash,
+                    Message::ProvisionFlashToggled
+                ),
+                Space::with_width(Length::Fixed(20.0)),
+                if self.selected.is_some() {
+                    start.on_press(Message::StartProvisioning)
+                } else {
+                    start
+                },
+            ]
+        };
+
+        let passed = self.records.iter().filter(|record| record.passed).count();
+        let log = self
+            .records
+            .iter()
+            .rev()
+            .fold(Column::new().spacing(5), |log, record| {
+                log.push(
+                    text(format!(
+                        "[{}] {}: {}",
+                        if record.passed { "PASS" } else { "FAIL" },
+                        record.device,
+                        record.message
+                    ))
+                    .size(16),
+                )
+            });
+
+        let message = column![
+            row![
+                button("Back").on_press(Message::CloseProvisioning),
+                text("Provisioning")
+                    .size(60)
+                    .width(Length::Fill)
+                    .horizontal_alignment(iced::alignment::Horizontal::Center),
+            ],
+            Space::with_height(Length::Fixed(20.0)),
+            controls,
+            text(self.error.clone().unwrap_or_default()).size(16),
+            text(format!(
+                "{} passed, {} failed",
+                passed,
+                self.records.len() - passed
+            ))
+            .size(20),
+            Space::with_height(Length::Fixed(10.0)),
+            scrollable(log).height(Length::Fill),
+        ];
+
+        container(message)
+            .width(Length::Fill)
+            .height(Length::Fill)
+            .padding(20)
+            .into()
+    }
 }
 
 trait Tab {
@@ -1297,6 +2568,7 @@ trait Tab {
 pub struct ActionOptionControls {
     pub delay_text: String,
     pub show_color_picker: bool,
+    pub color_text: String,
     pub sub_delay_text: String,
     pub string_text: String,
     pub chord_text: String,
@@ -1308,6 +2580,7 @@ impl Default for ActionOptionControls {
         Self {
             delay_text: String::new(),
             show_color_picker: false,
+            color_text: String::new(),
             sub_delay_text: String::new(),
             string_text: String::new(),
             chord_text: String::new(),
@@ -1321,30 +2594,41 @@ struct KeyTab {
     selected_key: Option<usize>,
     clicked: bool,
     show_picker: bool,
+    color_text: String,
     key_configs: Vec<macro_parser::KeyConfig>,
+    loaded_macros: Vec<bool>,
+    features: Features,
     editor: macro_editor::State,
     editor_actions: Vec<Action>,
     action_option_controls: ActionOptionControls,
     selected_action: Option<macro_editor::SelectedAction>,
-    actions: HashMap<
-        macropad_protocol::data_protocol::KeyConfigElements,
-        (bool, Instant, hid_manager::MacropadCommand),
-    >,
+    pipeline: ColorPipeline,
+    /// The effect the macro preview is drawn over.
+    led_config: LedConfig,
+    led_runner: LedRunner,
+    /// The macro playing in the preview and how far into it we are.
+    playback: Option<(MacroTimeline, LedRunner)>,
 }
 
 impl KeyTab {
-    fn new(macropad: Arc<Mutex<macro_parser::Macropad>>) -> Self {
+    fn new(macropad: Arc<Mutex<macro_parser::Macropad>>, pipeline: ColorPipeline) -> Self {
         let macropad = macropad.lock().unwrap().clone();
         Self {
             selected_key: None,
             clicked: false,
             show_picker: false,
+            color_text: String::new(),
             key_configs: macropad.key_configs.clone(),
+            loaded_macros: macropad.loaded_macros.clone(),
+            features: macropad.protocol.features(),
             editor: macro_editor::State::default(),
             editor_actions: Vec::new(),
             action_option_controls: ActionOptionControls::default(),
             selected_action: None,
-            actions: HashMap::new(),
+            pipeline,
+            led_config: macropad.led_config.clone(),
+            led_runner: LedRunner::default(),
+            playback: None,
         }
     }
 
@@ -1387,63 +2671,158 @@ impl KeyTab {
     fn update_config(&mut self, macropad: Arc<Mutex<macro_parser::Macropad>>) {
         let macropad = macropad.lock().unwrap().clone();
         self.key_configs = macropad.key_configs.clone();
+        self.loaded_macros = macropad.loaded_macros.clone();
+        self.features = macropad.protocol.features();
+        self.led_config = macropad.led_config.clone();
+    }
+
+    /// The key's color picker, empty if the firmware has no per key colors.
+    fn key_color_picker(&self, key: usize, palette: &Palette) -> Element<'_, Message> {
+        if !self.features.key_colors {
+            return Space::with_height(Length::Shrink).into();
+        }
+
+        let (r, g, b) = self.pipeline.to_screen(self.key_configs[key].key_color);
+
+        container(column![
+            text("Key Color").size(30),
+            ColorPicker::new(
+                self.show_picker,
+                Color::from_rgb8(r, g, b),
+                button("Pick Color").on_press(Message::KeyPickColor),
+                Message::KeyCancelColor,
+                Message::KeySubmitColor,
+            ),
+            color_tools(ColorTarget::Key, (r, g, b), &self.color_text, palette),
+        ])
+        .padding(Padding {
+            top: 20.0,
+            right: 0.0,
+            bottom: 20.0,
+            left: 0.0,
+        })
+        .into()
+    }
+
+    /// A button opening one of the key's macros, disabled until the macro
+    /// has been read from the device.
+    fn macro_button(
+        &self,
+        key: usize,
+        macro_type: macro_parser::MacroType,
+    ) -> iced::widget::Button<'_, Message> {
+        let (label, slot) = match macro_type {
+            macro_parser::MacroType::Tap => ("Tap Macro", 0),
+            macro_parser::MacroType::Hold => ("Hold Macro", 1),
+            macro_parser::MacroType::DoubleTap => ("Double Tap Macro", 2),
+            macro_parser::MacroType::TapHold => ("Tap and Hold Macro", 3),
+        };
+
+        if self.loaded_macros[(key << 2) | slot] {
+            button(label).on_press(Message::LoadMacro(macro_type))
+        } else {
+            button(text(format!("{} (loading...)", label)))
+        }
+    }
+
+    /// Shows a change made outside the tab.
+    fn show(&mut self, command: &hid_manager::MacropadCommand) {
+        if let hid_manager::MacropadCommand::KeyColor(key, color) = command {
+            if let Some(config) = self.key_configs.get_mut(*key as usize) {
+                config.key_color = *color;
+            }
+        }
     }
 
-    fn run_actions(&mut self, con: &mut Connection) {
-        for (_, (active, time_to_run, action)) in self.actions.iter_mut() {
-            if *active && time_to_run.elapsed() > Duration::ZERO {
-                *active = false;
+    /// The LEDs with the playing macro's color drawn over the key.
+    fn preview_leds(&self, key: usize) -> [Color; 4] {
+        let key_colors: Vec<_> = if self.features.key_colors {
+            self.key_configs
+                .iter()
+                .map(|config| config.key_color)
+                .collect()
+        } else {
+            Vec::new()
+        };
+        let mut leds = self.led_runner.get_leds(&self.led_config, &key_colors);
 
-                con.send(hid_manager::Message::Set(action.clone()));
+        if let Some((timeline, runner)) = &self.playback {
+            if let Some(color) = timeline.color_at(runner.elapsed()) {
+                leds[key] = led_effects::key_led(color, self.led_config.brightness);
             }
         }
+
+        self.pipeline.screen_leds(leds)
+    }
+
+    /// Buttons playing the key's macros with the LED changes they make shown
+    /// on the pad.
+    fn macro_preview(
+        &self,
+        key: usize,
+        macro_types: &[macro_parser::MacroType],
+    ) -> Element<'_, Message> {
+        let buttons: Vec<Element<'_, Message>> = macro_types
+            .iter()
+            .map(|macro_type| {
+                let (label, slot) = match macro_type {
+                    macro_parser::MacroType::Tap => ("Tap", 0),
+                    macro_parser::MacroType::Hold => ("Hold", 1),
+                    macro_parser::MacroType::DoubleTap => ("Double Tap", 2),
+                    macro_parser::MacroType::TapHold => ("Tap and Hold", 3),
+                };
+
+                if self.loaded_macros[(key << 2) | slot] {
+                    button(label)
+                        .on_press(Message::PlayMacro(macro_type.clone()))
+                        .into()
+                } else {
+                    button(label).into()
+                }
+            })
+            .collect();
+
+        container(column![
+            text("Play Macro").size(30),
+            Row::with_children(buttons).spacing(20),
+            container(macropad::macropad_led(self.preview_leds(key)))
+                .width(Length::Fixed(200.0))
+                .height(Length::Fixed(200.0)),
+        ])
+        .padding(Padding {
+            top: 20.0,
+            right: 0.0,
+            bottom: 20.0,
+            left: 0.0,
+        })
+        .into()
     }
 
-    fn queue_action(&mut self, action: hid_manager::MacropadCommand) {
+    fn queue_action(
+        &mut self,
+        scheduler: &mut WriteScheduler,
+        restore: &mut control_api::Restore,
+        action: hid_manager::MacropadCommand,
+    ) {
+        // A change made here is kept, not put back after a notification
+        restore.release(&[action.clone()]);
+
         match action {
             hid_manager::MacropadCommand::KeyMode(key, mode) => {
                 self.key_configs[key as usize].key_mode = mode;
-                self.actions.insert(
-                    macropad_protocol::data_protocol::KeyConfigElements::KeyMode,
-                    (
-                        true,
-                        Instant::now() + Duration::from_millis(ACTION_DELAY),
-                        action,
-                    ),
-                );
+                scheduler.schedule(action);
             }
             hid_manager::MacropadCommand::KeyboardData(key, keyboard) => {
                 self.key_configs[key as usize].keyboard_data = keyboard;
-                self.actions.insert(
-                    macropad_protocol::data_protocol::KeyConfigElements::KeyboardData,
-                    (
-                        true,
-                        Instant::now() + Duration::from_millis(ACTION_DELAY),
-                        action,
-                    ),
-                );
+                scheduler.schedule(action);
             }
             hid_manager::MacropadCommand::ConsumerData(key, consumer) => {
                 self.key_configs[key as usize].consumer_data = consumer;
-                self.actions.insert(
-                    macropad_protocol::data_protocol::KeyConfigElements::ConsumerData,
-                    (
-                        true,
-                        Instant::now() + Duration::from_millis(ACTION_DELAY),
-                        action,
-                    ),
-                );
+                scheduler.schedule(action);
             }
             hid_manager::MacropadCommand::KeyColor(key, color) => {
                 self.key_configs[key as usize].key_color = color;
-                self.actions.insert(
-                    macropad_protocol::data_protocol::KeyConfigElements::KeyColor,
-                    (
-                        true,
-                        Instant::now() + Duration::from_millis(ACTION_DELAY),
-                        action,
-                    ),
-                );
+                scheduler.schedule(action);
             }
             _ => unreachable!(),
         }
@@ -1456,12 +2835,18 @@ impl Default for KeyTab {
             selected_key: None,
             clicked: false,
             show_picker: false,
+            color_text: String::new(),
             key_configs: Vec::new(),
+            loaded_macros: Vec::new(),
+            features: Adapter::V1.features(),
             editor: macro_editor::State::default(),
             editor_actions: Vec::new(),
             action_option_controls: ActionOptionControls::default(),
             selected_action: None,
-            actions: HashMap::new(),
+            pipeline: ColorPipeline::default(),
+            led_config: LedConfig::default(),
+            led_runner: LedRunner::default(),
+            playback: None,
         }
     }
 }
@@ -1487,6 +2872,15 @@ impl Tab for KeyTab {
                 .on_press(Message::ButtonPressed)
                 .on_hover(Message::ButtonHovered)
                 .on_click(Message::ButtonClicked),
+            text(
+                match self.loaded_macros.iter().filter(|loaded| !**loaded).count() {
+                    0 => String::new(),
+                    remaining => format!("Loading macros... {} left", remaining),
+                }
+            )
+            .size(16)
+            .width(Length::Fill)
+            .horizontal_alignment(iced::alignment::Horizontal::Center),
         ];
 
         container(message)
@@ -1499,103 +2893,258 @@ impl Tab for KeyTab {
     }
 }
 
+/// The per key colors, all black if the firmware does not support them.
+fn key_colors(macropad: &macro_parser::Macropad) -> Vec<(u8, u8, u8)> {
+    if !macropad.protocol.features().key_colors {
+        return vec![(0, 0, 0); macropad.key_configs.len()];
+    }
+
+    macropad
+        .key_configs
+        .iter()
+        .map(|config| config.key_color)
+        .collect()
+}
+
+/// Which color picker a palette color or typed color goes to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorTarget {
+    LedBase,
+    /// The key being modified.
+    Key,
+    /// The selected set LED action.
+    MacroLed,
+}
+
+impl ColorTarget {
+    /// The message the target's color picker submits.
+    fn submit(self, color: (u8, u8, u8)) -> Message {
+        let color = Color::from_rgb8(color.0, color.1, color.2);
+        match self {
+            ColorTarget::LedBase => Message::LedSubmitColor(color),
+            ColorTarget::Key => Message::KeySubmitColor(color),
+            ColorTarget::MacroLed => Message::MacroActionSubmitColor(color),
+        }
+    }
+}
+
+/// A button filled with the color it picks.
+struct SwatchStyle((u8, u8, u8));
+
+impl button::StyleSheet for SwatchStyle {
+    type Style = Theme;
+
+    fn active(&self, _style: &Self::Style) -> button::Appearance {
+        button::Appearance {
+            background: Some(iced::Background::Color(Color::from_rgb8(
+                self.0 .0, self.0 .1, self.0 .2,
+            ))),
+            border_radius: 4.0,
+            border_width: 1.0,
+            border_color: Color::from_rgb8(0x80, 0x80, 0x80),
+            ..Default::default()
+        }
+    }
+}
+
+fn swatches<'a>(
+    label: &'static str,
+    colors: &[(u8, u8, u8)],
+    target: ColorTarget,
+) -> Element<'a, Message> {
+    if colors.is_empty() {
+        return Space::with_height(Length::Shrink).into();
+    }
+
+    colors
+        .iter()
+        .fold(
+            row![text(label).size(16).width(Length::Fixed(70.0))].spacing(5),
+            |row, color| {
+                row.push(
+                    button(Space::new(Length::Fixed(16.0), Length::Fixed(16.0)))
+                        .style(Button::Custom(Box::new(SwatchStyle(*color))))
+                        .on_press(Message::PaletteColorSelected(target, *color)),
+                )
+            },
+        )
+        .into()
+}
+
+/// Typed color entry, the current color in every notation and the shared
+/// palette, shown under a color picker.
+fn color_tools<'a>(
+    target: ColorTarget,
+    current: (u8, u8, u8),
+    color_text: &str,
+    palette: &Palette,
+) -> Element<'a, Message> {
+    let description = if color_text.trim().is_empty() {
+        format!(
+            "{}  {}  {}",
+            palette::to_hex(current),
+            palette::to_rgb(current),
+            palette::to_hsv(current)
+        )
+    } else {
+        match palette::parse_color(color_text) {
+            Ok(color) => format!("Press enter to use {}", palette::to_hex(color)),
+            Err(()) => String::from("Type #rrggbb, rgb(r, g, b) or hsv(h, s%, v%)"),
+        }
+    };
+
+    let swatch_button = if palette.swatches.contains(&current) {
+        button("Remove Swatch").on_press(Message::RemoveSwatch(current))
+    } else {
+        button("Save Swatch").on_press(Message::SaveSwatch(current))
+    };
+
+    column![
+        row![
+            text_input("#rrggbb", color_text, move |text| {
+                Message::ColorTextChanged(target, text)
+            })
+            .on_submit(Message::ColorTextSubmitted(target))
+            .width(Length::Fixed(150.0)),
+            Space::with_width(Length::Fixed(20.0)),
+            swatch_button,
+        ],
+        text(description).size(16),
+        swatches("Recent", &palette.recent, target),
+        swatches("Swatches", &palette.swatches, target),
+    ]
+    .spacing(5)
+    .padding(Padding {
+        top: 10.0,
+        right: 0.0,
+        bottom: 0.0,
+        left: 0.0,
+    })
+    .into()
+}
+
 #[derive(Debug)]
 struct LedTab {
     config: macro_parser::LedConfig,
+    /// Drawn over the effect in the preview.
+    key_colors: Vec<(u8, u8, u8)>,
+    export_status: Option<String>,
+    /// Names of the saved presets.
+    presets: Vec<String>,
+    selected_preset: Option<String>,
+    preset_name_text: String,
+    preset_status: Option<String>,
+    /// Whether the transaction being applied is a preset.
+    applying_preset: bool,
+    pipeline: ColorPipeline,
+    palette: Palette,
+    color_text: String,
+    /// Whether harmonies can be applied to the key colors.
+    has_key_colors: bool,
+    reactive_mode: ReactiveMode,
+    reactive_path_text: String,
+    reactive_status: Option<String>,
     led_runner: LedRunner,
     show_picker: bool,
     period_text: String,
     brightness_text: String,
-    actions: HashMap<
-        macropad_protocol::data_protocol::LedCommand,
-        (bool, Instant, hid_manager::MacropadCommand),
-    >,
 }
 
 impl LedTab {
-    fn new(macropad: Arc<Mutex<macro_parser::Macropad>>, led_runner: LedRunner) -> Self {
+    fn new(
+        macropad: Arc<Mutex<macro_parser::Macropad>>,
+        led_runner: LedRunner,
+        app_config: &AppConfig,
+    ) -> Self {
         let config = macropad.lock().unwrap().led_config.clone();
+        let key_colors = key_colors(&macropad.lock().unwrap());
+        let has_key_colors = macropad.lock().unwrap().protocol.features().key_colors;
+        let pipeline = app_config.color_pipeline;
 
         Self {
             config: config.clone(),
+            key_colors,
+            export_status: None,
+            presets: app_config.led_preset_names(),
+            selected_preset: None,
+            preset_name_text: String::new(),
+            preset_status: None,
+            applying_preset: false,
+            pipeline,
+            palette: app_config.palette.clone(),
+            color_text: String::new(),
+            has_key_colors,
+            reactive_mode: ReactiveMode::of(app_config.reactive_lighting.as_ref()),
+            reactive_path_text: match &app_config.reactive_lighting {
+                Some(ReactiveSource::Image { path }) => path.display().to_string(),
+                _ => String::new(),
+            },
+            reactive_status: None,
             led_runner,
             show_picker: false,
             period_text: config.effect_period.to_string(),
-            brightness_text: config.brightness.to_string(),
-            actions: HashMap::new(),
+            brightness_text: pipeline.brightness_to_screen(config.brightness).to_string(),
         }
     }
 
     fn update_config(&mut self, macropad: Arc<Mutex<macro_parser::Macropad>>) {
         self.config = macropad.lock().unwrap().led_config.clone();
+        self.key_colors = key_colors(&macropad.lock().unwrap());
     }
 
-    fn run_actions(&mut self, con: &mut Connection) {
-        for (_, (active, time_to_run, action)) in self.actions.iter_mut() {
-            if *active && time_to_run.elapsed() > Duration::ZERO {
-                *active = false;
-
-                con.send(hid_manager::Message::Set(action.clone()));
+    /// Shows a change made outside the tab.
+    fn show(&mut self, command: &hid_manager::MacropadCommand) {
+        match command {
+            hid_manager::MacropadCommand::LedBaseColor(color) => self.config.base_color = *color,
+            hid_manager::MacropadCommand::LedEffect(effect) => self.config.effect = *effect,
+            hid_manager::MacropadCommand::LedBrightness(brightness) => {
+                self.config.brightness = *brightness;
+                self.brightness_text = self.pipeline.brightness_to_screen(*brightness).to_string();
             }
+            hid_manager::MacropadCommand::LedEffectPeriod(period) => {
+                self.config.effect_period = *period;
+                self.period_text = period.to_string();
+            }
+            hid_manager::MacropadCommand::LedEffectOffset(offset) => {
+                self.config.effect_offset = *offset
+            }
+            hid_manager::MacropadCommand::KeyColor(key, color) => {
+                if let Some(key_color) = self.key_colors.get_mut(*key as usize) {
+                    *key_color = *color;
+                }
+            }
+            _ => {}
         }
     }
 
-    fn queue_action(&mut self, action: hid_manager::MacropadCommand) {
+    fn queue_action(
+        &mut self,
+        scheduler: &mut WriteScheduler,
+        restore: &mut control_api::Restore,
+        action: hid_manager::MacropadCommand,
+    ) {
+        restore.release(&[action.clone()]);
+
         match action {
             hid_manager::MacropadCommand::LedBaseColor(color) => {
                 self.config.base_color = color;
-                self.actions.insert(
-                    macropad_protocol::data_protocol::LedCommand::BaseColor,
-                    (
-                        true,
-                        Instant::now() + Duration::from_millis(ACTION_DELAY),
-                        action,
-                    ),
-                );
+                scheduler.schedule(action);
             }
             hid_manager::MacropadCommand::LedEffect(effect) => {
                 self.config.effect = effect;
-                self.actions.insert(
-                    macropad_protocol::data_protocol::LedCommand::Effect,
-                    (
-                        true,
-                        Instant::now() + Duration::from_millis(ACTION_DELAY),
-                        action,
-                    ),
-                );
+                scheduler.schedule(action);
             }
             hid_manager::MacropadCommand::LedBrightness(brightness) => {
                 self.config.brightness = brightness;
-                self.actions.insert(
-                    macropad_protocol::data_protocol::LedCommand::Brightness,
-                    (
-                        true,
-                        Instant::now() + Duration::from_millis(ACTION_DELAY),
-                        action,
-                    ),
-                );
+                scheduler.schedule(action);
             }
             hid_manager::MacropadCommand::LedEffectPeriod(period) => {
                 self.config.effect_period = period;
-                self.actions.insert(
-                    macropad_protocol::data_protocol::LedCommand::EffectPeriod,
-                    (
-                        true,
-                        Instant::now() + Duration::from_millis(ACTION_DELAY),
-                        action,
-                    ),
-                );
+                scheduler.schedule(action);
             }
             hid_manager::MacropadCommand::LedEffectOffset(offset) => {
                 self.config.effect_offset = offset;
-                self.actions.insert(
-                    macropad_protocol::data_protocol::LedCommand::EffectOffset,
-                    (
-                        true,
-                        Instant::now() + Duration::from_millis(ACTION_DELAY),
-                        action,
-                    ),
-                );
+                scheduler.schedule(action);
             }
             _ => unreachable!(),
         }
@@ -1606,11 +3155,24 @@ impl Default for LedTab {
     fn default() -> Self {
         Self {
             config: LedConfig::default(),
+            key_colors: Vec::new(),
+            export_status: None,
+            presets: Vec::new(),
+            selected_preset: None,
+            preset_name_text: String::new(),
+            preset_status: None,
+            applying_preset: false,
+            pipeline: ColorPipeline::default(),
+            palette: Palette::default(),
+            color_text: String::new(),
+            has_key_colors: false,
+            reactive_mode: ReactiveMode::Off,
+            reactive_path_text: String::new(),
+            reactive_status: None,
             led_runner: LedRunner::default(),
             show_picker: false,
             period_text: String::from(""),
             brightness_text: String::from(""),
-            actions: HashMap::new(),
         }
     }
 }
@@ -1627,6 +3189,9 @@ impl Tab for LedTab {
     }
 
     fn content(&self) -> Element<'_, Self::Message> {
+        let brightness = self.pipeline.brightness_to_screen(self.config.brightness);
+        let base_color = self.pipeline.to_screen(self.config.base_color);
+
         let message = column![row![
             column![
                 container(column![
@@ -1672,13 +3237,13 @@ impl Tab for LedTab {
                     row![
                         slider(
                             0.0..=255.0,
-                            self.config.brightness as f32,
+                            brightness as f32,
                             Message::LedBrightnessChanged
                         )
                         .width(Length::Fixed(200.0)),
                         Space::with_width(Length::Fixed(20.0)),
                         text_input(
-                            self.config.brightness.to_string().as_str(),
+                            brightness.to_string().as_str(),
                             self.brightness_text.as_str(),
                             Message::LedBrightnessChangedText
                         )
@@ -1695,15 +3260,132 @@ impl Tab for LedTab {
                     text("Base Color").size(30),
                     ColorPicker::new(
                         self.show_picker,
-                        Color::from_rgb8(
-                            self.config.base_color.0,
-                            self.config.base_color.1,
-                            self.config.base_color.2
-                        ),
+                        Color::from_rgb8(base_color.0, base_color.1, base_color.2),
                         button("Pick Color").on_press(Message::LedPickColor),
                         Message::LedCancelColor,
                         Message::LedSubmitColor,
-                    )
+                    ),
+                    color_tools(
+                        ColorTarget::LedBase,
+                        base_color,
+                        &self.color_text,
+                        &self.palette
+                    ),
+                ])
+                .padding(Padding {
+                    top: 20.0,
+                    right: 0.0,
+                    bottom: 20.0,
+                    left: 0.0,
+                }),
+                container(column![
+                    text("Key Color Harmony").size(30),
+                    Harmony::ALL
+                        .iter()
+                        .fold(Row::new().spacing(10), |row, harmony| {
+                            let button = button(text(harmony.to_string()));
+                            row.push(if self.has_key_colors {
+                                button.on_press(Message::ApplyHarmony(*harmony))
+                            } else {
+                                button
+                            })
+                        }),
+                    text("Sets the key colors from the base color").size(16),
+                ])
+                .padding(Padding {
+                    top: 20.0,
+                    right: 0.0,
+                    bottom: 20.0,
+                    left: 0.0,
+                }),
+                container(column![
+                    text("Reactive Lighting").size(30),
+                    if self.has_key_colors {
+                        Element::from(pick_list(
+                            &ReactiveMode::ALL[..],
+                            Some(self.reactive_mode),
+                            Message::ReactiveModeSelected,
+                        ))
+                    } else {
+                        text("Needs firmware with per key colors").size(16).into()
+                    },
+                    if self.reactive_mode == ReactiveMode::Image {
+                        Element::from(
+                            text_input(
+                                "Image path",
+                                self.reactive_path_text.as_str(),
+                                Message::ReactiveImagePathChangedText,
+                            )
+                            .on_submit(Message::ReactiveImagePathSubmitted)
+                            .width(Length::Fixed(300.0)),
+                        )
+                    } else {
+                        Space::with_height(Length::Shrink).into()
+                    },
+                    text(self.reactive_status.clone().unwrap_or_else(|| {
+                        String::from("Follows the host while the configurator is open")
+                    }))
+                    .size(16),
+                ])
+                .padding(Padding {
+                    top: 20.0,
+                    right: 0.0,
+                    bottom: 20.0,
+                    left: 0.0,
+                }),
+                container(column![
+                    text("Presets").size(30),
+                    row![
+                        pick_list(
+                            self.presets.clone(),
+                            self.selected_preset.clone(),
+                            Message::LedPresetSelected
+                        ),
+                        Space::with_width(Length::Fixed(20.0)),
+                        if self.selected_preset.is_some() {
+                            button("Apply").on_press(Message::ApplyLedPreset)
+                        } else {
+                            button("Apply")
+                        },
+                        Space::with_width(Length::Fixed(20.0)),
+                        if self.selected_preset.is_some() {
+                            button("Delete").on_press(Message::DeleteLedPreset)
+                        } else {
+                            button("Delete")
+                        },
+                    ],
+                    Space::with_height(Length::Fixed(10.0)),
+                    row![
+                        text_input(
+                            "Preset name",
+                            self.preset_name_text.as_str(),
+                            Message::LedPresetNameChangedText
+                        )
+                        .width(Length::Fixed(200.0)),
+                        Space::with_width(Length::Fixed(20.0)),
+                        if self.preset_name_text.trim().is_empty() {
+                            button("Save Current")
+                        } else {
+                            button("Save Current").on_press(Message::SaveLedPreset)
+                        },
+                    ],
+                    text(self.preset_status.clone().unwrap_or_default()).size(16),
+                ])
+                .padding(Padding {
+                    top: 20.0,
+                    right: 0.0,
+                    bottom: 20.0,
+                    left: 0.0,
+                }),
+                container(column![
+                    text("Export Preview").size(30),
+                    row![
+                        button("GIF").on_press(Message::ExportLeds(ExportFormat::Gif)),
+                        Space::with_width(Length::Fixed(20.0)),
+                        button("Sprite Sheet")
+                            .on_press(Message::ExportLeds(ExportFormat::SpriteSheet)),
+                    ],
+                    text(self.export_status.clone().unwrap_or_default()).size(16),
                 ])
                 .padding(Padding {
                     top: 20.0,
@@ -1712,7 +3394,10 @@ impl Tab for LedTab {
                     left: 0.0,
                 }),
             ],
-            macropad::macropad_led(self.led_runner.get_leds(&self.config)),
+            macropad::macropad_led(
+                self.pipeline
+                    .screen_leds(self.led_runner.get_leds(&self.config, &self.key_colors))
+            ),
         ],];
 
         container(message)
@@ -1732,16 +3417,34 @@ struct SettingsTab {
     theme: Theme,
     press_time_text: String,
     hold_time_text: String,
-    actions: HashMap<
-        macropad_protocol::data_protocol::ConfigElements,
-        (bool, Instant, hid_manager::MacropadCommand),
-    >,
+    profile_name_text: String,
+    profile_saved: Option<bool>,
+    profiles: Vec<(String, std::path::PathBuf)>,
+    selected_profile: Option<String>,
+    load_status: Option<String>,
+    timings: Option<hid_manager::Timings>,
+    macros_loading: bool,
+    verify_writes: bool,
+    verify_log: Vec<String>,
+    control_api: bool,
+    pipeline: ColorPipeline,
+    schedule: BrightnessSchedule,
+    schedule_time_text: String,
+    schedule_level_text: String,
+    schedule_error: Option<String>,
+    /// Whether the LEDs were changed by hand since the last transition.
+    schedule_overridden: bool,
 }
 
 impl SettingsTab {
-    fn new(macropad: Arc<Mutex<macro_parser::Macropad>>, theme: Theme) -> Self {
+    fn new(
+        macropad: Arc<Mutex<macro_parser::Macropad>>,
+        theme: Theme,
+        app_config: &AppConfig,
+    ) -> Self {
         let config = macropad.lock().unwrap().config.clone();
         let build_info = macropad.lock().unwrap().build_info.clone();
+        let macros_loading = macropad.lock().unwrap().next_unloaded_macro().is_some();
 
         Self {
             config: config.clone(),
@@ -1749,51 +3452,141 @@ impl SettingsTab {
             theme,
             press_time_text: (config.tap_speed / 1000).to_string(),
             hold_time_text: (config.hold_speed / 1000).to_string(),
-            actions: HashMap::new(),
+            profile_name_text: String::new(),
+            profile_saved: None,
+            profiles: profile::list_profiles(),
+            selected_profile: None,
+            load_status: None,
+            timings: None,
+            macros_loading,
+            verify_writes: app_config.verify_writes,
+            verify_log: Vec::new(),
+            control_api: app_config.control_api,
+            pipeline: app_config.color_pipeline,
+            schedule: app_config.brightness_schedule.clone(),
+            schedule_time_text: String::new(),
+            schedule_level_text: String::new(),
+            schedule_error: None,
+            schedule_overridden: false,
         }
     }
 
     fn update_config(&mut self, macropad: Arc<Mutex<macro_parser::Macropad>>) {
         self.config = macropad.lock().unwrap().config.clone();
+        self.macros_loading = macropad.lock().unwrap().next_unloaded_macro().is_some();
     }
 
-    fn run_actions(&mut self, con: &mut Connection) {
-        for (_, (active, time_to_run, action)) in self.actions.iter_mut() {
-            if *active && time_to_run.elapsed() > Duration::ZERO {
-                *active = false;
-
-                con.send(hid_manager::Message::Set(action.clone()));
-            }
-        }
-    }
+    fn queue_action(
+        &mut self,
+        scheduler: &mut WriteScheduler,
+        restore: &mut control_api::Restore,
+        action: hid_manager::MacropadCommand,
+    ) {
+        restore.release(&[action.clone()]);
 
-    fn queue_action(&mut self, action: hid_manager::MacropadCommand) {
         match action {
             hid_manager::MacropadCommand::TapSpeed(speed) => {
                 self.config.tap_speed = speed;
-                self.actions.insert(
-                    macropad_protocol::data_protocol::ConfigElements::TapSpeed,
-                    (
-                        true,
-                        Instant::now() + Duration::from_millis(ACTION_DELAY),
-                        action,
-                    ),
-                );
+                scheduler.schedule(action);
             }
             hid_manager::MacropadCommand::HoldSpeed(speed) => {
                 self.config.hold_speed = speed;
-                self.actions.insert(
-                    macropad_protocol::data_protocol::ConfigElements::HoldSpeed,
-                    (
-                        true,
-                        Instant::now() + Duration::from_millis(ACTION_DELAY),
-                        action,
-                    ),
-                );
+                scheduler.schedule(action);
             }
             _ => unreachable!(),
         }
     }
+
+    /// A labelled slider for one of the color pipeline's settings, saved
+    /// once it is let go.
+    fn pipeline_slider(
+        &self,
+        label: &str,
+        range: std::ops::RangeInclusive<f32>,
+        value: f32,
+        message: impl Fn(f32) -> Message + 'static,
+    ) -> Element<'_, Message> {
+        row![
+            text(label).width(Length::Fixed(60.0)),
+            slider(range, value, message)
+                .step(0.01)
+                .on_release(Message::SaveColorPipeline)
+                .width(Length::Fixed(200.0)),
+            Space::with_width(Length::Fixed(20.0)),
+            text(format!("{:.2}", value)),
+        ]
+        .into()
+    }
+
+    fn schedule_section(&self) -> Element<'_, Message> {
+        let status = if !self.schedule.enabled {
+            String::new()
+        } else {
+            let next = self
+                .schedule
+                .next_change(chrono::Local::now().naive_local())
+                .map(|at| at.format("%a %H:%M").to_string());
+
+            match (next, self.schedule_overridden) {
+                (Some(next), true) => format!("Changed by hand, the schedule resumes {}", next),
+                (Some(next), false) => format!("Next change {}", next),
+                (None, _) => String::from("Add a time to start the schedule"),
+            }
+        };
+
+        let transitions = self.schedule.transitions.iter().enumerate().fold(
+            Column::new().spacing(5),
+            |column, (i, transition)| {
+                column.push(row![
+                    text(transition.to_string()).width(Length::Fixed(200.0)),
+                    button("Remove").on_press(Message::RemoveScheduleTransition(i)),
+                ])
+            },
+        );
+
+        container(column![
+            text("Brightness Schedule").size(30),
+            checkbox(
+                "Follow the schedule",
+                self.schedule.enabled,
+                Message::ScheduleToggled
+            ),
+            checkbox(
+                "Off on weekends",
+                self.schedule.off_on_weekends,
+                Message::ScheduleWeekendsToggled
+            ),
+            Space::with_height(Length::Fixed(10.0)),
+            transitions,
+            Space::with_height(Length::Fixed(10.0)),
+            row![
+                text_input(
+                    "hh:mm",
+                    self.schedule_time_text.as_str(),
+                    Message::ScheduleTimeChangedText
+                )
+                .width(Length::Fixed(80.0)),
+                Space::with_width(Length::Fixed(10.0)),
+                text_input(
+                    "Brightness, on or off",
+                    self.schedule_level_text.as_str(),
+                    Message::ScheduleLevelChangedText
+                )
+                .on_submit(Message::AddScheduleTransition)
+                .width(Length::Fixed(160.0)),
+                Space::with_width(Length::Fixed(10.0)),
+                button("Add").on_press(Message::AddScheduleTransition),
+            ],
+            text(self.schedule_error.clone().unwrap_or(status)).size(16),
+        ])
+        .padding(Padding {
+            top: 20.0,
+            right: 0.0,
+            bottom: 20.0,
+            left: 0.0,
+        })
+        .into()
+    }
 }
 
 impl Default for SettingsTab {
@@ -1808,7 +3601,22 @@ impl Default for SettingsTab {
             },
             press_time_text: String::from(""),
             hold_time_text: String::from(""),
-            actions: HashMap::new(),
+            profile_name_text: String::from(""),
+            profile_saved: None,
+            profiles: Vec::new(),
+            selected_profile: None,
+            load_status: None,
+            timings: None,
+            macros_loading: false,
+            verify_writes: false,
+            verify_log: Vec::new(),
+            control_api: false,
+            pipeline: ColorPipeline::default(),
+            schedule: BrightnessSchedule::default(),
+            schedule_time_text: String::new(),
+            schedule_level_text: String::new(),
+            schedule_error: None,
+            schedule_overridden: false,
         }
     }
 }
@@ -1873,11 +3681,155 @@ impl Tab for SettingsTab {
                     bottom: 20.0,
                     left: 0.0,
                 }),
-                container(button(text("Update Macropad")).on_press(Message::MacropadBootloader))
-                    // .width(Length::Fill)
-                    .height(Length::Fill)
-                    .align_x(alignment::Horizontal::Center)
-                    .align_y(alignment::Vertical::Bottom)
+                container(column![
+                    text("Save Profile").size(30),
+                    row![
+                        text_input(
+                            "Profile name",
+                            self.profile_name_text.as_str(),
+                            Message::ProfileNameChangedText
+                        )
+                        .width(Length::Fixed(200.0)),
+                        Space::with_width(Length::Fixed(20.0)),
+                        if !profile::is_valid_name(self.profile_name_text.trim())
+                            || self.macros_loading
+                        {
+                            button("Save")
+                        } else {
+                            button("Save").on_press(Message::SaveProfile)
+                        },
+                        Space::with_width(Length::Fixed(20.0)),
+                        text(match self.profile_saved {
+                            Some(true) => "Saved",
+                            Some(false) => "Could not save profile",
+                            None => "",
+                        })
+                        .size(16),
+                    ],
+                ])
+                .padding(Padding {
+                    top: 20.0,
+                    right: 0.0,
+                    bottom: 20.0,
+                    left: 0.0,
+                }),
+                container(column![
+                    text("Load Profile").size(30),
+                    row![
+                        pick_list(
+                            self.profiles
+                                .iter()
+                                .map(|(name, _)| name.clone())
+                                .collect::<Vec<_>>(),
+                            self.selected_profile.clone(),
+                            Message::LoadProfileSelected
+                        ),
+                        Space::with_width(Length::Fixed(20.0)),
+                        if self.selected_profile.is_some() {
+                            button("Apply").on_press(Message::LoadProfile)
+                        } else {
+                            button("Apply")
+                        },
+                        Space::with_width(Length::Fixed(20.0)),
+                        text(self.load_status.clone().unwrap_or_default()).size(16),
+                    ],
+                ])
+                .padding(Padding {
+                    top: 20.0,
+                    right: 0.0,
+                    bottom: 20.0,
+                    left: 0.0,
+                }),
+                container(column![
+                    text("Verification").size(30),
+                    row![
+                        checkbox(
+                            "Verify every write",
+                            self.verify_writes,
+                            Message::VerifyWritesToggled
+                        ),
+                        Space::with_width(Length::Fixed(20.0)),
+                        // Macros still loading would all show up as differences
+                        if self.macros_loading {
+                            button("Verify Device")
+                        } else {
+                            button("Verify Device").on_press(Message::VerifyDevice)
+                        },
+                    ],
+                    self.verify_log
+                        .iter()
+                        .fold(Column::new(), |log, line| log.push(text(line).size(16))),
+                ])
+                .padding(Padding {
+                    top: 20.0,
+                    right: 0.0,
+                    bottom: 20.0,
+                    left: 0.0,
+                }),
+                container(column![
+                    text("Color Correction").size(30),
+                    self.pipeline_slider(
+                        "Gamma",
+                        1.0..=3.0,
+                        self.pipeline.gamma,
+                        Message::GammaChanged
+                    ),
+                    self.pipeline_slider("Red", 0.0..=1.0, self.pipeline.correction.0, |value| {
+                        Message::ColorCorrectionChanged(0, value)
+                    }),
+                    self.pipeline_slider("Green", 0.0..=1.0, self.pipeline.correction.1, |value| {
+                        Message::ColorCorrectionChanged(1, value)
+                    }),
+                    self.pipeline_slider("Blue", 0.0..=1.0, self.pipeline.correction.2, |value| {
+                        Message::ColorCorrectionChanged(2, value)
+                    }),
+                ])
+                .padding(Padding {
+                    top: 20.0,
+                    right: 0.0,
+                    bottom: 20.0,
+                    left: 0.0,
+                }),
+                container(column![
+                    text("Control API").size(30),
+                    checkbox(
+                        "Let other programs change the LEDs",
+                        self.control_api,
+                        Message::ControlApiToggled
+                    ),
+                    text(match (control_api::error(), control_api::socket_path()) {
+                        _ if !self.control_api => String::new(),
+                        (Some(error), _) => format!("Not listening: {}", error),
+                        (None, Some(path)) if control_api::is_listening() =>
+                            format!("Listening on {}", path.display()),
+                        _ => String::from("Starting..."),
+                    })
+                    .size(16),
+                ])
+                .padding(Padding {
+                    top: 20.0,
+                    right: 0.0,
+                    bottom: 20.0,
+                    left: 0.0,
+                }),
+                self.schedule_section(),
+                text(match device_watcher::error() {
+                    Some(error) => format!(
+                        "Hot-plug events unavailable, polling for devices instead: {}",
+                        error
+                    ),
+                    None => String::new(),
+                })
+                .size(16),
+                container(row![
+                    button(text("Update Macropad")).on_press(Message::MacropadBootloader),
+                    Space::with_width(Length::Fixed(20.0)),
+                    button(text("Provisioning Mode")).on_press(Message::OpenProvisioning),
+                ])
+                // .width(Length::Fill)
+                .height(Length::Fill)
+                .align_x(alignment::Horizontal::Center)
+                .align_y(alignment::Vertical::Bottom)
             ])
             .width(Length::Fill)
             .height(Length::Fill)
@@ -1890,6 +3842,25 @@ impl Tab for SettingsTab {
                     .vertical_alignment(alignment::Vertical::Bottom)
                     .horizontal_alignment(alignment::Horizontal::Left)
                     .width(Length::Fill),
+                text(match self.timings {
+                    Some(timings) => match timings.last_save {
+                        Some(save) => format!(
+                            "Connected in {} ms, last save {} ms",
+                            timings.connect.as_millis(),
+                            save.as_millis()
+                        ),
+                        None => format!(
+                            "Connected in {} ms, macros {} ms",
+                            timings.connect.as_millis(),
+                            timings.macros.as_millis()
+                        ),
+                    },
+                    None => String::new(),
+                })
+                .size(16)
+                .vertical_alignment(alignment::Vertical::Bottom)
+                .horizontal_alignment(alignment::Horizontal::Center)
+                .width(Length::Fill),
                 text(format!(
                     "Configurator Version: {}",
                     env!("CARGO_PKG_VERSION")
@@ -1913,3 +3884,86 @@ impl Tab for SettingsTab {
             .into()
     }
 }
+
+#[derive(Debug, Default)]
+struct ProtocolTab {
+    exchanges: VecDeque<protocol_log::Exchange>,
+    /// Only show exchanges for this command.
+    filter: Option<String>,
+    capturing: bool,
+    status: Option<String>,
+}
+
+impl Tab for ProtocolTab {
+    type Message = Message;
+
+    fn title(&self) -> String {
+        String::from("Protocol")
+    }
+
+    fn tab_label(&self) -> TabLabel {
+        TabLabel::Text(self.title())
+    }
+
+    fn content(&self) -> Element<'_, Self::Message> {
+        let filters = std::iter::once("All")
+            .chain(protocol_log::COMMANDS.iter().map(|(_, name)| *name))
+            .map(String::from)
+            .collect::<Vec<_>>();
+
+        let exchanges = self
+            .exchanges
+            .iter()
+            .rev()
+            .filter(|exchange| match &self.filter {
+                Some(filter) => exchange.command_name() == filter,
+                None => true,
+            })
+            .fold(Column::new(), |log, exchange| {
+                let time = exchange.timestamp % 86_400_000;
+                log.push(
+                    text(format!(
+                        "{:02}:{:02}:{:02}.{:03}  {:<16} {:>7.1} ms  > {}  < {}",
+                        time / 3_600_000,
+                        time / 60_000 % 60,
+                        time / 1000 % 60,
+                        time % 1000,
+                        exchange.command_name(),
+                        exchange.latency().as_secs_f32() * 1000.0,
+                        protocol_log::hex(&exchange.request),
+                        match &exchange.response {
+                            Some(response) => protocol_log::hex(response),
+                            None => String::from("no response"),
+                        }
+                    ))
+                    .size(14),
+                )
+            });
+
+        column![
+            row![
+                pick_list(
+                    filters,
+                    Some(self.filter.clone().unwrap_or_else(|| String::from("All"))),
+                    Message::ProtocolFilterSelected
+                ),
+                Space::with_width(Length::Fixed(20.0)),
+                button("Clear").on_press(Message::ClearProtocolLog),
+                Space::with_width(Length::Fixed(20.0)),
+                button("Export Trace").on_press(Message::ExportTrace),
+                Space::with_width(Length::Fixed(20.0)),
+                if self.capturing {
+                    button("Save Capture").on_press(Message::FinishCapture)
+                } else {
+                    button("Start Capture").on_press(Message::StartCapture)
+                },
+                Space::with_width(Length::Fixed(20.0)),
+                text(self.status.clone().unwrap_or_default()).size(16),
+            ],
+            Space::with_height(Length::Fixed(10.0)),
+            scrollable(exchanges).height(Length::Fill),
+        ]
+        .padding(10)
+        .into()
+    }
+}
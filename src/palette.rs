@@ -0,0 +1,318 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::led_effects::hsv2rgb;
+
+/// Number of recently used colors kept.
+pub const RECENT_COLORS: usize = 8;
+
+/// Colors shared by every color picker, the base color, key colors and the
+/// colors macros set.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Palette {
+    /// Most recently used first.
+    pub recent: Vec<(u8, u8, u8)>,
+    pub swatches: Vec<(u8, u8, u8)>,
+}
+
+impl Palette {
+    pub fn use_color(&mut self, color: (u8, u8, u8)) {
+        self.recent.retain(|recent| *recent != color);
+        self.recent.insert(0, color);
+        self.recent.truncate(RECENT_COLORS);
+    }
+
+    pub fn save_swatch(&mut self, color: (u8, u8, u8)) {
+        if !self.swatches.contains(&color) {
+            self.swatches.push(color);
+        }
+    }
+
+    pub fn remove_swatch(&mut self, color: (u8, u8, u8)) {
+        self.swatches.retain(|swatch| *swatch != color);
+    }
+}
+
+/// A way of picking colors for the four keys which go together.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Harmony {
+    /// Alternating between the color and the opposite one.
+    Complementary,
+    /// Three colors evenly spaced around the hue wheel.
+    Triadic,
+    /// Neighbouring hues either side of the color.
+    Analogous,
+    /// From the color to the opposite one across the keys.
+    Gradient,
+}
+
+impl Harmony {
+    pub const ALL: [Harmony; 4] = [
+        Harmony::Complementary,
+        Harmony::Triadic,
+        Harmony::Analogous,
+        Harmony::Gradient,
+    ];
+}
+
+impl fmt::Display for Harmony {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Harmony::Complementary => write!(f, "Complementary"),
+            Harmony::Triadic => write!(f, "Triadic"),
+            Harmony::Analogous => write!(f, "Analogous"),
+            Harmony::Gradient => write!(f, "Gradient"),
+        }
+    }
+}
+
+/// The hue in degrees, saturation and value from 0 to 1.
+pub fn rgb_to_hsv(color: (u8, u8, u8)) -> (f32, f32, f32) {
+    let (r, g, b) = (
+        color.0 as f32 / 255.0,
+        color.1 as f32 / 255.0,
+        color.2 as f32 / 255.0,
+    );
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let delta = max - min;
+
+    let hue = if delta == 0.0 {
+        0.0
+    } else if max == r {
+        60.0 * ((g - b) / delta).rem_euclid(6.0)
+    } else if max == g {
+        60.0 * ((b - r) / delta + 2.0)
+    } else {
+        60.0 * ((r - g) / delta + 4.0)
+    };
+    let saturation = if max == 0.0 { 0.0 } else { delta / max };
+
+    (hue, saturation, max)
+}
+
+pub fn hsv_to_rgb(hue: f32, saturation: f32, value: f32) -> (u8, u8, u8) {
+    let (r, g, b) = hsv2rgb(
+        hue.rem_euclid(360.0),
+        saturation.clamp(0.0, 1.0),
+        value.clamp(0.0, 1.0),
+    );
+    let channel = |c: f32| (c.clamp(0.0, 1.0) * 255.0).round() as u8;
+
+    (channel(r), channel(g), channel(b))
+}
+
+/// Colors for the four keys, starting from `base` and keeping its
+/// saturation and value.
+pub fn harmony(base: (u8, u8, u8), harmony: Harmony) -> [(u8, u8, u8); 4] {
+    let (h, s, v) = rgb_to_hsv(base);
+    let turn = |degrees: f32| hsv_to_rgb(h + degrees, s, v);
+
+    match harmony {
+        Harmony::Complementary => [base, turn(180.0), base, turn(180.0)],
+        Harmony::Triadic => [base, turn(120.0), turn(240.0), base],
+        Harmony::Analogous => [turn(-30.0), base, turn(30.0), turn(60.0)],
+        Harmony::Gradient => gradient(base, turn(180.0)),
+    }
+}
+
+/// Blends from `from` on the first key to `to` on the last, going the short
+/// way round the hue wheel so the middle keys do not wash out to grey.
+pub fn gradient(from: (u8, u8, u8), to: (u8, u8, u8)) -> [(u8, u8, u8); 4] {
+    let (mut h1, s1, v1) = rgb_to_hsv(from);
+    let (mut h2, s2, v2) = rgb_to_hsv(to);
+
+    // Greys have no hue of their own, so take the other end's
+    if s1 == 0.0 {
+        h1 = h2;
+    }
+    if s2 == 0.0 {
+        h2 = h1;
+    }
+
+    let mut turn = h2 - h1;
+    if turn > 180.0 {
+        turn -= 360.0;
+    } else if turn < -180.0 {
+        turn += 360.0;
+    }
+
+    let mut colors = [from; 4];
+    for (i, color) in colors.iter_mut().enumerate().skip(1) {
+        let t = i as f32 / 3.0;
+        *color = hsv_to_rgb(h1 + turn * t, s1 + (s2 - s1) * t, v1 + (v2 - v1) * t);
+    }
+    colors[3] = to;
+
+    colors
+}
+
+pub fn to_hex(color: (u8, u8, u8)) -> String {
+    format!("#{:02x}{:02x}{:02x}", color.0, color.1, color.2)
+}
+
+pub fn to_rgb(color: (u8, u8, u8)) -> String {
+    format!("rgb({}, {}, {})", color.0, color.1, color.2)
+}
+
+pub fn to_hsv(color: (u8, u8, u8)) -> String {
+    let (h, s, v) = rgb_to_hsv(color);
+    format!(
+        "hsv({}, {}%, {}%)",
+        h.round(),
+        (s * 100.0).round(),
+        (v * 100.0).round()
+    )
+}
+
+/// The numbers between the brackets of `name(...)`.
+fn arguments(text: &str, name: &str) -> Option<Result<Vec<f32>, ()>> {
+    let inner = text.strip_prefix(name)?.trim().strip_prefix('(')?;
+    let inner = match inner.strip_suffix(')') {
+        Some(inner) => inner,
+        None => return Some(Err(())),
+    };
+
+    Some(
+        inner
+            .split(',')
+            .map(|value| {
+                value
+                    .trim()
+                    .trim_end_matches('%')
+                    .parse::<f32>()
+                    .map_err(|_| ())
+            })
+            .collect(),
+    )
+}
+
+/// Reads a color written as `#rrggbb`, `#rgb`, `rgb(r, g, b)` or
+/// `hsv(h, s%, v%)` with the hue in degrees.
+pub fn parse_color(text: &str) -> Result<(u8, u8, u8), ()> {
+    let text = text.trim().to_lowercase();
+
+    if let Some(values) = arguments(&text, "rgb") {
+        return match values?.as_slice() {
+            [r, g, b] if [r, g, b].iter().all(|c| (0.0..=255.0).contains(*c)) => {
+                Ok((r.round() as u8, g.round() as u8, b.round() as u8))
+            }
+            _ => Err(()),
+        };
+    }
+
+    if let Some(values) = arguments(&text, "hsv") {
+        return match values?.as_slice() {
+            [h, s, v] if (0.0..=100.0).contains(s) && (0.0..=100.0).contains(v) => {
+                Ok(hsv_to_rgb(*h, s / 100.0, v / 100.0))
+            }
+            _ => Err(()),
+        };
+    }
+
+    let hex = text.strip_prefix('#').unwrap_or(&text);
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(());
+    }
+
+    let channel = |digits: &str| u8::from_str_radix(digits, 16).map_err(|_| ());
+    match hex.len() {
+        6 => Ok((
+            channel(&hex[0..2])?,
+            channel(&hex[2..4])?,
+            channel(&hex[4..6])?,
+        )),
+        // Each digit is doubled, #f80 is #ff8800
+        3 => Ok((
+            channel(&hex[0..1])? * 17,
+            channel(&hex[1..2])? * 17,
+            channel(&hex[2..3])? * 17,
+        )),
+        _ => Err(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_every_notation() {
+        assert_eq!(parse_color("#ff8000"), Ok((255, 128, 0)));
+        assert_eq!(parse_color("FF8000"), Ok((255, 128, 0)));
+        assert_eq!(parse_color("#f80"), Ok((255, 136, 0)));
+        assert_eq!(parse_color(" rgb(10, 20,30) "), Ok((10, 20, 30)));
+        assert_eq!(parse_color("hsv(120, 100%, 50%)"), Ok((0, 128, 0)));
+        assert_eq!(parse_color("HSV(240, 100, 100)"), Ok((0, 0, 255)));
+
+        for bad in [
+            "",
+            "#12345",
+            "rgb(1, 2)",
+            "rgb(0, 0, 256)",
+            "hsv(0, 150, 0)",
+            "red",
+        ] {
+            assert_eq!(parse_color(bad), Err(()), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn formats_round_trip() {
+        let color = (18, 52, 86);
+
+        assert_eq!(to_hex(color), "#123456");
+        assert_eq!(parse_color(&to_hex(color)), Ok(color));
+        assert_eq!(parse_color(&to_rgb(color)), Ok(color));
+        assert_eq!(to_hsv((255, 0, 0)), "hsv(0, 100%, 100%)");
+    }
+
+    #[test]
+    fn harmonies_turn_the_hue() {
+        let red = (255, 0, 0);
+
+        assert_eq!(
+            harmony(red, Harmony::Complementary),
+            [red, (0, 255, 255), red, (0, 255, 255)]
+        );
+        assert_eq!(
+            harmony(red, Harmony::Triadic),
+            [red, (0, 255, 0), (0, 0, 255), red]
+        );
+        assert_eq!(harmony(red, Harmony::Analogous)[0], (255, 0, 128));
+    }
+
+    #[test]
+    fn gradient_takes_the_short_way_round() {
+        // Red to blue goes through magenta rather than green
+        let colors = gradient((255, 0, 0), (0, 0, 255));
+
+        assert_eq!(colors[0], (255, 0, 0));
+        assert_eq!(colors[1], (255, 0, 170));
+        assert_eq!(colors[2], (170, 0, 255));
+        assert_eq!(colors[3], (0, 0, 255));
+
+        // A grey end keeps the hue of the other one
+        let colors = gradient((0, 0, 0), (0, 255, 0));
+        assert_eq!(colors[2], (0, 170, 0));
+    }
+
+    #[test]
+    fn recent_colors_are_unique_and_limited() {
+        let mut palette = Palette::default();
+
+        for i in 0..10 {
+            palette.use_color((i, 0, 0));
+        }
+        palette.use_color((5, 0, 0));
+
+        assert_eq!(palette.recent.len(), RECENT_COLORS);
+        assert_eq!(palette.recent[0], (5, 0, 0));
+        assert_eq!(
+            palette.recent.iter().filter(|c| **c == (5, 0, 0)).count(),
+            1
+        );
+    }
+}
@@ -0,0 +1,563 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use macropad_protocol::data_protocol::{KeyMode, LedEffect};
+use serde::{Deserialize, Serialize};
+use usbd_human_interface_device::page::{Consumer, Keyboard};
+
+use crate::app_config;
+use crate::hid_manager::MacropadCommand;
+use crate::macro_parser::{self, LedConfig, Macro, Macropad};
+use crate::macropad_wrapper::{EFFECTS, MACRO_SIZE};
+
+/// Keys on the macropad.
+pub const KEY_COUNT: usize = 4;
+/// Tap, hold, double tap and tap and hold.
+pub const MACROS_PER_KEY: usize = 4;
+
+const KEY_MODES: [KeyMode; 4] = [
+    KeyMode::MacroMode,
+    KeyMode::SingleTapMode,
+    KeyMode::KeyboardMode,
+    KeyMode::ConsumerMode,
+];
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct KeyProfile {
+    pub key_mode: u8,
+    pub keyboard_data: u8,
+    pub consumer_data: u16,
+    pub key_color: (u8, u8, u8),
+    /// Packed tap, hold, double tap and tap and hold macros, in that order.
+    pub macros: Vec<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LedProfile {
+    pub base_color: (u8, u8, u8),
+    pub effect: u8,
+    pub brightness: u8,
+    pub effect_period: f32,
+    pub effect_offset: f32,
+}
+
+impl LedProfile {
+    pub fn from_config(config: &LedConfig) -> Self {
+        Self {
+            base_color: config.base_color,
+            effect: config.effect as u8,
+            brightness: config.brightness,
+            effect_period: config.effect_period,
+            effect_offset: config.effect_offset,
+        }
+    }
+
+    /// The commands needed to write these LED settings to a device.
+    pub fn commands(&self) -> Vec<MacropadCommand> {
+        vec![
+            MacropadCommand::LedBaseColor(self.base_color),
+            MacropadCommand::LedEffect(LedEffect::from(self.effect)),
+            MacropadCommand::LedBrightness(self.brightness),
+            MacropadCommand::LedEffectPeriod(self.effect_period),
+            MacropadCommand::LedEffectOffset(self.effect_offset),
+        ]
+    }
+}
+
+/// A named lighting setup, the LED settings and every key's color, which
+/// can be switched to without touching the rest of the configuration.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LedPreset {
+    pub name: String,
+    pub led: LedProfile,
+    pub key_colors: Vec<(u8, u8, u8)>,
+}
+
+impl LedPreset {
+    pub fn from_macropad(name: &str, macropad: &Macropad) -> Self {
+        Self {
+            name: name.to_owned(),
+            led: LedProfile::from_config(&macropad.led_config),
+            key_colors: macropad
+                .key_configs
+                .iter()
+                .map(|config| config.key_color)
+                .collect(),
+        }
+    }
+
+    /// The commands needed to switch a device to this preset, to be sent
+    /// together as one transaction. Colors for keys the macropad does not
+    /// have are left out.
+    pub fn commands(&self) -> Vec<MacropadCommand> {
+        let mut commands = self.led.commands();
+        for (i, color) in self.key_colors.iter().take(KEY_COUNT).enumerate() {
+            commands.push(MacropadCommand::KeyColor(i as u8, *color));
+        }
+
+        commands
+    }
+}
+
+/// A complete macropad configuration which can be saved to disk and written
+/// back to any device.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Profile {
+    pub name: String,
+    pub tap_speed: u32,
+    pub hold_speed: u32,
+    pub keys: Vec<KeyProfile>,
+    pub led: LedProfile,
+}
+
+pub fn profiles_dir() -> Option<PathBuf> {
+    app_config::data_dir().map(|dir| dir.join("profiles"))
+}
+
+/// Whether `name` can be used as a file name in the profiles directory
+/// without reaching outside it or being hidden.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.trim().is_empty()
+        && !name.starts_with('.')
+        && !name.contains("..")
+        // Either separator, so profiles can be copied between systems
+        && !name.chars().any(|c| matches!(c, '/' | '\\'))
+}
+
+/// Lists the saved profiles as `(name, path)` pairs.
+pub fn list_profiles() -> Vec<(String, PathBuf)> {
+    let mut profiles = profiles_dir()
+        .and_then(|dir| std::fs::read_dir(dir).ok())
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| path.extension().map_or(false, |ext| ext == "json"))
+                .map(|path| {
+                    (
+                        path.file_stem().unwrap().to_string_lossy().to_string(),
+                        path,
+                    )
+                })
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+
+    profiles.sort();
+    profiles
+}
+
+fn pack_macro(macro_data: &Macro) -> Vec<u8> {
+    macro_data
+        .pack()
+        .map(|data| data[..macro_data.size().min(MACRO_SIZE)].to_vec())
+        .unwrap_or_default()
+}
+
+fn padded_macro(data: &[u8]) -> [u8; MACRO_SIZE] {
+    let mut buf = [0u8; MACRO_SIZE];
+    let len = data.len().min(MACRO_SIZE);
+    buf[..len].copy_from_slice(&data[..len]);
+    buf
+}
+
+fn unpack_macro(data: &[u8]) -> Macro {
+    macro_parser::parse_macro(&padded_macro(data))
+}
+
+impl Profile {
+    pub fn from_macropad(name: &str, macropad: &Macropad) -> Self {
+        let keys = macropad
+            .key_configs
+            .iter()
+            .zip(macropad.macros.iter())
+            .map(|(config, macros)| KeyProfile {
+                key_mode: config.key_mode as u8,
+                keyboard_data: config.keyboard_data as u8,
+                consumer_data: config.consumer_data as u16,
+                key_color: config.key_color,
+                macros: vec![
+                    pack_macro(&macros.tap),
+                    pack_macro(&macros.hold),
+                    pack_macro(&macros.double_tap),
+                    pack_macro(&macros.tap_hold),
+                ],
+            })
+            .collect();
+
+        Self {
+            name: name.to_owned(),
+            tap_speed: macropad.config.tap_speed,
+            hold_speed: macropad.config.hold_speed,
+            keys,
+            led: LedProfile::from_config(&macropad.led_config),
+        }
+    }
+
+    /// Reads a profile, rejecting one with more keys or macros than the
+    /// macropad has, a key mode or effect it does not know, or a macro it
+    /// can not read.
+    pub fn load(path: &Path) -> Result<Self, ()> {
+        let file = File::open(path).map_err(|_| ())?;
+        let profile: Self = serde_json::from_reader(file).map_err(|_| ())?;
+        profile.validate()?;
+
+        Ok(profile)
+    }
+
+    fn validate(&self) -> Result<(), ()> {
+        if self.keys.len() > KEY_COUNT
+            || !EFFECTS
+                .iter()
+                .any(|effect| *effect as u8 == self.led.effect)
+        {
+            return Err(());
+        }
+
+        for key in &self.keys {
+            if key.macros.len() > MACROS_PER_KEY
+                || !KEY_MODES.iter().any(|mode| *mode as u8 == key.key_mode)
+            {
+                return Err(());
+            }
+
+            for data in &key.macros {
+                if data.len() > MACRO_SIZE || !macro_parser::is_valid_macro(&padded_macro(data)) {
+                    return Err(());
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), ()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).map_err(|_| ())?;
+        }
+
+        let data = serde_json::to_vec_pretty(self).map_err(|_| ())?;
+        File::create(path)
+            .and_then(|mut f| f.write_all(&data))
+            .map_err(|_| ())
+    }
+
+    /// An offline macropad holding this profile.
+    pub fn to_macropad(&self) -> Macropad {
+        let mut macropad = Macropad::offline();
+
+        // Keys and macros the macropad does not have are left out
+        let mut profile = self.clone();
+        profile.keys.truncate(macropad.key_configs.len());
+        for key in &mut profile.keys {
+            key.macros.truncate(MACROS_PER_KEY);
+        }
+
+        for command in profile.commands() {
+            command.apply(&mut macropad);
+        }
+
+        macropad
+    }
+
+    /// Replaces the key colors, for keys the profile has.
+    pub fn set_key_colors(&mut self, colors: &[(u8, u8, u8)]) {
+        for (key, color) in self.keys.iter_mut().zip(colors) {
+            key.key_color = *color;
+        }
+    }
+
+    /// The commands needed to write this profile to a device.
+    pub fn commands(&self) -> Vec<MacropadCommand> {
+        let mut commands = vec![
+            MacropadCommand::TapSpeed(self.tap_speed),
+            MacropadCommand::HoldSpeed(self.hold_speed),
+        ];
+
+        for (i, key) in self.keys.iter().enumerate() {
+            let i = i as u8;
+            commands.push(MacropadCommand::KeyMode(i, KeyMode::from(key.key_mode)));
+            commands.push(MacropadCommand::KeyboardData(
+                i,
+                Keyboard::from(key.keyboard_data),
+            ));
+            commands.push(MacropadCommand::ConsumerData(
+                i,
+                Consumer::from(key.consumer_data),
+            ));
+            commands.push(MacropadCommand::KeyColor(i, key.key_color));
+
+            for (m, data) in key.macros.iter().enumerate() {
+                commands.push(MacropadCommand::Macro(
+                    (i << 2) | m as u8,
+                    unpack_macro(data),
+                ));
+            }
+        }
+
+        commands.extend(self.led.commands());
+
+        commands
+    }
+
+    /// Describes every setting that differs between the two profiles, the
+    /// names are ignored.
+    pub fn diff(&self, other: &Profile) -> Vec<String> {
+        let mut differences = Vec::new();
+
+        if self.tap_speed != other.tap_speed {
+            differences.push(format!(
+                "Tap speed: {} != {}",
+                self.tap_speed, other.tap_speed
+            ));
+        }
+        if self.hold_speed != other.hold_speed {
+            differences.push(format!(
+                "Hold speed: {} != {}",
+                self.hold_speed, other.hold_speed
+            ));
+        }
+
+        if self.keys.len() != other.keys.len() {
+            differences.push(format!(
+                "Key count: {} != {}",
+                self.keys.len(),
+                other.keys.len()
+            ));
+        }
+
+        for (i, (a, b)) in self.keys.iter().zip(other.keys.iter()).enumerate() {
+            if a.key_mode != b.key_mode {
+                differences.push(format!(
+                    "Key {} mode: {:?} != {:?}",
+                    i,
+                    KeyMode::from(a.key_mode),
+                    KeyMode::from(b.key_mode)
+                ));
+            }
+            if a.keyboard_data != b.keyboard_data {
+                differences.push(format!(
+                    "Key {} keyboard data: {:?} != {:?}",
+                    i,
+                    Keyboard::from(a.keyboard_data),
+                    Keyboard::from(b.keyboard_data)
+                ));
+            }
+            if a.consumer_data != b.consumer_data {
+                differences.push(format!(
+                    "Key {} consumer data: {:?} != {:?}",
+                    i,
+                    Consumer::from(a.consumer_data),
+                    Consumer::from(b.consumer_data)
+                ));
+            }
+            if a.key_color != b.key_color {
+                differences.push(format!(
+                    "Key {} color: {:?} != {:?}",
+                    i, a.key_color, b.key_color
+                ));
+            }
+            for (m, (a, b)) in a.macros.iter().zip(b.macros.iter()).enumerate() {
+                if a != b {
+                    differences.push(format!("Key {} macro {}: contents differ", i, m));
+                }
+            }
+        }
+
+        if self.led.base_color != other.led.base_color {
+            differences.push(format!(
+                "LED base color: {:?} != {:?}",
+                self.led.base_color, other.led.base_color
+            ));
+        }
+        if self.led.effect != other.led.effect {
+            differences.push(format!(
+                "LED effect: {:?} != {:?}",
+                LedEffect::from(self.led.effect),
+                LedEffect::from(other.led.effect)
+            ));
+        }
+        if self.led.brightness != other.led.brightness {
+            differences.push(format!(
+                "LED brightness: {} != {}",
+                self.led.brightness, other.led.brightness
+            ));
+        }
+        if self.led.effect_period != other.led.effect_period {
+            differences.push(format!(
+                "LED effect period: {} != {}",
+                self.led.effect_period, other.led.effect_period
+            ));
+        }
+        if self.led.effect_offset != other.led.effect_offset {
+            differences.push(format!(
+                "LED effect offset: {} != {}",
+                self.led.effect_offset, other.led.effect_offset
+            ));
+        }
+
+        differences
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use macropad_protocol::macro_protocol::MacroCommand;
+
+    use crate::reactive_lighting::ReactiveSource;
+
+    #[test]
+    fn offline_macropad_round_trip() {
+        let mut macropad = Macropad::offline();
+        macropad.config.tap_speed = 150_000;
+        macropad.key_configs[2].key_mode = KeyMode::ConsumerMode;
+        macropad.key_configs[2].consumer_data = Consumer::VolumeIncrement;
+        macropad.key_configs[3].key_color = (10, 20, 30);
+        macropad.led_config.effect_offset = 0.25;
+
+        let profile = Profile::from_macropad("offline", &macropad);
+
+        assert_eq!(
+            Profile::from_macropad("offline", &profile.to_macropad()),
+            profile
+        );
+        assert_eq!(
+            profile
+                .diff(&Profile::from_macropad("", &Macropad::offline()))
+                .len(),
+            5
+        );
+    }
+
+    #[test]
+    fn profile_names_stay_in_the_directory() {
+        assert!(is_valid_name("Work setup 2"));
+        for bad in [
+            "",
+            "  ",
+            ".hidden",
+            "../escape",
+            "a..b",
+            "dir/name",
+            "dir\\name",
+        ] {
+            assert!(!is_valid_name(bad), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn malformed_profiles_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("profile.json");
+        let profile = Profile::from_macropad("malformed", &Macropad::offline());
+        let load = |profile: &Profile| {
+            profile.save(&path).unwrap();
+            Profile::load(&path)
+        };
+
+        assert_eq!(load(&profile), Ok(profile.clone()));
+
+        let mut extra_key = profile.clone();
+        extra_key.keys.push(profile.keys[0].clone());
+        assert_eq!(load(&extra_key), Err(()));
+
+        let mut extra_macro = profile.clone();
+        extra_macro.keys[1].macros.push(Vec::new());
+        assert_eq!(load(&extra_macro), Err(()));
+
+        let mut bad_mode = profile.clone();
+        bad_mode.keys[2].key_mode = 200;
+        assert_eq!(load(&bad_mode), Err(()));
+
+        let mut bad_effect = profile.clone();
+        bad_effect.led.effect = 200;
+        assert_eq!(load(&bad_effect), Err(()));
+
+        let mut unbalanced_loop = profile.clone();
+        unbalanced_loop.keys[0].macros[0] = vec![(MacroCommand::LoopEnd as u8) << 2, 0, 1];
+        assert_eq!(load(&unbalanced_loop), Err(()));
+
+        let mut unterminated_string = profile.clone();
+        unterminated_string.keys[0].macros[0] = vec![b'a'; MACRO_SIZE];
+        unterminated_string.keys[0].macros[0][0] = (MacroCommand::TypeString as u8) << 2;
+        assert_eq!(load(&unterminated_string), Err(()));
+
+        let mut oversized = profile;
+        oversized.keys[0].macros[0] = vec![0; MACRO_SIZE + 1];
+        assert_eq!(load(&oversized), Err(()));
+    }
+
+    #[test]
+    fn reactive_colors_are_not_saved() {
+        let mut macropad = Macropad::offline();
+        macropad.key_configs[1].key_color = (255, 0, 0);
+        let mut config = app_config::AppConfig {
+            reactive_saved_colors: Some(vec![(1, 1, 1), (2, 2, 2), (3, 3, 3), (4, 4, 4)]),
+            ..Default::default()
+        };
+
+        // Saved colors left over from before are ignored once it is off
+        assert!(config.saved_key_colors().is_none());
+
+        config.reactive_lighting = Some(ReactiveSource::CpuLoad);
+        let mut profile = Profile::from_macropad("reactive", &macropad);
+        profile.set_key_colors(config.saved_key_colors().unwrap());
+
+        let colors = profile
+            .keys
+            .iter()
+            .map(|key| key.key_color)
+            .collect::<Vec<_>>();
+        assert_eq!(colors, [(1, 1, 1), (2, 2, 2), (3, 3, 3), (4, 4, 4)]);
+    }
+
+    #[test]
+    fn led_preset_only_changes_lighting() {
+        let mut source = Macropad::offline();
+        source.led_config.effect = LedEffect::Static;
+        source.led_config.base_color = (40, 0, 0);
+        source.led_config.brightness = 30;
+        source.key_configs[1].key_color = (0, 0, 255);
+        let preset = LedPreset::from_macropad("focus", &source);
+
+        let mut target = Macropad::offline();
+        target.config.tap_speed = 150_000;
+        target.key_configs[0].key_mode = KeyMode::KeyboardMode;
+        for command in preset.commands() {
+            command.apply(&mut target);
+        }
+
+        assert_eq!(LedPreset::from_macropad("focus", &target), preset);
+        assert_eq!(target.config.tap_speed, 150_000);
+        assert_eq!(target.key_configs[0].key_mode, KeyMode::KeyboardMode);
+    }
+
+    #[test]
+    fn led_preset_ignores_extra_key_colors() {
+        let mut preset = LedPreset::from_macropad("extra", &Macropad::offline());
+        preset.key_colors = (0..KEY_COUNT as u8 + 2).map(|i| (i, i, i)).collect();
+
+        let commands = preset.commands();
+        assert_eq!(
+            commands
+                .iter()
+                .filter(|command| matches!(command, MacropadCommand::KeyColor(_, _)))
+                .count(),
+            KEY_COUNT
+        );
+
+        let mut target = Macropad::offline();
+        for command in commands {
+            command.apply(&mut target);
+        }
+
+        let colors = target
+            .key_configs
+            .iter()
+            .map(|config| config.key_color)
+            .collect::<Vec<_>>();
+        assert_eq!(colors, preset.key_colors[..KEY_COUNT]);
+    }
+}
@@ -0,0 +1,312 @@
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use iced_futures::futures;
+use iced_native::subscription::{self, Subscription};
+use macropad_protocol::data_protocol::DataCommand;
+use serde::{Deserialize, Serialize};
+
+use futures::channel::mpsc;
+use futures::stream::StreamExt;
+
+use crate::app_config;
+
+/// Size at which the log file is rotated.
+const LOG_SIZE: u64 = 1024 * 1024;
+/// Number of rotated log files kept next to the current one.
+const LOG_FILES: usize = 3;
+/// Number of exchanges kept in memory for exporting.
+const HISTORY: usize = 5000;
+
+pub const COMMANDS: [(u8, &str); 13] = [
+    (DataCommand::GetBuildVersion as u8, "GetBuildVersion"),
+    (DataCommand::GetBuildInfo as u8, "GetBuildInfo"),
+    (DataCommand::ReadConfig as u8, "ReadConfig"),
+    (DataCommand::WriteConfig as u8, "WriteConfig"),
+    (DataCommand::ReadKeyConfig as u8, "ReadKeyConfig"),
+    (DataCommand::WriteKeyConfig as u8, "WriteKeyConfig"),
+    (DataCommand::ReadMacro as u8, "ReadMacro"),
+    (DataCommand::WriteMacro as u8, "WriteMacro"),
+    (DataCommand::ValidateMacro as u8, "ValidateMacro"),
+    (DataCommand::ClearMacro as u8, "ClearMacro"),
+    (DataCommand::GetLed as u8, "GetLed"),
+    (DataCommand::SetLed as u8, "SetLed"),
+    (DataCommand::EnterBootloader as u8, "EnterBootloader"),
+];
+
+pub fn command_name(command: u8) -> &'static str {
+    COMMANDS
+        .iter()
+        .find(|(c, _)| *c == command)
+        .map_or("Unknown", |(_, name)| name)
+}
+
+/// A single request sent to a macropad and the response it gave.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Exchange {
+    /// Milliseconds since the unix epoch.
+    pub timestamp: u64,
+    pub request: Vec<u8>,
+    /// `None` if the device did not answer.
+    pub response: Option<Vec<u8>>,
+    pub latency_us: u64,
+}
+
+impl Exchange {
+    pub fn new(request: &[u8], response: Option<&[u8]>, latency: Duration) -> Self {
+        Self {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_millis() as u64),
+            request: request.to_vec(),
+            response: response.map(|response| response.to_vec()),
+            latency_us: latency.as_micros() as u64,
+        }
+    }
+
+    pub fn command(&self) -> u8 {
+        self.request.first().copied().unwrap_or_default()
+    }
+
+    pub fn command_name(&self) -> &'static str {
+        command_name(self.command())
+    }
+
+    pub fn latency(&self) -> Duration {
+        Duration::from_micros(self.latency_us)
+    }
+}
+
+/// Formats bytes as hex, leaving out the zero padding at the end.
+pub fn hex(bytes: &[u8]) -> String {
+    let used = bytes.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+    bytes[..used]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+struct Logger {
+    history: VecDeque<Exchange>,
+    file: Option<(File, u64)>,
+    /// Exchanges recorded since [`start_capture`].
+    capture: Option<Vec<Exchange>>,
+    subscribers: Vec<mpsc::UnboundedSender<Exchange>>,
+}
+
+static LOGGER: Mutex<Logger> = Mutex::new(Logger {
+    history: VecDeque::new(),
+    file: None,
+    capture: None,
+    subscribers: Vec::new(),
+});
+
+pub fn log_path() -> Option<PathBuf> {
+    app_config::data_dir().map(|dir| dir.join("logs").join("protocol.log"))
+}
+
+/// Shifts `path` to `path.1`, `path.1` to `path.2` and so on, dropping the
+/// oldest once there are `keep` of them.
+fn rotate(path: &Path, keep: usize) {
+    let rotated = |i: usize| PathBuf::from(format!("{}.{}", path.display(), i));
+
+    fs::remove_file(rotated(keep)).ok();
+    for i in (1..keep).rev() {
+        fs::rename(rotated(i), rotated(i + 1)).ok();
+    }
+    fs::rename(path, rotated(1)).ok();
+}
+
+fn open_log() -> Option<(File, u64)> {
+    let path = log_path()?;
+    fs::create_dir_all(path.parent()?).ok()?;
+
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .ok()?;
+    let size = file.metadata().map_or(0, |metadata| metadata.len());
+
+    Some((file, size))
+}
+
+/// Records an exchange to the log file, the in memory history and anyone
+/// subscribed through [`exchanges`].
+pub fn record(exchange: Exchange) {
+    let mut logger = LOGGER.lock().unwrap();
+
+    if logger.file.is_none() {
+        logger.file = open_log();
+    }
+
+    if let Some((file, size)) = &mut logger.file {
+        if let Ok(line) = serde_json::to_string(&exchange) {
+            if writeln!(file, "{}", line).is_ok() {
+                *size += line.len() as u64 + 1;
+            }
+        }
+
+        if *size > LOG_SIZE {
+            logger.file = None;
+            if let Some(path) = log_path() {
+                rotate(&path, LOG_FILES);
+            }
+        }
+    }
+
+    if logger.history.len() == HISTORY {
+        logger.history.pop_front();
+    }
+    logger.history.push_back(exchange.clone());
+
+    if let Some(capture) = &mut logger.capture {
+        capture.push(exchange.clone());
+    }
+
+    logger
+        .subscribers
+        .retain(|subscriber| subscriber.unbounded_send(exchange.clone()).is_ok());
+}
+
+pub fn history() -> Vec<Exchange> {
+    LOGGER.lock().unwrap().history.iter().cloned().collect()
+}
+
+pub fn clear() {
+    LOGGER.lock().unwrap().history.clear();
+}
+
+/// Writes exchanges as one JSON object per line.
+pub fn write_trace(path: &Path, exchanges: &[Exchange]) -> Result<(), ()> {
+    let mut file = File::create(path).map_err(|_| ())?;
+
+    for exchange in exchanges {
+        let line = serde_json::to_string(exchange).map_err(|_| ())?;
+        writeln!(file, "{}", line).map_err(|_| ())?;
+    }
+
+    Ok(())
+}
+
+pub fn read_trace(path: &Path) -> Result<Vec<Exchange>, ()> {
+    let contents = fs::read_to_string(path).map_err(|_| ())?;
+
+    contents
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| serde_json::from_str(line).map_err(|_| ()))
+        .collect()
+}
+
+/// Exports the in memory history to a new file in the traces directory,
+/// returning where it was written.
+pub fn export_trace() -> Result<PathBuf, ()> {
+    let dir = app_config::data_dir().ok_or(())?.join("traces");
+    fs::create_dir_all(&dir).map_err(|_| ())?;
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs());
+    let path = dir.join(format!("trace-{}.jsonl", timestamp));
+    write_trace(&path, &history())?;
+
+    Ok(path)
+}
+
+/// Starts recording every exchange, unlike the history a capture is not
+/// limited in length.
+pub fn start_capture() {
+    LOGGER.lock().unwrap().capture = Some(Vec::new());
+}
+
+pub fn is_capturing() -> bool {
+    LOGGER.lock().unwrap().capture.is_some()
+}
+
+/// Stops recording and saves the capture to a new file in the captures
+/// directory, returning where it was written. Captures can be replayed with
+/// [`crate::transport::ReplayTransport`].
+pub fn finish_capture() -> Result<PathBuf, ()> {
+    let capture = LOGGER.lock().unwrap().capture.take().ok_or(())?;
+
+    let dir = app_config::data_dir().ok_or(())?.join("captures");
+    fs::create_dir_all(&dir).map_err(|_| ())?;
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs());
+    let path = dir.join(format!("capture-{}.jsonl", timestamp));
+    write_trace(&path, &capture)?;
+
+    Ok(path)
+}
+
+/// Emits every exchange recorded after subscribing.
+pub fn exchanges() -> Subscription<Exchange> {
+    struct Exchanges;
+
+    subscription::unfold(
+        std::any::TypeId::of::<Exchanges>(),
+        None,
+        |receiver: Option<mpsc::UnboundedReceiver<Exchange>>| async move {
+            let mut receiver = match receiver {
+                Some(receiver) => receiver,
+                None => {
+                    let (sender, receiver) = mpsc::unbounded();
+                    LOGGER.lock().unwrap().subscribers.push(sender);
+                    receiver
+                }
+            };
+
+            let exchange = receiver.select_next_some().await;
+            (Some(exchange), Some(receiver))
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotate_keeps_newest_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("protocol.log");
+
+        for i in 0..4 {
+            fs::write(&path, i.to_string()).unwrap();
+            rotate(&path, 2);
+        }
+
+        assert!(!path.exists());
+        assert_eq!(
+            fs::read_to_string(dir.path().join("protocol.log.1")).unwrap(),
+            "3"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.path().join("protocol.log.2")).unwrap(),
+            "2"
+        );
+        assert!(!dir.path().join("protocol.log.3").exists());
+    }
+
+    #[test]
+    fn trace_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("trace.jsonl");
+        let exchanges = vec![
+            Exchange::new(&[1, 2, 3], Some(&[1, 2, 3, 4]), Duration::from_millis(3)),
+            Exchange::new(&[5, 0, 0], None, Duration::from_secs(1)),
+        ];
+
+        write_trace(&path, &exchanges).unwrap();
+        assert_eq!(read_trace(&path).unwrap(), exchanges);
+        assert_eq!(hex(&exchanges[1].request), "05");
+    }
+}
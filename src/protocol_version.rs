@@ -0,0 +1,153 @@
+use semver::Version;
+
+use crate::hid_manager::MacropadCommand;
+
+/// The command layouts the configurator can talk to a macropad with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Adapter {
+    /// Firmware from before 1.0, which is only sent the settings every
+    /// layout has.
+    Legacy,
+    /// Firmware 1.x, the layout the configurator is written against.
+    V1,
+}
+
+/// Settings which only some firmware versions understand.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Features {
+    /// `KeyConfigElements::KeyColor`.
+    pub key_colors: bool,
+    /// `LedCommand::EffectOffset`.
+    pub led_effect_offset: bool,
+}
+
+impl Adapter {
+    /// The features every firmware using this adapter has. Commands are
+    /// encoded with the current `macropad_protocol` layout, so settings it
+    /// added are only sent to firmware on that layout rather than guessing
+    /// which older release first had them.
+    pub fn features(&self) -> Features {
+        match self {
+            Adapter::Legacy => Features {
+                key_colors: false,
+                led_effect_offset: false,
+            },
+            Adapter::V1 => Features {
+                key_colors: true,
+                led_effect_offset: true,
+            },
+        }
+    }
+}
+
+impl Features {
+    /// Whether the firmware will accept the command.
+    pub fn supports(&self, command: &MacropadCommand) -> bool {
+        match command {
+            MacropadCommand::KeyColor(_, _) => self.key_colors,
+            MacropadCommand::LedEffectOffset(_) => self.led_effect_offset,
+            _ => true,
+        }
+    }
+}
+
+/// The protocol picked for a macropad from the version it reported.
+#[derive(Debug, Clone)]
+pub struct Negotiated {
+    /// `None` if the reported version could not be parsed.
+    pub version: Option<Version>,
+    pub adapter: Adapter,
+    /// Shown to the user when the configurator had to guess.
+    pub warning: Option<String>,
+}
+
+impl Negotiated {
+    pub fn features(&self) -> Features {
+        self.adapter.features()
+    }
+}
+
+impl Default for Negotiated {
+    fn default() -> Self {
+        Self {
+            version: None,
+            adapter: Adapter::V1,
+            warning: None,
+        }
+    }
+}
+
+pub fn negotiate(reported: &str) -> Negotiated {
+    let version = match Version::parse(reported.trim().trim_start_matches('v')) {
+        Ok(version) => version,
+        Err(_) => {
+            return Negotiated {
+                version: None,
+                adapter: Adapter::V1,
+                warning: Some(format!(
+                    "The macropad reported an unrecognized firmware version \"{}\", assuming the latest protocol",
+                    reported
+                )),
+            }
+        }
+    };
+
+    let (adapter, warning) = match version.major {
+        0 => (Adapter::Legacy, None),
+        1 => (Adapter::V1, None),
+        _ => (
+            Adapter::V1,
+            Some(format!(
+                "Firmware {} is newer than this configurator supports, some settings may not apply",
+                version
+            )),
+        ),
+    };
+
+    Negotiated {
+        version: Some(version),
+        adapter,
+        warning,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiate_versions() {
+        let legacy = negotiate("0.4.2");
+        assert_eq!(legacy.adapter, Adapter::Legacy);
+        assert!(legacy.warning.is_none());
+        assert!(!legacy.features().key_colors);
+
+        let current = negotiate("v1.2.0-3-gdeadbee");
+        assert_eq!(current.adapter, Adapter::V1);
+        assert!(current.warning.is_none());
+
+        let newer = negotiate("2.0.0");
+        assert_eq!(newer.adapter, Adapter::V1);
+        assert!(newer.warning.is_some());
+
+        let unknown = negotiate("dirty");
+        assert_eq!(unknown.adapter, Adapter::V1);
+        assert!(unknown.version.is_none());
+        assert!(unknown.warning.unwrap().contains("dirty"));
+    }
+
+    #[test]
+    fn features_follow_the_adapter() {
+        assert_eq!(negotiate("1.0.0").features(), Adapter::V1.features());
+        assert_eq!(negotiate("0.9.9").features(), Adapter::Legacy.features());
+    }
+
+    #[test]
+    fn legacy_filters_commands() {
+        let features = Adapter::Legacy.features();
+
+        assert!(!features.supports(&MacropadCommand::KeyColor(0, (1, 2, 3))));
+        assert!(!features.supports(&MacropadCommand::LedEffectOffset(0.5)));
+        assert!(features.supports(&MacropadCommand::TapSpeed(200)));
+    }
+}
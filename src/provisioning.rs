@@ -0,0 +1,317 @@
+use std::collections::HashSet;
+use std::fs::OpenOptions;
+use std::io::prelude::*;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hidapi::HidApi;
+use iced_futures::futures;
+use iced_native::subscription::{self, Subscription};
+
+use crate::hid_manager::{self, DeviceId};
+use crate::macropad_updater::{self, ReleaseSourceConfig};
+use crate::profile::Profile;
+use crate::{app_config, macro_parser, macropad_wrapper, protocol_version, transaction};
+
+/// What to do with every macropad plugged in while provisioning is running.
+#[derive(Debug, Clone)]
+pub struct Job {
+    pub profile: Profile,
+    /// Flash the latest firmware from this source before configuring.
+    pub firmware: Option<ReleaseSourceConfig>,
+    pub verify_checksum: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct ProvisionRecord {
+    pub timestamp: u64,
+    pub device: String,
+    pub passed: bool,
+    pub message: String,
+}
+
+#[derive(Debug, Clone)]
+pub enum Event {
+    Started,
+    FirmwareUnavailable,
+    Record(ProvisionRecord),
+}
+
+enum State {
+    Starting(Job),
+    Running {
+        job: Job,
+        firmware: Option<Vec<u8>>,
+        api: HidApi,
+        /// Devices which have been handled and are still plugged in.
+        present: HashSet<String>,
+        flashed: HashSet<String>,
+        /// The device last sent to the bootloader, whose drive is flashed next.
+        bootloader: Option<String>,
+        /// Devices whose firmware was written, logged once they come back.
+        written: HashSet<String>,
+        passed: HashSet<String>,
+    },
+    Finished,
+}
+
+fn device_key(id: &DeviceId) -> String {
+    id.serial.clone().unwrap_or_else(|| id.path.clone())
+}
+
+fn record(device: String, passed: bool, message: String) -> ProvisionRecord {
+    let record = ProvisionRecord {
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs()),
+        device,
+        passed,
+        message,
+    };
+
+    if let Some(dir) = app_config::data_dir() {
+        let log = std::fs::create_dir_all(&dir).and_then(|_| {
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(dir.join("provisioning.log"))
+        });
+
+        if let Ok(mut log) = log {
+            writeln!(
+                log,
+                "{}\t{}\t{}\t{}",
+                record.timestamp,
+                record.device,
+                if record.passed { "PASS" } else { "FAIL" },
+                record.message
+            )
+            .ok();
+        }
+    }
+
+    record
+}
+
+/// Writes the profile to the device and reads everything back to make sure
+/// it stuck.
+fn configure(device: &hidapi::HidDevice, profile: &Profile) -> Result<(), String> {
+    macropad_wrapper::prime_device(device).map_err(|_| String::from("Device not responding"))?;
+
+    let version =
+        macropad_wrapper::get_git_semver(device).map_err(|_| String::from("Read failed"))?;
+    let features = protocol_version::negotiate(&version).features();
+    let commands = profile
+        .commands()
+        .into_iter()
+        .filter(|command| features.supports(command))
+        .collect::<Vec<_>>();
+
+    transaction::apply(device, &commands).map_err(|error| error.to_string())?;
+
+    let macropad =
+        macro_parser::get_macro_pad(device).map_err(|_| String::from("Read back failed"))?;
+
+    // Only the settings this firmware supports are expected to match
+    let mut expected = macropad.clone();
+    for command in &commands {
+        command.apply(&mut expected);
+    }
+    let differences = Profile::from_macropad(&profile.name, &expected)
+        .diff(&Profile::from_macropad(&profile.name, &macropad));
+
+    if differences.is_empty() {
+        Ok(())
+    } else {
+        Err(format!("Verification failed: {}", differences.join(", ")))
+    }
+}
+
+pub fn provision(run: usize, job: Job) -> Subscription<Event> {
+    struct Provision;
+
+    subscription::unfold(
+        (std::any::TypeId::of::<Provision>(), run),
+        State::Starting(job),
+        |state| async move {
+            match state {
+                State::Starting(job) => {
+                    let firmware = match &job.firmware {
+                        Some(source) => {
+                            match macropad_updater::download_firmware(
+                                source.source(),
+                                None,
+                                job.verify_checksum,
+                            )
+                            .await
+                            {
+                                Ok(firmware) => Some(firmware),
+                                Err(_) => {
+                                    return (Some(Event::FirmwareUnavailable), State::Finished)
+                                }
+                            }
+                        }
+                        None => None,
+                    };
+
+                    (
+                        Some(Event::Started),
+                        State::Running {
+                            job,
+                            firmware,
+                            api: HidApi::new().unwrap(),
+                            present: HashSet::new(),
+                            flashed: HashSet::new(),
+                            bootloader: None,
+                            written: HashSet::new(),
+                            passed: HashSet::new(),
+                        },
+                    )
+                }
+                State::Running {
+                    job,
+                    firmware,
+                    mut api,
+                    mut present,
+                    mut flashed,
+                    mut bootloader,
+                    mut written,
+                    mut passed,
+                } => {
+                    if firmware.is_some() {
+                        if let Some(pico) = macropad_updater::scan_devices().await {
+                            let result =
+                                macropad_updater::write_firmware(&pico, firmware.as_ref().unwrap());
+                            // Give the drive time to disappear so it is not flashed twice
+                            tokio::time::sleep(tokio::time::Duration::from_secs(3)).await;
+
+                            // A flashed pad is logged under its serial once it
+                            // comes back, one left in the bootloader straight away
+                            let key = bootloader.take();
+                            let event = match (result, key) {
+                                (Ok(_), Some(key)) => {
+                                    written.insert(key);
+                                    None
+                                }
+                                (Ok(_), None) => None,
+                                (Err(_), key) => Some(Event::Record(record(
+                                    key.unwrap_or_else(|| String::from("Unknown device")),
+                                    false,
+                                    String::from("Flashing firmware failed"),
+                                ))),
+                            };
+
+                            return (
+                                event,
+                                State::Running {
+                                    job,
+                                    firmware,
+                                    api,
+                                    present,
+                                    flashed,
+                                    bootloader,
+                                    written,
+                                    passed,
+                                },
+                            );
+                        }
+                    }
+
+                    let devices = hid_manager::scan_devices(&mut api).await;
+                    present.retain(|key| devices.iter().any(|id| &device_key(id) == key));
+
+                    let next = devices.into_iter().find(|id| {
+                        let key = device_key(id);
+                        !present.contains(&key) && !passed.contains(&key)
+                    });
+
+                    let id = match next {
+                        Some(id) => id,
+                        None => {
+                            tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
+
+                            return (
+                                None,
+                                State::Running {
+                                    job,
+                                    firmware,
+                                    api,
+                                    present,
+                                    flashed,
+                                    bootloader,
+                                    written,
+                                    passed,
+                                },
+                            );
+                        }
+                    };
+
+                    let key = device_key(&id);
+                    if written.remove(&key) {
+                        // Configured on the next pass, now it is back
+                        return (
+                            Some(Event::Record(record(
+                                key,
+                                true,
+                                String::from("Flashed firmware"),
+                            ))),
+                            State::Running {
+                                job,
+                                firmware,
+                                api,
+                                present,
+                                flashed,
+                                bootloader,
+                                written,
+                                passed,
+                            },
+                        );
+                    }
+                    present.insert(key.clone());
+
+                    let event = match hid_manager::open_device(&mut api, &id).await {
+                        None => record(key, false, String::from("Could not open device")),
+                        Some(device) => {
+                            if firmware.is_some() && !flashed.contains(&key) {
+                                flashed.insert(key.clone());
+                                match macropad_wrapper::enter_bootloader(&device) {
+                                    Ok(_) => {
+                                        bootloader = Some(key.clone());
+                                        record(key, true, String::from("Entered bootloader"))
+                                    }
+                                    Err(_) => record(
+                                        key,
+                                        false,
+                                        String::from("Could not enter bootloader"),
+                                    ),
+                                }
+                            } else {
+                                match configure(&device, &job.profile) {
+                                    Ok(_) => {
+                                        passed.insert(key.clone());
+                                        record(key, true, format!("Applied {}", job.profile.name))
+                                    }
+                                    Err(message) => record(key, false, message),
+                                }
+                            }
+                        }
+                    };
+
+                    (
+                        Some(Event::Record(event)),
+                        State::Running {
+                            job,
+                            firmware,
+                            api,
+                            present,
+                            flashed,
+                            bootloader,
+                            written,
+                            passed,
+                        },
+                    )
+                }
+                State::Finished => futures::future::pending().await,
+            }
+        },
+    )
+}
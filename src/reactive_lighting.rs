@@ -0,0 +1,423 @@
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
+
+use iced_native::subscription::{self, Subscription};
+use image::{GenericImageView, RgbImage};
+use serde::{Deserialize, Serialize};
+use sysinfo::{CpuExt, CpuRefreshKind, RefreshKind, System, SystemExt};
+
+use crate::hid_manager::MacropadCommand;
+use crate::palette::hsv_to_rgb;
+
+/// How often the source is asked for new colors.
+pub const POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// The least time between two writes of the key colors.
+pub const MIN_WRITE_INTERVAL: Duration = Duration::from_millis(500);
+/// Images are shrunk to fit this many pixels across before their colors
+/// are counted.
+const THUMBNAIL_SIZE: u32 = 64;
+
+/// What the key colors follow while reactive lighting is on.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(tag = "source", rename_all = "snake_case")]
+pub enum ReactiveSource {
+    /// Green when idle through to red when busy, with a quarter of the CPU
+    /// cores on each key.
+    CpuLoad,
+    /// The dominant color of the quarter of the image over each key, read
+    /// again whenever the file changes.
+    Image { path: PathBuf },
+}
+
+impl ReactiveSource {
+    pub fn open(&self) -> Box<dyn ColorSource> {
+        match self {
+            ReactiveSource::CpuLoad => Box::new(CpuLoadSource::new()),
+            ReactiveSource::Image { path } => Box::new(ImageSource::new(path.clone())),
+        }
+    }
+}
+
+impl fmt::Display for ReactiveSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReactiveSource::CpuLoad => write!(f, "CPU Load"),
+            ReactiveSource::Image { path } => write!(f, "Image {}", path.display()),
+        }
+    }
+}
+
+/// The choices offered for reactive lighting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReactiveMode {
+    Off,
+    CpuLoad,
+    Image,
+}
+
+impl ReactiveMode {
+    pub const ALL: [ReactiveMode; 3] = [
+        ReactiveMode::Off,
+        ReactiveMode::CpuLoad,
+        ReactiveMode::Image,
+    ];
+
+    pub fn of(source: Option<&ReactiveSource>) -> Self {
+        match source {
+            None => ReactiveMode::Off,
+            Some(ReactiveSource::CpuLoad) => ReactiveMode::CpuLoad,
+            Some(ReactiveSource::Image { .. }) => ReactiveMode::Image,
+        }
+    }
+}
+
+impl fmt::Display for ReactiveMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReactiveMode::Off => write!(f, "Off"),
+            ReactiveMode::CpuLoad => write!(f, "CPU Load"),
+            ReactiveMode::Image => write!(f, "Image"),
+        }
+    }
+}
+
+/// Works out colors for the four keys from something on the host.
+pub trait ColorSource: Send {
+    /// The colors for the keys as they should look, in key order.
+    fn colors(&mut self) -> Result<[(u8, u8, u8); 4], String>;
+}
+
+pub struct CpuLoadSource {
+    system: System,
+}
+
+impl CpuLoadSource {
+    pub fn new() -> Self {
+        Self {
+            system: System::new_with_specifics(
+                RefreshKind::new().with_cpu(CpuRefreshKind::new().with_cpu_usage()),
+            ),
+        }
+    }
+}
+
+impl Default for CpuLoadSource {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ColorSource for CpuLoadSource {
+    fn colors(&mut self) -> Result<[(u8, u8, u8); 4], String> {
+        self.system.refresh_cpu();
+
+        let loads: Vec<f32> = self
+            .system
+            .cpus()
+            .iter()
+            .map(|cpu| cpu.cpu_usage() / 100.0)
+            .collect();
+        if loads.is_empty() {
+            return Err(String::from("No CPU usage available"));
+        }
+
+        Ok(key_loads(&loads).map(load_color))
+    }
+}
+
+/// The average load of each key's share of the cores. With fewer than four
+/// cores keys share them.
+pub fn key_loads(loads: &[f32]) -> [f32; 4] {
+    std::array::from_fn(|key| {
+        let start = (key * loads.len() / 4).min(loads.len().saturating_sub(1));
+        let end = ((key + 1) * loads.len() / 4)
+            .max(start + 1)
+            .min(loads.len());
+        let group = &loads[start..end];
+
+        if group.is_empty() {
+            0.0
+        } else {
+            group.iter().sum::<f32>() / group.len() as f32
+        }
+    })
+}
+
+/// Green for no load, through yellow, to red for full load.
+pub fn load_color(load: f32) -> (u8, u8, u8) {
+    hsv_to_rgb(120.0 * (1.0 - load.clamp(0.0, 1.0)), 1.0, 1.0)
+}
+
+pub struct ImageSource {
+    path: PathBuf,
+    /// When the file was last read, to only decode it again once it changes.
+    modified: Option<SystemTime>,
+    colors: [(u8, u8, u8); 4],
+}
+
+impl ImageSource {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            modified: None,
+            colors: [(0, 0, 0); 4],
+        }
+    }
+}
+
+impl ColorSource for ImageSource {
+    fn colors(&mut self) -> Result<[(u8, u8, u8); 4], String> {
+        let error = |error: &dyn fmt::Display| format!("{}: {}", self.path.display(), error);
+
+        let modified = fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .map_err(|e| error(&e))?;
+
+        if self.modified != Some(modified) {
+            let image = image::open(&self.path)
+                .map_err(|e| error(&e))?
+                .thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE)
+                .to_rgb8();
+            self.colors = quarter_colors(&image);
+            self.modified = Some(modified);
+        }
+
+        Ok(self.colors)
+    }
+}
+
+/// The dominant color of each quarter of the image, in key order. The keys
+/// go anticlockwise from the top left, see [`crate::macropad::outline`].
+pub fn quarter_colors(image: &RgbImage) -> [(u8, u8, u8); 4] {
+    let (width, height) = image.dimensions();
+    let (left, top) = (width / 2, height / 2);
+    let quarters = [
+        (0, 0, left, top),
+        (0, top, left, height - top),
+        (left, top, width - left, height - top),
+        (left, 0, width - left, top),
+    ];
+
+    quarters.map(|(x, y, width, height)| {
+        dominant_color(
+            image
+                .view(x, y, width, height)
+                .pixels()
+                .map(|(_, _, pixel)| (pixel.0[0], pixel.0[1], pixel.0[2])),
+        )
+    })
+}
+
+/// The average of the most common group of similar colors. Black if there
+/// are no pixels.
+pub fn dominant_color(pixels: impl Iterator<Item = (u8, u8, u8)>) -> (u8, u8, u8) {
+    // Each channel is cut down to its top three bits to group the colors
+    let mut buckets = vec![(0u32, 0u32, 0u32, 0u32); 512];
+    for (r, g, b) in pixels {
+        let bucket =
+            &mut buckets[(r as usize >> 5) << 6 | (g as usize >> 5) << 3 | b as usize >> 5];
+        bucket.0 += 1;
+        bucket.1 += r as u32;
+        bucket.2 += g as u32;
+        bucket.3 += b as u32;
+    }
+
+    let (count, r, g, b) =
+        buckets.into_iter().fold(
+            (0, 0, 0, 0),
+            |most, bucket| if bucket.0 > most.0 { bucket } else { most },
+        );
+    if count == 0 {
+        return (0, 0, 0);
+    }
+
+    let average = |sum: u32| ((sum + count / 2) / count) as u8;
+    (average(r), average(g), average(b))
+}
+
+/// Decides which key colors to write, so a source changing quickly does not
+/// flood the macropad with writes.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    min_interval: Duration,
+    last_write: Option<Instant>,
+    written: [Option<(u8, u8, u8)>; 4],
+}
+
+impl RateLimiter {
+    pub fn new(min_interval: Duration) -> Self {
+        Self {
+            min_interval,
+            last_write: None,
+            written: [None; 4],
+        }
+    }
+
+    /// The writes for the keys whose color changed, none if the last write
+    /// was too recent. Colors skipped are written by a later call.
+    pub fn update(&mut self, colors: [(u8, u8, u8); 4], now: Instant) -> Vec<MacropadCommand> {
+        if let Some(last_write) = self.last_write {
+            if now.duration_since(last_write) < self.min_interval {
+                return Vec::new();
+            }
+        }
+
+        let mut commands = Vec::new();
+        for (i, color) in colors.into_iter().enumerate() {
+            if self.written[i] != Some(color) {
+                self.written[i] = Some(color);
+                commands.push(MacropadCommand::KeyColor(i as u8, color));
+            }
+        }
+
+        if !commands.is_empty() {
+            self.last_write = Some(now);
+        }
+
+        commands
+    }
+
+    /// Forgets what was written, so every key is written next time.
+    pub fn reset(&mut self) {
+        self.last_write = None;
+        self.written = [None; 4];
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new(MIN_WRITE_INTERVAL)
+    }
+}
+
+/// Asks the source for colors every [`POLL_INTERVAL`], away from the UI
+/// thread since reading an image can take a while.
+pub fn colors(source: ReactiveSource) -> Subscription<Result<[(u8, u8, u8); 4], String>> {
+    struct Colors;
+
+    subscription::unfold(
+        (std::any::TypeId::of::<Colors>(), source.clone()),
+        None,
+        move |opened: Option<Box<dyn ColorSource>>| {
+            let source = source.clone();
+            async move {
+                tokio::time::sleep(POLL_INTERVAL).await;
+                let mut opened = opened.unwrap_or_else(|| source.open());
+
+                match tokio::task::spawn_blocking(move || {
+                    let colors = opened.colors();
+                    (opened, colors)
+                })
+                .await
+                {
+                    Ok((opened, colors)) => (Some(colors), Some(opened)),
+                    // Start over with a fresh source
+                    Err(_) => (Some(Err(format!("Reading {} failed", source))), None),
+                }
+            }
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use image::Rgb;
+
+    use super::*;
+
+    /// Replays a fixed list of colors.
+    struct Synthetic(Vec<[(u8, u8, u8); 4]>);
+
+    impl ColorSource for Synthetic {
+        fn colors(&mut self) -> Result<[(u8, u8, u8); 4], String> {
+            if self.0.is_empty() {
+                return Err(String::from("Out of colors"));
+            }
+            Ok(self.0.remove(0))
+        }
+    }
+
+    #[test]
+    fn only_changed_keys_are_written() {
+        let mut source = Synthetic(vec![
+            [(1, 0, 0); 4],
+            [(1, 0, 0), (2, 0, 0), (1, 0, 0), (1, 0, 0)],
+            [(1, 0, 0), (2, 0, 0), (1, 0, 0), (1, 0, 0)],
+        ]);
+        let mut limiter = RateLimiter::new(Duration::ZERO);
+        let now = Instant::now();
+
+        assert_eq!(limiter.update(source.colors().unwrap(), now).len(), 4);
+        assert!(matches!(
+            limiter.update(source.colors().unwrap(), now).as_slice(),
+            [MacropadCommand::KeyColor(1, (2, 0, 0))]
+        ));
+        assert!(limiter.update(source.colors().unwrap(), now).is_empty());
+        assert!(source.colors().is_err());
+    }
+
+    #[test]
+    fn writes_wait_for_the_interval() {
+        let mut limiter = RateLimiter::new(Duration::from_millis(500));
+        let start = Instant::now();
+
+        assert_eq!(limiter.update([(1, 0, 0); 4], start).len(), 4);
+        assert!(limiter
+            .update([(2, 0, 0); 4], start + Duration::from_millis(100))
+            .is_empty());
+        // The skipped colors are written once the interval has passed
+        assert_eq!(
+            limiter
+                .update([(2, 0, 0); 4], start + Duration::from_millis(500))
+                .len(),
+            4
+        );
+
+        limiter.reset();
+        assert_eq!(
+            limiter
+                .update([(2, 0, 0); 4], start + Duration::from_millis(600))
+                .len(),
+            4
+        );
+    }
+
+    #[test]
+    fn cores_are_shared_between_the_keys() {
+        assert_eq!(
+            key_loads(&[0.0, 0.25, 0.5, 0.75, 0.5, 1.0, 1.0, 1.0]),
+            [0.125, 0.625, 0.75, 1.0]
+        );
+        assert_eq!(key_loads(&[0.0, 1.0]), [0.0, 0.0, 1.0, 1.0]);
+        assert_eq!(key_loads(&[]), [0.0; 4]);
+
+        assert_eq!(load_color(0.0), (0, 255, 0));
+        assert_eq!(load_color(0.5), (255, 255, 0));
+        assert_eq!(load_color(1.0), (255, 0, 0));
+    }
+
+    #[test]
+    fn each_key_gets_its_quarter_of_the_image() {
+        let image = RgbImage::from_fn(8, 8, |x, y| match (x < 4, y < 4) {
+            (true, true) => Rgb([255, 0, 0]),
+            (true, false) => Rgb([0, 255, 0]),
+            (false, false) => Rgb([0, 0, 255]),
+            (false, true) => Rgb([255, 255, 255]),
+        });
+
+        assert_eq!(
+            quarter_colors(&image),
+            [(255, 0, 0), (0, 255, 0), (0, 0, 255), (255, 255, 255)]
+        );
+    }
+
+    #[test]
+    fn dominant_color_averages_the_largest_group() {
+        let pixels = [(200, 10, 10), (210, 20, 20), (0, 0, 250)];
+
+        assert_eq!(dominant_color(pixels.into_iter()), (205, 15, 15));
+        assert_eq!(dominant_color(std::iter::empty()), (0, 0, 0));
+    }
+}
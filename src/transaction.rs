@@ -0,0 +1,200 @@
+use std::fmt;
+
+use crate::hid_manager::{self, MacropadCommand};
+use crate::transport::Transport;
+use crate::{macro_parser, macropad_wrapper};
+
+#[derive(Debug, Clone)]
+pub struct TransactionError {
+    /// The writes which completed before the failure, in order.
+    pub applied: Vec<MacropadCommand>,
+    /// The write which failed, `None` if the snapshot could not be taken.
+    pub failed: Option<MacropadCommand>,
+    /// The writes whose previous value could not be put back, including the
+    /// failed one, so these settings are left as written. Empty if every
+    /// write was undone.
+    pub unrestored: Vec<MacropadCommand>,
+}
+
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.failed {
+            None => write!(
+                f,
+                "Could not read the current settings, nothing was written"
+            ),
+            Some(failed) => {
+                write!(
+                    f,
+                    "{} write(s) succeeded before \"{}\" failed",
+                    self.applied.len(),
+                    hid_manager::Message::Set(failed.clone())
+                )?;
+
+                if self.unrestored.is_empty() {
+                    write!(f, ", the previous settings were restored")
+                } else {
+                    write!(
+                        f,
+                        ", these settings could not be restored: {}",
+                        self.unrestored
+                            .iter()
+                            .map(|command| hid_manager::Message::Set(command.clone()).to_string())
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    )
+                }
+            }
+        }
+    }
+}
+
+/// Reads the current value of whatever `command` writes, returning the
+/// command that would put it back.
+pub fn snapshot(device: &dyn Transport, command: &MacropadCommand) -> Result<MacropadCommand, ()> {
+    Ok(match command {
+        MacropadCommand::Bootloader => return Err(()),
+        MacropadCommand::KeyMode(i, _) => {
+            MacropadCommand::KeyMode(*i, macropad_wrapper::get_key_mode(device, *i)?)
+        }
+        MacropadCommand::KeyboardData(i, _) => {
+            MacropadCommand::KeyboardData(*i, macropad_wrapper::get_keyboard_data(device, *i)?)
+        }
+        MacropadCommand::ConsumerData(i, _) => {
+            MacropadCommand::ConsumerData(*i, macropad_wrapper::get_consumer_data(device, *i)?)
+        }
+        MacropadCommand::KeyColor(i, _) => {
+            MacropadCommand::KeyColor(*i, macropad_wrapper::get_key_color(device, *i)?)
+        }
+        MacropadCommand::Macro(i, _) => {
+            MacropadCommand::Macro(*i, macro_parser::get_macro(device, *i)?)
+        }
+        MacropadCommand::TapSpeed(_) => {
+            MacropadCommand::TapSpeed(macropad_wrapper::get_tap_speed(device)?)
+        }
+        MacropadCommand::HoldSpeed(_) => {
+            MacropadCommand::HoldSpeed(macropad_wrapper::get_hold_speed(device)?)
+        }
+        MacropadCommand::LedBaseColor(_) => {
+            MacropadCommand::LedBaseColor(macropad_wrapper::get_led_base_color(device)?)
+        }
+        MacropadCommand::LedEffect(_) => {
+            MacropadCommand::LedEffect(macropad_wrapper::get_led_effect(device)?)
+        }
+        MacropadCommand::LedBrightness(_) => {
+            MacropadCommand::LedBrightness(macropad_wrapper::get_led_brightness(device)?)
+        }
+        MacropadCommand::LedEffectPeriod(_) => {
+            MacropadCommand::LedEffectPeriod(macropad_wrapper::get_led_effect_period(device)?)
+        }
+        MacropadCommand::LedEffectOffset(_) => {
+            MacropadCommand::LedEffectOffset(macropad_wrapper::get_led_effect_offset(device)?)
+        }
+    })
+}
+
+/// Applies every command or none of them. The affected settings are read
+/// before anything is written and restored in reverse order if a write fails,
+/// carrying on past any restore which fails itself.
+pub fn apply(device: &dyn Transport, commands: &[MacropadCommand]) -> Result<(), TransactionError> {
+    let snapshots = commands
+        .iter()
+        .map(|command| snapshot(device, command))
+        .collect::<Result<Vec<_>, ()>>()
+        .map_err(|_| TransactionError {
+            applied: Vec::new(),
+            failed: None,
+            unrestored: Vec::new(),
+        })?;
+
+    for (i, command) in commands.iter().enumerate() {
+        if hid_manager::write_command(device, command).is_err() {
+            // The failed write may have been partially applied, so it is
+            // restored as well
+            let mut unrestored = Vec::new();
+            for (restore, written) in snapshots[..=i].iter().zip(&commands[..=i]).rev() {
+                if hid_manager::write_command(device, restore).is_err() {
+                    unrestored.insert(0, written.clone());
+                }
+            }
+
+            return Err(TransactionError {
+                applied: commands[..i].to_vec(),
+                failed: Some(command.clone()),
+                unrestored,
+            });
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use macropad_protocol::data_protocol::{DataCommand, KeyConfigElements};
+
+    use super::*;
+    use crate::protocol_log::Exchange;
+    use crate::transport::ReplayTransport;
+
+    fn key_color_request(command: DataCommand, index: u8, color: (u8, u8, u8)) -> Vec<u8> {
+        let mut request = vec![0u8; 64];
+        request[0] = command as u8;
+        request[1] = KeyConfigElements::KeyColor as u8;
+        request[2] = index;
+        request[3..6].copy_from_slice(&[color.0, color.1, color.2]);
+        request
+    }
+
+    fn read_color(index: u8, color: (u8, u8, u8)) -> Exchange {
+        let request = key_color_request(DataCommand::ReadKeyConfig, index, (0, 0, 0));
+        let response = key_color_request(DataCommand::ReadKeyConfig, index, color);
+
+        Exchange::new(&request, Some(&response), Duration::ZERO)
+    }
+
+    /// A write which is echoed back, or goes unanswered if `ok` is false.
+    fn write_color(index: u8, color: (u8, u8, u8), ok: bool) -> Exchange {
+        let request = key_color_request(DataCommand::WriteKeyConfig, index, color);
+
+        Exchange::new(&request, ok.then_some(&request[..]), Duration::ZERO)
+    }
+
+    #[test]
+    fn rollback_carries_on_past_failed_restores() {
+        let old = [(1, 1, 1), (2, 2, 2), (3, 3, 3)];
+        let new = [(10, 0, 0), (20, 0, 0), (30, 0, 0)];
+        let commands = (0..3)
+            .map(|i| MacropadCommand::KeyColor(i as u8, new[i]))
+            .collect::<Vec<_>>();
+
+        let replay = ReplayTransport::new(vec![
+            read_color(0, old[0]),
+            read_color(1, old[1]),
+            read_color(2, old[2]),
+            write_color(0, new[0], true),
+            write_color(1, new[1], true),
+            // The third write fails
+            write_color(2, new[2], false),
+            // Restored in reverse, the middle key can not be put back
+            write_color(2, old[2], true),
+            write_color(1, old[1], false),
+            write_color(0, old[0], true),
+        ]);
+
+        let error = apply(&replay, &commands).unwrap_err();
+
+        assert_eq!(replay.remaining(), 0);
+        assert_eq!(error.applied.len(), 2);
+        assert!(matches!(
+            error.failed,
+            Some(MacropadCommand::KeyColor(2, (30, 0, 0)))
+        ));
+        assert!(matches!(
+            error.unrestored.as_slice(),
+            [MacropadCommand::KeyColor(1, (20, 0, 0))]
+        ));
+    }
+}
@@ -0,0 +1,216 @@
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use hidapi::HidDevice;
+
+use crate::protocol_log::{self, Exchange};
+
+/// Something which can carry a command to a macropad and bring back its
+/// response.
+pub trait Transport {
+    /// Sends a 65 byte report, the first byte being the report id, and waits
+    /// for the 64 byte response.
+    fn send(&self, command: &[u8; 65]) -> Result<[u8; 64], ()>;
+}
+
+impl Transport for HidDevice {
+    fn send(&self, command: &[u8; 65]) -> Result<[u8; 64], ()> {
+        let mut response = [0u8; 64];
+        let started = Instant::now();
+        // A failed write is logged like a missing response
+        let result = self
+            .write(command)
+            .and_then(|_| self.read_timeout(&mut response, 1000));
+
+        protocol_log::record(Exchange::new(
+            &command[1..],
+            result.as_ref().ok().map(|_| &response[..]),
+            started.elapsed(),
+        ));
+
+        if result.is_err() {
+            Err(())
+        } else {
+            Ok(response)
+        }
+    }
+}
+
+/// Answers commands from a recorded capture. Every command has to match the
+/// next request in the capture, so replaying also checks that the same
+/// commands are sent in the same order as when it was recorded.
+#[derive(Debug)]
+pub struct ReplayTransport {
+    exchanges: Mutex<VecDeque<Exchange>>,
+}
+
+impl ReplayTransport {
+    pub fn new(exchanges: Vec<Exchange>) -> Self {
+        Self {
+            exchanges: Mutex::new(exchanges.into()),
+        }
+    }
+
+    /// Opens a capture saved from the protocol console.
+    pub fn open(path: &Path) -> Result<Self, ()> {
+        protocol_log::read_trace(path).map(Self::new)
+    }
+
+    /// The number of exchanges which have not been replayed yet.
+    pub fn remaining(&self) -> usize {
+        self.exchanges.lock().unwrap().len()
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn send(&self, command: &[u8; 65]) -> Result<[u8; 64], ()> {
+        let mut exchanges = self.exchanges.lock().unwrap();
+
+        // An unexpected command is left in place so the caller can see
+        // where the replay diverged
+        if exchanges.front().ok_or(())?.request[..] != command[1..] {
+            return Err(());
+        }
+
+        let response = exchanges.pop_front().unwrap().response.ok_or(())?;
+        let mut buf = [0u8; 64];
+        let len = response.len().min(64);
+        buf[..len].copy_from_slice(&response[..len]);
+
+        Ok(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use macropad_protocol::data_protocol::DataCommand;
+    use usbd_human_interface_device::page::Keyboard;
+
+    use super::*;
+    use crate::macro_parser::{self, ActionType, Macro, MacroFrame};
+    use crate::profile::Profile;
+    use crate::{macropad_wrapper, transaction};
+
+    fn test_macro(text: &str) -> Macro {
+        let mut macro_data = Macro::new();
+        macro_data.add_frame(MacroFrame {
+            action: ActionType::String(String::from(text), Duration::from_millis(5)),
+            delay: Duration::from_millis(10),
+        });
+        macro_data.add_frame(MacroFrame {
+            action: ActionType::KeyPress(Keyboard::A, Duration::from_millis(20)),
+            delay: Duration::ZERO,
+        });
+        macro_data
+    }
+
+    fn macro_request(command: DataCommand, index: u8, offset: usize, size: usize) -> Vec<u8> {
+        let mut request = vec![0u8; 64];
+        request[0] = command as u8;
+        request[1] = index;
+        request[2..4].copy_from_slice(&(offset as u16).to_le_bytes());
+        request[4] = size as u8;
+        request
+    }
+
+    fn read_exchange(index: u8, offset: usize, chunk: &[u8]) -> Exchange {
+        let request = macro_request(DataCommand::ReadMacro, index, offset, chunk.len());
+        let mut response = request.clone();
+        response[5..(5 + chunk.len())].copy_from_slice(chunk);
+
+        Exchange::new(&request, Some(&response), Duration::ZERO)
+    }
+
+    fn write_exchange(index: u8, offset: usize, chunk: &[u8]) -> Exchange {
+        let mut request = macro_request(DataCommand::WriteMacro, index, offset, chunk.len());
+        request[5..(5 + chunk.len())].copy_from_slice(chunk);
+
+        // Writes are echoed back
+        Exchange::new(&request, Some(&request), Duration::ZERO)
+    }
+
+    #[test]
+    fn get_macro_reads_until_end_marker() {
+        // Long enough to need a second chunk
+        let macro_data = test_macro(&"a".repeat(70));
+        let data = macro_data.pack().unwrap();
+        let replay = ReplayTransport::new(vec![
+            read_exchange(3, 0, &data[0..59]),
+            read_exchange(3, 59, &data[59..118]),
+        ]);
+
+        let read = macro_parser::get_macro(&replay, 3).unwrap();
+
+        assert_eq!(read.pack().unwrap(), data);
+        assert_eq!(replay.remaining(), 0);
+    }
+
+    #[test]
+    fn set_macro_delta_only_writes_changed_chunks() {
+        let previous = test_macro(&"a".repeat(70)).pack().unwrap();
+        let data = test_macro(&format!("{}b", "a".repeat(69))).pack().unwrap();
+        let replay = ReplayTransport::new(vec![write_exchange(7, 59, &data[59..118])]);
+
+        let written = macropad_wrapper::set_macro_delta(&replay, 7, &previous, &data).unwrap();
+
+        assert_eq!(written, 1);
+        assert_eq!(replay.remaining(), 0);
+    }
+
+    #[test]
+    fn replay_rejects_unexpected_commands() {
+        let data = test_macro("abc").pack().unwrap();
+        let replay = ReplayTransport::new(vec![read_exchange(0, 0, &data[0..59])]);
+
+        assert!(macro_parser::get_macro(&replay, 1).is_err());
+        assert_eq!(replay.remaining(), 1);
+    }
+
+    /// Captures of real hardware, recorded from the protocol console, are
+    /// replayed against the full read of a macropad followed by loading the
+    /// profile saved next to them, see `tests/captures/README.md`. Nothing is
+    /// checked until a capture has been recorded.
+    #[test]
+    fn replay_hardware_captures() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/captures");
+        let captures = std::fs::read_dir(dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().map_or(false, |ext| ext == "jsonl"))
+            .collect::<Vec<_>>();
+
+        for path in captures {
+            let replay = ReplayTransport::open(&path).unwrap();
+            let macropad = macro_parser::get_macro_pad(&replay).unwrap_or_else(|_| {
+                panic!(
+                    "{} diverged reading the macropad with {} exchanges left",
+                    path.display(),
+                    replay.remaining()
+                )
+            });
+
+            let profile = Profile::load(&path.with_extension("profile.json"))
+                .unwrap_or_else(|_| panic!("{} has no profile", path.display()));
+            let features = macropad.protocol.features();
+            let commands = profile
+                .commands()
+                .into_iter()
+                .filter(|command| features.supports(command))
+                .collect::<Vec<_>>();
+
+            assert!(
+                transaction::apply(&replay, &commands).is_ok(),
+                "{} diverged loading the profile with {} exchanges left",
+                path.display(),
+                replay.remaining()
+            );
+            assert_eq!(replay.remaining(), 0, "{}", path.display());
+        }
+    }
+}
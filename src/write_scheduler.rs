@@ -0,0 +1,163 @@
+use std::time::{Duration, Instant};
+
+use crate::hid_manager::MacropadCommand;
+
+/// How long a setting has to stop changing before it is written.
+pub const DEFAULT_DELAY: Duration = Duration::from_millis(200);
+
+/// Holds back writes until a setting has stopped changing for a while, so
+/// dragging a slider sends one write instead of dozens. Writes are keyed by
+/// the setting and key index they change, so changes to different keys never
+/// replace each other.
+#[derive(Debug)]
+pub struct WriteScheduler {
+    delay: Duration,
+    /// Pending writes in the order they were first scheduled, with the time
+    /// each becomes due.
+    pending: Vec<(Instant, MacropadCommand)>,
+}
+
+impl WriteScheduler {
+    pub fn new(delay: Duration) -> Self {
+        Self {
+            delay,
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn set_delay(&mut self, delay: Duration) {
+        self.delay = delay;
+    }
+
+    pub fn schedule(&mut self, command: MacropadCommand) {
+        self.schedule_at(command, Instant::now());
+    }
+
+    /// Schedules a write, replacing any pending write to the same setting
+    /// and restarting its delay.
+    pub fn schedule_at(&mut self, command: MacropadCommand, now: Instant) {
+        let due = now + self.delay;
+
+        if let Some(setting) = command.setting() {
+            if let Some(pending) = self
+                .pending
+                .iter_mut()
+                .find(|(_, pending)| pending.setting() == Some(setting))
+            {
+                *pending = (due, command);
+                return;
+            }
+        }
+
+        self.pending.push((due, command));
+    }
+
+    /// Takes the writes whose delay has passed.
+    pub fn due(&mut self, now: Instant) -> Vec<MacropadCommand> {
+        let (due, pending) = self
+            .pending
+            .drain(..)
+            .partition::<Vec<_>, _>(|(time, _)| *time <= now);
+        self.pending = pending;
+
+        due.into_iter().map(|(_, command)| command).collect()
+    }
+
+    /// Takes every pending write whether it is due or not, for when the page
+    /// or device changes.
+    pub fn flush(&mut self) -> Vec<MacropadCommand> {
+        self.pending.drain(..).map(|(_, command)| command).collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+impl Default for WriteScheduler {
+    fn default() -> Self {
+        Self::new(DEFAULT_DELAY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use macropad_protocol::data_protocol::KeyMode;
+
+    use super::*;
+
+    #[test]
+    fn writes_to_different_keys_are_kept() {
+        let mut scheduler = WriteScheduler::default();
+        let now = Instant::now();
+
+        scheduler.schedule_at(MacropadCommand::KeyMode(1, KeyMode::MacroMode), now);
+        scheduler.schedule_at(MacropadCommand::KeyMode(2, KeyMode::MacroMode), now);
+
+        let due = scheduler.due(now + DEFAULT_DELAY);
+        assert!(matches!(
+            due.as_slice(),
+            [
+                MacropadCommand::KeyMode(1, _),
+                MacropadCommand::KeyMode(2, _)
+            ]
+        ));
+    }
+
+    #[test]
+    fn writes_to_the_same_setting_are_debounced() {
+        let mut scheduler = WriteScheduler::new(Duration::from_millis(100));
+        let now = Instant::now();
+
+        scheduler.schedule_at(MacropadCommand::LedBrightness(10), now);
+        scheduler.schedule_at(
+            MacropadCommand::LedBrightness(20),
+            now + Duration::from_millis(80),
+        );
+
+        // The second write restarted the delay
+        assert!(scheduler.due(now + Duration::from_millis(120)).is_empty());
+
+        let due = scheduler.due(now + Duration::from_millis(180));
+        assert!(matches!(
+            due.as_slice(),
+            [MacropadCommand::LedBrightness(20)]
+        ));
+        assert!(scheduler.is_empty());
+    }
+
+    #[test]
+    fn only_due_writes_are_taken() {
+        let mut scheduler = WriteScheduler::new(Duration::from_millis(100));
+        let now = Instant::now();
+
+        scheduler.schedule_at(MacropadCommand::TapSpeed(1000), now);
+        scheduler.schedule_at(
+            MacropadCommand::HoldSpeed(2000),
+            now + Duration::from_millis(50),
+        );
+
+        let due = scheduler.due(now + Duration::from_millis(100));
+        assert!(matches!(due.as_slice(), [MacropadCommand::TapSpeed(1000)]));
+        assert!(!scheduler.is_empty());
+    }
+
+    #[test]
+    fn flush_takes_everything() {
+        let mut scheduler = WriteScheduler::default();
+
+        scheduler.schedule(MacropadCommand::KeyColor(0, (1, 2, 3)));
+        scheduler.schedule(MacropadCommand::KeyColor(0, (4, 5, 6)));
+        scheduler.schedule(MacropadCommand::LedEffectOffset(0.5));
+
+        let flushed = scheduler.flush();
+        assert!(matches!(
+            flushed.as_slice(),
+            [
+                MacropadCommand::KeyColor(0, (4, 5, 6)),
+                MacropadCommand::LedEffectOffset(_)
+            ]
+        ));
+        assert!(scheduler.is_empty());
+    }
+}